@@ -0,0 +1,450 @@
+//! Access log of relayed streams
+//!
+//! With `access_log`, local and server write one JSON object per line for each stream they relay, when it closes:
+//!
+//! ```plain
+//! {"peer":"203.0.113.7:41234","session":3,"stream":5,"client":"127.0.0.1:50312","upstream":"example.com:443",
+//!  "start":"2024-05-01T08:30:00.125Z","end":"2024-05-01T08:30:12.500Z","bytes_up":1024,"bytes_down":65536,
+//!  "close":"eof"}
+//! ```
+//!
+//! `peer` and `session` are the session that carries the stream, `client` is the TCP connection it relays, if any.
+//! `bytes_up` flows from the side that opened the stream to `upstream`. `close` is `eof`, `reset`, `aborted` if the
+//! session was closed under the stream, or the text of the error.
+//!
+//! Log files are rotated when they would grow past `access_log_max_size`, keeping `ACCESS_LOG_FILES` old ones.
+//! Records are written and files rotated on a thread of their own, so closing a stream never blocks on the disk.
+
+use std::{
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    pin::Pin,
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc,
+    },
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::opt::PluginOpts;
+
+/// Path of `access_log` that means stdout
+const STDOUT_PATH: &str = "-";
+
+/// Default of `access_log_max_size`, in MiB
+const DEFAULT_MAX_SIZE: u64 = 64;
+
+/// Rotated log files that are kept, `PATH.1` is the newest
+pub const ACCESS_LOG_FILES: usize = 5;
+
+/// Records waiting for the writer thread, more are dropped
+const ACCESS_LOG_QUEUE: usize = 4096;
+
+/// A stream in the access log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRecord {
+    /// Peer of the session carrying the stream
+    pub peer: String,
+    pub session: u64,
+    pub stream: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    pub start: String,
+    pub end: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub close: String,
+}
+
+enum LogTarget {
+    Stdout,
+    File { path: String, file: File, size: u64 },
+}
+
+impl LogTarget {
+    fn write_line(&mut self, line: &[u8], max_size: u64) -> io::Result<()> {
+        match *self {
+            LogTarget::Stdout => io::stdout().lock().write_all(line),
+            LogTarget::File {
+                ref path,
+                ref mut file,
+                ref mut size,
+            } => {
+                if *size > 0 && *size + line.len() as u64 > max_size {
+                    *file = rotate(path)?;
+                    *size = 0;
+                }
+                file.write_all(line)?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Where access records are written
+///
+/// Lines are queued to a writer thread, which ends after the log drops and the queue is written.
+pub struct AccessLog {
+    path: String,
+    sender: Option<SyncSender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path == STDOUT_PATH {
+            f.write_str("AccessLog(stdout)")
+        } else {
+            write!(f, "AccessLog({})", self.path)
+        }
+    }
+}
+
+impl AccessLog {
+    /// Append to `path`, or write to stdout if it is `-`. Files are rotated at `max_size` bytes.
+    pub fn open(path: &str, max_size: u64) -> io::Result<AccessLog> {
+        let target = if path == STDOUT_PATH {
+            LogTarget::Stdout
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let size = file.metadata()?.len();
+            LogTarget::File {
+                path: path.to_owned(),
+                file,
+                size,
+            }
+        };
+
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(ACCESS_LOG_QUEUE);
+        let writer = thread::Builder::new().name("access-log".to_owned()).spawn(move || {
+            let mut target = target;
+            for line in receiver {
+                if let Err(err) = target.write_line(&line, max_size) {
+                    error!("access log write failed, error: {}", err);
+                }
+            }
+        })?;
+
+        Ok(AccessLog {
+            path: path.to_owned(),
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Access log of `access_log`, if set
+    pub fn from_plugin_opts(opts: &PluginOpts) -> io::Result<Option<Arc<AccessLog>>> {
+        match opts.access_log {
+            Some(ref path) => {
+                let max_size = opts.access_log_max_size.unwrap_or(DEFAULT_MAX_SIZE) * 1024 * 1024;
+                AccessLog::open(path, max_size).map(|log| Some(Arc::new(log)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn write(&self, record: &AccessRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("access log record {:?} serialize failed, error: {}", record, err);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let sender = match self.sender {
+            Some(ref sender) => sender,
+            None => return,
+        };
+        match sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(..)) => error!("access log queue is full, record {:?} dropped", record),
+            Err(TrySendError::Disconnected(..)) => error!("access log writer has stopped, record {:?} dropped", record),
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // Closing the queue lets the writer finish the lines still in it
+        self.sender = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2` and so on, and open `path` again
+fn rotate(path: &str) -> io::Result<File> {
+    for i in (1..ACCESS_LOG_FILES).rev() {
+        match fs::rename(format!("{}.{}", path, i), format!("{}.{}", path, i + 1)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    fs::rename(path, format!("{}.1", path))?;
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// A stream being relayed, written to the access log when it drops
+#[derive(Debug)]
+pub struct AccessEntry {
+    log: Arc<AccessLog>,
+    peer: String,
+    session: u64,
+    stream: u32,
+    client: Option<String>,
+    upstream: Option<String>,
+    start: SystemTime,
+    bytes_up: u64,
+    bytes_down: u64,
+    close: Option<String>,
+}
+
+impl AccessEntry {
+    pub fn new(log: Arc<AccessLog>, peer: &str, session: u64, stream: u32) -> AccessEntry {
+        AccessEntry {
+            log,
+            peer: peer.to_owned(),
+            session,
+            stream,
+            client: None,
+            upstream: None,
+            start: SystemTime::now(),
+            bytes_up: 0,
+            bytes_down: 0,
+            close: None,
+        }
+    }
+}
+
+impl Drop for AccessEntry {
+    fn drop(&mut self) {
+        let record = AccessRecord {
+            peer: self.peer.clone(),
+            session: self.session,
+            stream: self.stream,
+            client: self.client.take(),
+            upstream: self.upstream.take(),
+            start: Timestamp(self.start).to_string(),
+            end: Timestamp(SystemTime::now()).to_string(),
+            bytes_up: self.bytes_up,
+            bytes_down: self.bytes_down,
+            close: self.close.take().unwrap_or_else(|| "aborted".to_owned()),
+        };
+        self.log.write(&record);
+    }
+}
+
+/// Stream that opened a relayed stream, counting bytes of its access log entry
+///
+/// Bytes read from it are `bytes_up`, bytes written to it are `bytes_down`. Without an entry, it only passes through.
+#[derive(Debug)]
+pub struct AccessLogged<S> {
+    stream: S,
+    entry: Option<AccessEntry>,
+}
+
+impl<S> AccessLogged<S> {
+    pub fn new(stream: S, entry: Option<AccessEntry>) -> AccessLogged<S> {
+        AccessLogged { stream, entry }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Address of the TCP connection relayed in the stream
+    pub fn set_client<A: Display>(&mut self, client: A) {
+        if let Some(ref mut entry) = self.entry {
+            entry.client = Some(client.to_string());
+        }
+    }
+
+    /// Address that the stream is relayed to
+    pub fn set_upstream<A: Display>(&mut self, upstream: A) {
+        if let Some(ref mut entry) = self.entry {
+            entry.upstream = Some(upstream.to_string());
+        }
+    }
+
+    /// Record how relaying the stream ended
+    pub fn finish<T>(&mut self, result: &io::Result<T>) {
+        if let Some(ref mut entry) = self.entry {
+            entry.close = Some(match *result {
+                Ok(..) => "eof".to_owned(),
+                Err(ref err) if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe) => {
+                    "reset".to_owned()
+                }
+                Err(ref err) => err.to_string(),
+            });
+        }
+    }
+}
+
+impl<S> AsyncRead for AccessLogged<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(ref mut entry)) = (&result, &mut this.entry) {
+            entry.bytes_up += (buf.filled().len() - filled) as u64;
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for AccessLogged<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(ref mut entry)) = (&result, &mut this.entry) {
+            entry.bytes_down += *n as u64;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// RFC 3339 time in UTC, with milliseconds
+struct Timestamp(SystemTime);
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+        let (year, month, day) = civil_date(days);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+/// Year, month and day of `days` since 1970-01-01, by Howard Hinnant's algorithm
+pub(crate) fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    struct TempLog(String);
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            for i in 1..=ACCESS_LOG_FILES {
+                let _ = fs::remove_file(format!("{}.{}", self.0, i));
+            }
+        }
+    }
+
+    fn record(stream: u32) -> AccessRecord {
+        AccessRecord {
+            peer: "203.0.113.7:41234".to_owned(),
+            session: 3,
+            stream,
+            client: None,
+            upstream: Some("example.com:443".to_owned()),
+            start: Timestamp(UNIX_EPOCH).to_string(),
+            end: Timestamp(UNIX_EPOCH).to_string(),
+            bytes_up: 1024,
+            bytes_down: 65536,
+            close: "eof".to_owned(),
+        }
+    }
+
+    fn read_streams(path: &str) -> Vec<u32> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AccessRecord>(line).unwrap().stream)
+            .collect()
+    }
+
+    #[test]
+    fn written_on_drop() {
+        let temp = TempLog(format!(
+            "{}/sskcp-access-{}-written.log",
+            env::temp_dir().display(),
+            process::id()
+        ));
+
+        let log = AccessLog::open(&temp.0, 1024 * 1024).unwrap();
+        for stream in 0..100 {
+            log.write(&record(stream));
+        }
+        drop(log);
+
+        assert_eq!(read_streams(&temp.0), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rotated() {
+        let temp = TempLog(format!(
+            "{}/sskcp-access-{}-rotated.log",
+            env::temp_dir().display(),
+            process::id()
+        ));
+        let line_len = serde_json::to_vec(&record(1)).unwrap().len() as u64 + 1;
+
+        let log = AccessLog::open(&temp.0, line_len * 2).unwrap();
+        for stream in 1..=5 {
+            log.write(&record(stream));
+        }
+        drop(log);
+
+        assert_eq!(read_streams(&temp.0), [5]);
+        assert_eq!(read_streams(&format!("{}.1", temp.0)), [3, 4]);
+        assert_eq!(read_streams(&format!("{}.2", temp.0)), [1, 2]);
+    }
+}
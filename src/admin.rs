@@ -0,0 +1,629 @@
+//! Admin control socket
+//!
+//! With `admin_socket`, local and server listen on a unix domain socket for commands, one JSON object per line, and
+//! reply to each with one JSON object per line.
+//!
+//! ```plain
+//! {"command":"sessions"}                    Sessions with their peers, ages, open streams and bytes
+//! {"command":"kill","id":3}                 Close a session and its streams
+//! {"command":"drain"}                       Stop accepting, and exit when all streams have finished
+//! {"command":"opts"}                        Effective plugin options, secrets hidden
+//! {"command":"log_level","filters":"debug"} Replace the log filters, formatted like `RUST_LOG`
+//! {"command":"bans"}                        Addresses banned by the firewall of server, with seconds left
+//! {"command":"unban","ip":"203.0.113.7"}    Lift a ban
+//! ```
+//!
+//! Replies have `ok`, and `error` if it is `false`. `sskcp-ctl` sends commands from the command line.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use env_logger::{Builder, Logger};
+use log::{Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+    time,
+};
+
+use crate::{
+    access_log::{AccessEntry, AccessLog},
+    firewall::{BanInfo, Firewall},
+    limit::{Limits, RefusedCounts},
+    opt::PluginOpts,
+    udp::stats::{KcpStats, KcpStatsSnapshot},
+    user::Users,
+};
+
+/// Options that are never shown by `opts`
+const SECRET_OPTS: &[&str] = &["frontend_password", "e2e_key", "session_key", "user_key"];
+
+/// Command sent to the admin socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Sessions,
+    Kill { id: u64 },
+    Drain,
+    Opts,
+    LogLevel { filters: String },
+    Bans,
+    Unban { ip: IpAddr },
+}
+
+/// Reply of the admin socket
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<Vec<SessionInfo>>,
+    /// Requests refused by the limits, with `sessions`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refused: Option<RefusedCounts>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opts: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bans: Option<Vec<BanInfo>>,
+}
+
+impl Response {
+    fn ok() -> Response {
+        Response {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn error<E: Display>(err: E) -> Response {
+        Response {
+            ok: false,
+            error: Some(err.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A session listed by `sessions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer: String,
+    /// User that the session is authenticated to, with `users`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Seconds since the session started
+    pub age: u64,
+    pub streams: usize,
+    /// Bytes written to streams of the session
+    pub bytes_sent: u64,
+    /// Bytes read from streams of the session
+    pub bytes_received: u64,
+    /// Statistics of the KCP session, with `kcp_stats`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kcp: Option<KcpStatsSnapshot>,
+}
+
+/// Sessions of a local or server, for the admin socket
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+    next_id: AtomicU64,
+    draining: AtomicBool,
+    /// Notified when a session or a stream closes, or draining starts
+    changed: Notify,
+    access_log: Option<Arc<AccessLog>>,
+    limits: Arc<Limits>,
+    users: Option<Arc<Users>>,
+    firewall: Option<Arc<Firewall>>,
+}
+
+impl SessionRegistry {
+    /// Registry whose streams are recorded in `access_log`, whose sessions and streams are admitted by `limits`, whose
+    /// sessions are authenticated to `users`, and whose bans are kept by `firewall`
+    pub fn new(
+        access_log: Option<Arc<AccessLog>>,
+        limits: Arc<Limits>,
+        users: Option<Arc<Users>>,
+        firewall: Option<Arc<Firewall>>,
+    ) -> SessionRegistry {
+        SessionRegistry {
+            access_log,
+            limits,
+            users,
+            firewall,
+            ..Default::default()
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn users(&self) -> Option<&Arc<Users>> {
+        self.users.as_ref()
+    }
+
+    /// Add a session, which is removed when the handle drops
+    pub fn register<P: Display>(
+        self: &Arc<Self>,
+        peer: P,
+        user: Option<&str>,
+        kcp_stats: Option<Arc<KcpStats>>,
+    ) -> SessionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session {
+            id,
+            peer: peer.to_string(),
+            user: user.map(str::to_owned),
+            started: Instant::now(),
+            streams: AtomicUsize::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            killed: Notify::new(),
+            closed: AtomicBool::new(false),
+            kcp_stats,
+            registry: self.clone(),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+        SessionHandle(session)
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.values().map(|s| s.info()).collect()
+    }
+
+    /// Close session `id`, returns `false` if there is no such session
+    pub fn kill(&self, id: u64) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(&id) {
+            Some(session) => {
+                session.killed.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop accepting sessions and streams, sessions close when their streams have finished
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Completes when draining starts
+    pub async fn draining(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.is_draining() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Completes when draining and all sessions have closed
+    pub async fn drained(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.is_draining() && self.sessions.lock().unwrap().is_empty() {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+/// A session in a `SessionRegistry`
+#[derive(Debug)]
+pub struct Session {
+    id: u64,
+    peer: String,
+    user: Option<String>,
+    started: Instant,
+    streams: AtomicUsize,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    killed: Notify,
+    closed: AtomicBool,
+    kcp_stats: Option<Arc<KcpStats>>,
+    registry: Arc<SessionRegistry>,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            peer: self.peer.clone(),
+            user: self.user.clone(),
+            age: self.started.elapsed().as_secs(),
+            streams: self.streams.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            kcp: self.kcp_stats.as_ref().map(|s| s.snapshot()),
+        }
+    }
+
+    /// Count `stream` as a stream of this session, until it drops
+    pub fn counted<S>(self: &Arc<Self>, stream: S) -> CountedStream<S> {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        CountedStream {
+            stream,
+            session: self.clone(),
+        }
+    }
+
+    /// Streams of the session that are open
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    pub fn registry(&self) -> &Arc<SessionRegistry> {
+        &self.registry
+    }
+
+    /// Whether the handle of the session has dropped
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Completes when the session has had no streams for `timeout`
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let changed = self.registry.changed.notified();
+            if self.streams.load(Ordering::Relaxed) > 0 {
+                changed.await;
+                continue;
+            }
+
+            // Streams that close notify, streams that open are seen when the timer fires
+            tokio::select! {
+                _ = time::sleep(timeout) => {
+                    if self.streams.load(Ordering::Relaxed) == 0 {
+                        return;
+                    }
+                }
+                _ = changed => {}
+            }
+        }
+    }
+
+    /// Entry of stream `stream` in the access log, if any
+    pub fn access_entry(&self, stream: u32) -> Option<AccessEntry> {
+        let log = self.registry.access_log.as_ref()?;
+        Some(AccessEntry::new(log.clone(), &self.peer, self.id, stream))
+    }
+
+    /// Completes when the session is killed, or draining and it has no streams
+    pub async fn closing(&self) {
+        let killed = self.killed.notified();
+        tokio::pin!(killed);
+
+        loop {
+            let changed = self.registry.changed.notified();
+            if self.registry.is_draining() && self.streams.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            tokio::select! {
+                _ = &mut killed => return,
+                _ = changed => {}
+            }
+        }
+    }
+}
+
+/// Keeps a session in its registry
+#[derive(Debug)]
+pub struct SessionHandle(Arc<Session>);
+
+impl SessionHandle {
+    pub fn session(&self) -> &Arc<Session> {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for SessionHandle {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.0
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        let registry = &self.0.registry;
+        self.0.closed.store(true, Ordering::Relaxed);
+        registry.sessions.lock().unwrap().remove(&self.0.id);
+        registry.changed.notify_waiters();
+    }
+}
+
+/// Stream of a session, counting its bytes
+#[derive(Debug)]
+pub struct CountedStream<S> {
+    stream: S,
+    session: Arc<Session>,
+}
+
+impl<S> CountedStream<S> {
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> Drop for CountedStream<S> {
+    fn drop(&mut self) {
+        self.session.streams.fetch_sub(1, Ordering::Relaxed);
+        self.session.registry.changed.notify_waiters();
+    }
+}
+
+impl<S> AsyncRead for CountedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let n = (buf.filled().len() - filled) as u64;
+            this.session.bytes_received.fetch_add(n, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for CountedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.session.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Logger installed by `init_logger`, replaced by `log_level`
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+
+struct ReloadableLogger;
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match *LOGGER.read().unwrap() {
+            Some(ref logger) => logger.enabled(metadata),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(ref logger) = *LOGGER.read().unwrap() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(ref logger) = *LOGGER.read().unwrap() {
+            logger.flush();
+        }
+    }
+}
+
+fn log_builder(filters: Option<&str>) -> Builder {
+    let mut builder = match filters {
+        Some(filters) => {
+            let mut builder = Builder::new();
+            builder.parse_filters(filters);
+            builder
+        }
+        None => Builder::from_default_env(),
+    };
+    builder.format_timestamp_millis();
+    builder
+}
+
+/// Install a logger configured by `RUST_LOG`, whose filters can be replaced by `log_level`
+pub fn init_logger() {
+    let logger = log_builder(None).build();
+    let max_level = logger.filter();
+    *LOGGER.write().unwrap() = Some(logger);
+
+    log::set_logger(&ReloadableLogger).expect("logger already installed");
+    log::set_max_level(max_level);
+}
+
+/// Replace filters of the logger installed by `init_logger`, formatted like `RUST_LOG`
+pub fn set_log_filters(filters: &str) -> io::Result<()> {
+    let mut logger = LOGGER.write().unwrap();
+    if logger.is_none() {
+        return Err(io::Error::other("logger is not installed by init_logger"));
+    }
+
+    let new_logger = log_builder(Some(filters)).build();
+    log::set_max_level(new_logger.filter());
+    *logger = Some(new_logger);
+    Ok(())
+}
+
+/// Plugin options as shown by `opts`
+fn dump_opts(opts: &PluginOpts) -> io::Result<Value> {
+    let mut value = serde_json::to_value(opts)?;
+    if let Value::Object(ref mut map) = value {
+        map.retain(|_, v| !v.is_null());
+        for key in SECRET_OPTS {
+            if let Some(v) = map.get_mut(*key) {
+                *v = Value::String("******".to_owned());
+            }
+        }
+    }
+    Ok(value)
+}
+
+fn handle_request(request: Request, registry: &SessionRegistry, opts: &Value) -> Response {
+    match request {
+        Request::Sessions => Response {
+            sessions: Some(registry.list()),
+            refused: Some(registry.limits().refused()),
+            ..Response::ok()
+        },
+        Request::Kill { id } => {
+            if registry.kill(id) {
+                Response::ok()
+            } else {
+                Response::error(format!("no session {}", id))
+            }
+        }
+        Request::Drain => {
+            registry.drain();
+            Response::ok()
+        }
+        Request::Opts => Response {
+            opts: Some(opts.clone()),
+            ..Response::ok()
+        },
+        Request::LogLevel { filters } => match set_log_filters(&filters) {
+            Ok(()) => Response::ok(),
+            Err(err) => Response::error(err),
+        },
+        Request::Bans => Response {
+            bans: Some(registry.firewall.as_ref().map(|f| f.bans()).unwrap_or_default()),
+            ..Response::ok()
+        },
+        Request::Unban { ip } => {
+            if registry.firewall.as_ref().is_some_and(|f| f.unban(ip)) {
+                Response::ok()
+            } else {
+                Response::error(format!("{} is not banned", ip))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{io, path::Path, sync::Arc, time::Duration};
+
+    use log::{debug, error, info};
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixStream,
+        time,
+    };
+
+    use super::{dump_opts, handle_request, Request, Response, SessionRegistry};
+    use crate::{
+        opt::PluginOpts,
+        unix_socket::{bind_with_umask, remove_stale_socket},
+    };
+
+    /// Listen on the admin socket at `path`, replacing a socket left by an earlier process
+    pub async fn start_admin(path: &str, registry: Arc<SessionRegistry>, opts: &PluginOpts) -> io::Result<()> {
+        remove_stale_socket(Path::new(path))?;
+        // Only accessible by the owner from the start, so that nobody else connects before it is restricted
+        let listener = bind_with_umask(Path::new(path), 0o177)?;
+        info!("admin socket listening on {}", path);
+
+        let opts = Arc::new(dump_opts(opts)?);
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        error!("admin socket accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let registry = registry.clone();
+                let opts = opts.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_admin(stream, &registry, &opts).await {
+                        debug!("admin connection error: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn handle_admin(stream: UnixStream, registry: &SessionRegistry, opts: &Value) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    debug!("admin command {:?}", request);
+                    handle_request(request, registry, opts)
+                }
+                Err(err) => Response::error(format!("invalid command, {}", err)),
+            };
+
+            let mut buf = serde_json::to_vec(&response)?;
+            buf.push(b'\n');
+            writer.write_all(&buf).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::start_admin;
+
+/// Admin sockets are unix domain sockets
+#[cfg(not(unix))]
+pub async fn start_admin(_path: &str, _registry: Arc<SessionRegistry>, _opts: &PluginOpts) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "admin_socket is only supported on unix",
+    ))
+}
@@ -0,0 +1,356 @@
+//! Destinations that server connects to for stream headers
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::net::{lookup_host, TcpStream};
+
+use crate::{
+    config::{network_required, ServerAddr},
+    opt::{create_outbound_tcp, PluginOpts},
+};
+
+/// Hosts matched by a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetHost {
+    /// Addresses in this network
+    Net(IpNet),
+    /// This domain name
+    Domain(String),
+    /// Subdomains of this domain name, written as `*.example.com`
+    Subdomain(String),
+}
+
+/// Destination allowed by `allowed_targets`, a host with an optional port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetRule {
+    pub host: TargetHost,
+    pub port: Option<u16>,
+}
+
+impl TargetRule {
+    fn matches_port(&self, port: u16) -> bool {
+        self.port.is_none() || self.port == Some(port)
+    }
+
+    fn matches_addr(&self, addr: &SocketAddr) -> bool {
+        match self.host {
+            TargetHost::Net(ref net) => self.matches_port(addr.port()) && net.contains(&addr.ip()),
+            _ => false,
+        }
+    }
+
+    fn matches_domain(&self, dname: &str, port: u16) -> bool {
+        let dname = dname.trim_end_matches('.');
+        match self.host {
+            TargetHost::Domain(ref d) => self.matches_port(port) && dname.eq_ignore_ascii_case(d),
+            TargetHost::Subdomain(ref d) => {
+                self.matches_port(port)
+                    && dname.len() > d.len() + 1
+                    && dname.as_bytes()[dname.len() - d.len() - 1] == b'.'
+                    && dname[dname.len() - d.len()..].eq_ignore_ascii_case(d)
+            }
+            TargetHost::Net(..) => false,
+        }
+    }
+}
+
+fn parse_host(s: &str) -> Result<TargetHost, String> {
+    if let Ok(net) = s.parse::<IpNet>() {
+        return Ok(TargetHost::Net(net));
+    }
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok(TargetHost::Net(ip.into()));
+    }
+
+    let (subdomain, dname) = match s.strip_prefix("*.") {
+        Some(d) => (true, d),
+        None => (false, s),
+    };
+    let dname = dname.trim_end_matches('.');
+    if dname.is_empty() || dname.contains(['*', '/', '[', ']', ':']) {
+        return Err(format!("invalid target host {:?}", s));
+    }
+
+    let dname = dname.to_ascii_lowercase();
+    if subdomain {
+        Ok(TargetHost::Subdomain(dname))
+    } else {
+        Ok(TargetHost::Domain(dname))
+    }
+}
+
+impl FromStr for TargetRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TargetRule, String> {
+        let s = s.trim();
+
+        // Addresses and networks without ports, IPv6 ones contain `:`
+        if s.parse::<IpNet>().is_ok() || s.parse::<IpAddr>().is_ok() {
+            return Ok(TargetRule {
+                host: parse_host(s)?,
+                port: None,
+            });
+        }
+
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            // [IPV6]:PORT or [IPV6/PREFIX]:PORT
+            let (host, port) = rest.split_once(']').ok_or_else(|| format!("invalid target {:?}", s))?;
+            match port {
+                "" => (host, None),
+                _ => (
+                    host,
+                    Some(
+                        port.strip_prefix(':')
+                            .ok_or_else(|| format!("invalid target {:?}", s))?,
+                    ),
+                ),
+            }
+        } else {
+            match s.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        let port = match port {
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid port in target {:?}", s))?,
+            ),
+            None => None,
+        };
+
+        Ok(TargetRule {
+            host: parse_host(host)?,
+            port,
+        })
+    }
+}
+
+impl Display for TargetHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TargetHost::Net(ref net) if net.prefix_len() == net.max_prefix_len() => net.addr().fmt(f),
+            TargetHost::Net(ref net) => net.fmt(f),
+            TargetHost::Domain(ref d) => f.write_str(d),
+            TargetHost::Subdomain(ref d) => write!(f, "*.{}", d),
+        }
+    }
+}
+
+impl Display for TargetRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.host, self.port) {
+            (TargetHost::Net(IpNet::V6(..)), Some(port)) => write!(f, "[{}]:{}", self.host, port),
+            (host, Some(port)) => write!(f, "{}:{}", host, port),
+            (host, None) => host.fmt(f),
+        }
+    }
+}
+
+/// Rules of `allowed_targets` option, a `,` separated list of networks, addresses or domain names, each with an
+/// optional `:PORT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetList(pub Vec<TargetRule>);
+
+impl TargetList {
+    fn allows_addr(&self, addr: &SocketAddr) -> bool {
+        self.0.iter().any(|r| r.matches_addr(addr))
+    }
+
+    fn allows_domain(&self, dname: &str, port: u16) -> bool {
+        self.0.iter().any(|r| r.matches_domain(dname, port))
+    }
+
+    /// Connect to `target` if it is allowed
+    ///
+    /// Domain names not matched by any domain rule are resolved, and only addresses matched by the address rules are
+    /// connected.
+    pub async fn connect(&self, target: &ServerAddr, opts: &PluginOpts) -> io::Result<TcpStream> {
+        let addrs = match *target {
+            ServerAddr::SocketAddr(ref a) => vec![*a],
+            ServerAddr::DomainName(ref dname, port) => {
+                if self.allows_domain(dname, port) {
+                    return create_outbound_tcp((dname.as_str(), port), opts).await;
+                }
+                lookup_host((dname.as_str(), port)).await?.collect()
+            }
+            ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+        };
+
+        let addrs = addrs.into_iter().filter(|a| self.allows_addr(a)).collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("target {} is not allowed", target),
+            ));
+        }
+
+        create_outbound_tcp(&addrs[..], opts).await
+    }
+}
+
+impl FromStr for TargetList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TargetList, String> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(TargetList)
+    }
+}
+
+impl Display for TargetList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, rule) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            rule.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TargetList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TargetList, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> TargetRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert_eq!(
+            rule("[2001:db8::/32]:443"),
+            TargetRule {
+                host: TargetHost::Net("2001:db8::/32".parse().unwrap()),
+                port: Some(443),
+            }
+        );
+        assert_eq!(
+            rule("[::1]:53"),
+            TargetRule {
+                host: TargetHost::Net("::1/128".parse().unwrap()),
+                port: Some(53),
+            }
+        );
+        assert_eq!(
+            rule("2001:db8::1"),
+            TargetRule {
+                host: TargetHost::Net("2001:db8::1/128".parse().unwrap()),
+                port: None,
+            }
+        );
+        assert_eq!(
+            rule("10.0.0.0/8:80"),
+            TargetRule {
+                host: TargetHost::Net("10.0.0.0/8".parse().unwrap()),
+                port: Some(80),
+            }
+        );
+        assert_eq!(
+            rule("Example.COM.:8080"),
+            TargetRule {
+                host: TargetHost::Domain("example.com".to_owned()),
+                port: Some(8080),
+            }
+        );
+        assert_eq!(
+            rule("*.example.com"),
+            TargetRule {
+                host: TargetHost::Subdomain("example.com".to_owned()),
+                port: None,
+            }
+        );
+
+        for s in [
+            "[::1",
+            "[::1]443",
+            "example.com:http",
+            "example.com:65536",
+            "*.",
+            "a.*.com",
+            "ex/ample",
+        ] {
+            assert!(s.parse::<TargetRule>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn display_rules() {
+        let s = "[2001:db8::/32]:443,::1,10.0.0.1:80,example.com:8080,*.example.com";
+        assert_eq!(s.parse::<TargetList>().unwrap().to_string(), s);
+    }
+
+    #[test]
+    fn match_domains() {
+        let r = rule("*.example.com");
+        assert!(r.matches_domain("www.example.com", 443));
+        assert!(r.matches_domain("a.b.example.com", 443));
+        assert!(r.matches_domain("WWW.Example.Com.", 443));
+        assert!(!r.matches_domain("example.com", 443));
+        assert!(!r.matches_domain("example.com.", 443));
+        assert!(!r.matches_domain("badexample.com", 443));
+        assert!(!r.matches_domain("www.example.com.evil", 443));
+
+        let r = rule("example.com:443");
+        assert!(r.matches_domain("example.com", 443));
+        assert!(r.matches_domain("EXAMPLE.com.", 443));
+        assert!(!r.matches_domain("example.com", 80));
+        assert!(!r.matches_domain("www.example.com", 443));
+        assert!(!r.matches_domain("badexample.com", 443));
+
+        assert!(!rule("0.0.0.0/0").matches_domain("example.com", 443));
+    }
+
+    #[test]
+    fn match_addrs() {
+        let r = rule("[2001:db8::/32]:443");
+        assert!(r.matches_addr(&"[2001:db8::1]:443".parse().unwrap()));
+        assert!(!r.matches_addr(&"[2001:db8::1]:80".parse().unwrap()));
+        assert!(!r.matches_addr(&"[2001:db9::1]:443".parse().unwrap()));
+        assert!(!r.matches_addr(&"10.0.0.1:443".parse().unwrap()));
+        assert!(!rule("*.example.com").matches_addr(&"10.0.0.1:443".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn connect_refuses_other_addrs() {
+        let list = "10.0.0.0/8:443,*.example.com".parse::<TargetList>().unwrap();
+        let opts = PluginOpts::default();
+
+        let targets = [
+            ServerAddr::SocketAddr("127.0.0.1:443".parse().unwrap()),
+            ServerAddr::SocketAddr("10.0.0.1:80".parse().unwrap()),
+            // Not matched by the domain rules, and resolves to an address outside of the list
+            ServerAddr::DomainName("localhost".to_owned(), 443),
+        ];
+        for target in targets {
+            let err = list.connect(&target, &opts).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", target);
+        }
+    }
+}
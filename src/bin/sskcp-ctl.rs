@@ -0,0 +1,138 @@
+use std::{env, process};
+
+use sskcp::admin::{Request, Response};
+
+const USAGE: &str = "usage: sskcp-ctl SOCKET COMMAND
+
+commands:
+    sessions          list sessions
+    kill ID           close a session and its streams
+    drain             stop accepting, and exit when all streams have finished
+    opts              show plugin options
+    log FILTERS       replace log filters, formatted like RUST_LOG
+    bans              list addresses banned by the firewall of server
+    unban IP          lift a ban";
+
+fn parse_request(args: &[String]) -> Option<Request> {
+    let request = match (args.first()?.as_str(), args.get(1)) {
+        ("sessions", None) => Request::Sessions,
+        ("kill", Some(id)) => Request::Kill { id: id.parse().ok()? },
+        ("drain", None) => Request::Drain,
+        ("opts", None) => Request::Opts,
+        ("log", Some(filters)) => Request::LogLevel {
+            filters: filters.clone(),
+        },
+        ("bans", None) => Request::Bans,
+        ("unban", Some(ip)) => Request::Unban { ip: ip.parse().ok()? },
+        _ => return None,
+    };
+    if args.len() > 2 {
+        return None;
+    }
+    Some(request)
+}
+
+fn print_response(response: &Response) {
+    if let Some(ref sessions) = response.sessions {
+        println!(
+            "{:>6}  {:<40}  {:<16}  {:>8}  {:>7}  {:>12}  {:>12}  {:>7}  {:>7}",
+            "ID", "PEER", "USER", "AGE", "STREAMS", "SENT", "RECEIVED", "RTT", "RETRANS"
+        );
+        for s in sessions {
+            let (rtt, retrans) = match s.kcp {
+                Some(ref kcp) => (format!("{}ms", kcp.srtt), kcp.retransmits.to_string()),
+                None => ("-".to_owned(), "-".to_owned()),
+            };
+            println!(
+                "{:>6}  {:<40}  {:<16}  {:>7}s  {:>7}  {:>12}  {:>12}  {:>7}  {:>7}",
+                s.id,
+                s.peer,
+                s.user.as_deref().unwrap_or("-"),
+                s.age,
+                s.streams,
+                s.bytes_sent,
+                s.bytes_received,
+                rtt,
+                retrans
+            );
+        }
+    }
+
+    if let Some(ref refused) = response.refused {
+        if refused.sessions + refused.sessions_per_ip + refused.streams + refused.dials > 0 {
+            println!(
+                "\nrefused: {} sessions, {} sessions per IP, {} streams, {} dials",
+                refused.sessions, refused.sessions_per_ip, refused.streams, refused.dials
+            );
+        }
+    }
+
+    if let Some(ref bans) = response.bans {
+        println!("{:<40}  {:>9}", "IP", "REMAINING");
+        for b in bans {
+            println!("{:<40}  {:>8}s", b.ip, b.remaining);
+        }
+    }
+
+    if let Some(ref opts) = response.opts {
+        println!("{}", serde_json::to_string_pretty(opts).unwrap());
+    }
+}
+
+#[cfg(unix)]
+fn send_request(path: &str, request: &Request) -> std::io::Result<Response> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream = UnixStream::connect(path)?;
+    let mut buf = serde_json::to_vec(request)?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+fn send_request(_path: &str, _request: &Request) -> std::io::Result<Response> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "admin sockets are only supported on unix",
+    ))
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    let (path, request) = match args.split_first() {
+        Some((path, rest)) => match parse_request(rest) {
+            Some(request) => (path, request),
+            None => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        },
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let response = match send_request(path, &request) {
+        Ok(r) => r,
+        Err(err) => {
+            eprintln!("sskcp-ctl: {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    if !response.ok {
+        eprintln!("sskcp-ctl: {}", response.error.as_deref().unwrap_or("command failed"));
+        process::exit(1);
+    }
+
+    print_response(&response);
+}
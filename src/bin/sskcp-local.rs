@@ -1,16 +1,16 @@
 use std::env;
 
-use env_logger::Builder;
 use sskcp::{
+    admin::init_logger,
     config::{Config, ServerAddr},
     local::start_proxy,
     opt::PluginOpts,
+    udp::DatagramLayers,
 };
 
 #[tokio::main]
 async fn main() {
-    let mut builder = Builder::from_default_env();
-    builder.format_timestamp_millis().init();
+    init_logger();
 
     #[cfg(all(unix, not(target_os = "android")))]
     sskcp::adjust_nofile();
@@ -29,10 +29,15 @@ async fn main() {
     }
 
     let config = Config {
-        local_addr: ServerAddr::from_str(local_host, local_port),
-        remote_addr: ServerAddr::from_str(remote_host, remote_port),
+        local_addr: ServerAddr::from_local_str(local_host, local_port),
+        remote_addr: ServerAddr::from_network_str(remote_host, remote_port)
+            .expect("SS_REMOTE_HOST must be a host, not a path"),
         kcp_config: plugin_opts.build_kcp_config(),
         plugin_opts,
+        transport: None,
+        datagram_layers: DatagramLayers::default(),
+        firewall: None,
+        limits: None,
     };
 
     start_proxy(config).await.unwrap();
@@ -0,0 +1,63 @@
+use std::env;
+
+use sskcp::{
+    admin::init_logger,
+    config::{Config, ServerAddr},
+    opt::PluginOpts,
+    relay::start_proxy,
+    udp::DatagramLayers,
+};
+
+#[tokio::main]
+async fn main() {
+    init_logger();
+
+    #[cfg(all(unix, not(target_os = "android")))]
+    sskcp::adjust_nofile();
+
+    let listen_host = env::var("SS_REMOTE_HOST").expect("require SS_REMOTE_HOST");
+    let listen_port = env::var("SS_REMOTE_PORT").expect("require SS_REMOTE_PORT");
+    let next_host = env::var("SSKCP_NEXT_HOST").expect("require SSKCP_NEXT_HOST");
+    let next_port = env::var("SSKCP_NEXT_PORT").expect("require SSKCP_NEXT_PORT");
+
+    let listen_port = listen_port.parse::<u16>().expect("SS_REMOTE_PORT must be a valid port");
+    let next_port = next_port.parse::<u16>().expect("SSKCP_NEXT_PORT must be a valid port");
+
+    let mut plugin_opts = PluginOpts::default();
+    if let Ok(opt) = env::var("SS_PLUGIN_OPTIONS") {
+        plugin_opts = PluginOpts::from_str(&opt).expect("unrecognized SS_PLUGIN_OPTIONS");
+    }
+
+    let mut next_opts = PluginOpts::default();
+    if let Ok(opt) = env::var("SSKCP_NEXT_OPTIONS") {
+        next_opts = PluginOpts::from_str(&opt).expect("unrecognized SSKCP_NEXT_OPTIONS");
+    }
+
+    let listen_addr =
+        ServerAddr::from_network_str(listen_host, listen_port).expect("SS_REMOTE_HOST must be a host, not a path");
+    let next_addr =
+        ServerAddr::from_network_str(next_host, next_port).expect("SSKCP_NEXT_HOST must be a host, not a path");
+
+    let inbound = Config {
+        local_addr: next_addr.clone(),
+        remote_addr: listen_addr.clone(),
+        kcp_config: plugin_opts.build_kcp_config(),
+        plugin_opts,
+        transport: None,
+        datagram_layers: DatagramLayers::default(),
+        firewall: None,
+        limits: None,
+    };
+    let outbound = Config {
+        local_addr: listen_addr,
+        remote_addr: next_addr,
+        kcp_config: next_opts.build_kcp_config(),
+        plugin_opts: next_opts,
+        transport: None,
+        datagram_layers: DatagramLayers::default(),
+        firewall: None,
+        limits: None,
+    };
+
+    start_proxy(inbound, outbound).await.unwrap();
+}
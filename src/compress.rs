@@ -0,0 +1,590 @@
+//! Stream compression between the multiplexer and KCP
+//!
+//! Data is framed in chunks as the [Snappy framing format](https://github.com/google/snappy/blob/main/framing_format.txt)
+//! describes. Each `poll_write` produces exactly one chunk, so nothing is held back waiting for more data, which
+//! keeps interactive traffic flowing. Chunks that do not compress are sent as-is.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressAlgorithm {
+    #[default]
+    #[serde(alias = "false")]
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl CompressAlgorithm {
+    pub fn from_u8(v: u8) -> Option<CompressAlgorithm> {
+        match v {
+            0 => Some(CompressAlgorithm::None),
+            1 => Some(CompressAlgorithm::Snappy),
+            2 => Some(CompressAlgorithm::Lz4),
+            3 => Some(CompressAlgorithm::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            CompressAlgorithm::None => 0,
+            CompressAlgorithm::Snappy => 1,
+            CompressAlgorithm::Lz4 => 2,
+            CompressAlgorithm::Zstd => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressAlgorithm::None => "none",
+            CompressAlgorithm::Snappy => "snappy",
+            CompressAlgorithm::Lz4 => "lz4",
+            CompressAlgorithm::Zstd => "zstd",
+        }
+    }
+}
+
+impl Display for CompressAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Maximum uncompressed bytes in one chunk, limited by the framing format
+const MAX_CHUNK_DATA_SIZE: usize = 65536;
+
+const CHUNK_HEADER_SIZE: usize = 4;
+const CHUNK_CHECKSUM_SIZE: usize = 4;
+
+const CHUNK_TYPE_COMPRESSED: u8 = 0x00;
+const CHUNK_TYPE_UNCOMPRESSED: u8 = 0x01;
+const CHUNK_TYPE_PADDING: u8 = 0xfe;
+const CHUNK_TYPE_STREAM_IDENTIFIER: u8 = 0xff;
+
+const STREAM_IDENTIFIER: &[u8] = b"sNaPpY";
+
+const ZSTD_LEVEL: i32 = 1;
+
+/// Byte counters of a compressed stream
+#[derive(Debug, Default)]
+pub struct CompressStats {
+    sent_raw: AtomicU64,
+    sent_wire: AtomicU64,
+    received_raw: AtomicU64,
+    received_wire: AtomicU64,
+}
+
+impl CompressStats {
+    /// Bytes written by the multiplexer
+    pub fn sent_raw(&self) -> u64 {
+        self.sent_raw.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent to the KCP session
+    pub fn sent_wire(&self) -> u64 {
+        self.sent_wire.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read by the multiplexer
+    pub fn received_raw(&self) -> u64 {
+        self.received_raw.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from the KCP session
+    pub fn received_wire(&self) -> u64 {
+        self.received_wire.load(Ordering::Relaxed)
+    }
+
+    /// Compressed size / uncompressed size of sent data
+    pub fn sent_ratio(&self) -> f64 {
+        ratio(self.sent_wire(), self.sent_raw())
+    }
+
+    /// Compressed size / uncompressed size of received data
+    pub fn received_ratio(&self) -> f64 {
+        ratio(self.received_wire(), self.received_raw())
+    }
+}
+
+fn ratio(wire: u64, raw: u64) -> f64 {
+    if raw == 0 {
+        1.0
+    } else {
+        wire as f64 / raw as f64
+    }
+}
+
+enum Codec {
+    Snappy(Box<(snap::raw::Encoder, snap::raw::Decoder)>),
+    Lz4,
+    Zstd(zstd::bulk::Compressor<'static>, zstd::bulk::Decompressor<'static>),
+}
+
+impl Codec {
+    fn new(algorithm: CompressAlgorithm) -> io::Result<Codec> {
+        match algorithm {
+            CompressAlgorithm::Snappy => Ok(Codec::Snappy(Box::new((
+                snap::raw::Encoder::new(),
+                snap::raw::Decoder::new(),
+            )))),
+            CompressAlgorithm::Lz4 => Ok(Codec::Lz4),
+            CompressAlgorithm::Zstd => Ok(Codec::Zstd(
+                zstd::bulk::Compressor::new(ZSTD_LEVEL)?,
+                zstd::bulk::Decompressor::new()?,
+            )),
+            CompressAlgorithm::None => Err(io::Error::new(ErrorKind::InvalidInput, "compress algorithm is none")),
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Codec::Snappy(ref mut codec) => codec.0.compress_vec(data).map_err(Into::into),
+            Codec::Lz4 => Ok(lz4_flex::block::compress_prepend_size(data)),
+            Codec::Zstd(ref mut enc, _) => enc.compress(data),
+        }
+    }
+
+    /// Maximum length of a chunk on the wire, including the checksum
+    fn max_chunk_size(&self) -> usize {
+        let max_compressed = match *self {
+            Codec::Snappy(..) => snap::raw::max_compress_len(MAX_CHUNK_DATA_SIZE),
+            Codec::Lz4 => 4 + lz4_flex::block::get_maximum_output_size(MAX_CHUNK_DATA_SIZE),
+            Codec::Zstd(..) => zstd::zstd_safe::compress_bound(MAX_CHUNK_DATA_SIZE),
+        };
+        CHUNK_CHECKSUM_SIZE + max_compressed.max(MAX_CHUNK_DATA_SIZE)
+    }
+
+    fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid compressed chunk");
+
+        match *self {
+            Codec::Snappy(ref mut codec) => {
+                let len = snap::raw::decompress_len(data).map_err(|_| invalid())?;
+                if len > MAX_CHUNK_DATA_SIZE {
+                    return Err(invalid());
+                }
+                codec.1.decompress_vec(data).map_err(|_| invalid())
+            }
+            Codec::Lz4 => {
+                if data.len() < 4
+                    || u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize > MAX_CHUNK_DATA_SIZE
+                {
+                    return Err(invalid());
+                }
+                lz4_flex::block::decompress_size_prepended(data).map_err(|_| invalid())
+            }
+            Codec::Zstd(_, ref mut dec) => dec.decompress(data, MAX_CHUNK_DATA_SIZE),
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli) lookup table
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Masked CRC-32C of uncompressed data, as the framing format requires
+fn masked_crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    let crc = !crc;
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+fn put_chunk_header(buf: &mut Vec<u8>, chunk_type: u8, len: usize) {
+    buf.push(chunk_type);
+    buf.extend_from_slice(&(len as u32).to_le_bytes()[..3]);
+}
+
+/// Compressed stream wrapper
+pub struct CompressedStream<S> {
+    stream: S,
+    algorithm: CompressAlgorithm,
+    codec: Codec,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    read_buf: Vec<u8>,
+    decoded: Vec<u8>,
+    decoded_pos: usize,
+    stats: Arc<CompressStats>,
+}
+
+impl<S> CompressedStream<S> {
+    pub fn new(stream: S, algorithm: CompressAlgorithm) -> io::Result<CompressedStream<S>> {
+        let codec = Codec::new(algorithm)?;
+
+        // Snappy framed streams start with a stream identifier, so peers using a standard framed reader can decode it
+        let mut write_buf = Vec::new();
+        if let CompressAlgorithm::Snappy = algorithm {
+            put_chunk_header(&mut write_buf, CHUNK_TYPE_STREAM_IDENTIFIER, STREAM_IDENTIFIER.len());
+            write_buf.extend_from_slice(STREAM_IDENTIFIER);
+        }
+
+        Ok(CompressedStream {
+            stream,
+            algorithm,
+            codec,
+            write_buf,
+            write_pos: 0,
+            read_buf: Vec::new(),
+            decoded: Vec::new(),
+            decoded_pos: 0,
+            stats: Arc::new(CompressStats::default()),
+        })
+    }
+
+    pub fn algorithm(&self) -> CompressAlgorithm {
+        self.algorithm
+    }
+
+    pub fn stats(&self) -> Arc<CompressStats> {
+        self.stats.clone()
+    }
+
+    fn encode_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let compressed = self.codec.compress(data)?;
+        let checksum = masked_crc32c(data);
+
+        let start = self.write_buf.len();
+        if compressed.len() < data.len() {
+            put_chunk_header(
+                &mut self.write_buf,
+                CHUNK_TYPE_COMPRESSED,
+                CHUNK_CHECKSUM_SIZE + compressed.len(),
+            );
+            self.write_buf.extend_from_slice(&checksum.to_le_bytes());
+            self.write_buf.extend_from_slice(&compressed);
+        } else {
+            put_chunk_header(
+                &mut self.write_buf,
+                CHUNK_TYPE_UNCOMPRESSED,
+                CHUNK_CHECKSUM_SIZE + data.len(),
+            );
+            self.write_buf.extend_from_slice(&checksum.to_le_bytes());
+            self.write_buf.extend_from_slice(data);
+        }
+
+        self.stats.sent_raw.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.stats
+            .sent_wire
+            .fetch_add((self.write_buf.len() - start) as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Decode one chunk from `read_buf`. Returns `false` if more data is required.
+    fn decode_chunk(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < CHUNK_HEADER_SIZE {
+            return Ok(false);
+        }
+
+        let chunk_type = self.read_buf[0];
+        let len = u32::from_le_bytes([self.read_buf[1], self.read_buf[2], self.read_buf[3], 0]) as usize;
+        if len > self.codec.max_chunk_size() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "chunk too large"));
+        }
+        if self.read_buf.len() < CHUNK_HEADER_SIZE + len {
+            return Ok(false);
+        }
+
+        let chunk = &self.read_buf[CHUNK_HEADER_SIZE..CHUNK_HEADER_SIZE + len];
+        match chunk_type {
+            CHUNK_TYPE_COMPRESSED | CHUNK_TYPE_UNCOMPRESSED => {
+                if len < CHUNK_CHECKSUM_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "chunk too short"));
+                }
+
+                let checksum = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                let payload = &chunk[CHUNK_CHECKSUM_SIZE..];
+
+                let data = if chunk_type == CHUNK_TYPE_COMPRESSED {
+                    self.codec.decompress(payload)?
+                } else if payload.len() > MAX_CHUNK_DATA_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "uncompressed chunk too large"));
+                } else {
+                    payload.to_vec()
+                };
+
+                if masked_crc32c(&data) != checksum {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "chunk checksum mismatch"));
+                }
+
+                self.stats.received_raw.fetch_add(data.len() as u64, Ordering::Relaxed);
+                self.decoded = data;
+                self.decoded_pos = 0;
+            }
+            CHUNK_TYPE_STREAM_IDENTIFIER => {
+                if chunk != STREAM_IDENTIFIER {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "invalid stream identifier"));
+                }
+            }
+            0x80..=CHUNK_TYPE_PADDING => {
+                // Skippable chunks
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unskippable chunk type {:#x}", chunk_type),
+                ));
+            }
+        }
+
+        self.stats
+            .received_wire
+            .fetch_add((CHUNK_HEADER_SIZE + len) as u64, Ordering::Relaxed);
+        self.read_buf.drain(..CHUNK_HEADER_SIZE + len);
+
+        Ok(true)
+    }
+}
+
+impl<S> CompressedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncRead for CompressedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.decoded_pos < this.decoded.len() {
+                let n = buf.remaining().min(this.decoded.len() - this.decoded_pos);
+                buf.put_slice(&this.decoded[this.decoded_pos..this.decoded_pos + n]);
+                this.decoded_pos += n;
+                return Ok(()).into();
+            }
+
+            if this.decode_chunk()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 16384];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+
+            let n = read_buf.filled().len();
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+            this.read_buf.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+impl<S> AsyncWrite for CompressedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_buffered(cx))?;
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let n = buf.len().min(MAX_CHUNK_DATA_SIZE);
+        this.encode_chunk(&buf[..n])?;
+
+        // Data is accepted. Try to send it out now, the rest will be sent in the next write or flush.
+        if let Poll::Ready(Err(err)) = this.poll_write_buffered(cx) {
+            return Err(err).into();
+        }
+
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl<S> Drop for CompressedStream<S> {
+    fn drop(&mut self) {
+        info!(
+            "compress {} sent {} -> {} bytes (ratio {:.3}), received {} <- {} bytes (ratio {:.3})",
+            self.algorithm,
+            self.stats.sent_raw(),
+            self.stats.sent_wire(),
+            self.stats.sent_ratio(),
+            self.stats.received_raw(),
+            self.stats.received_wire(),
+            self.stats.received_ratio(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const ALGORITHMS: [CompressAlgorithm; 3] = [
+        CompressAlgorithm::Snappy,
+        CompressAlgorithm::Lz4,
+        CompressAlgorithm::Zstd,
+    ];
+
+    /// Send `data` through a compressed stream, returning what was received and the stats of the sender
+    async fn round_trip(algorithm: CompressAlgorithm, data: &[u8]) -> (Vec<u8>, Arc<CompressStats>) {
+        let (a, b) = duplex(64 * 1024);
+        let mut writer = CompressedStream::new(a, algorithm).unwrap();
+        let mut reader = CompressedStream::new(b, algorithm).unwrap();
+        let stats = writer.stats();
+
+        let data = data.to_vec();
+        let write = tokio::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        write.await.unwrap();
+        (received, stats)
+    }
+
+    /// Encoded chunks of `data`
+    fn encode(algorithm: CompressAlgorithm, data: &[u8]) -> Vec<u8> {
+        let mut stream = CompressedStream::new((), algorithm).unwrap();
+        stream.encode_chunk(data).unwrap();
+        std::mem::take(&mut stream.write_buf)
+    }
+
+    async fn read_encoded(algorithm: CompressAlgorithm, encoded: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut reader = CompressedStream::new(&encoded[..], algorithm).unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.map(|_| received)
+    }
+
+    #[tokio::test]
+    async fn compressible() {
+        let data = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n".repeat(5000);
+        for algorithm in ALGORITHMS {
+            let (received, stats) = round_trip(algorithm, &data).await;
+            assert_eq!(received, data, "{}", algorithm);
+            assert_eq!(stats.sent_raw(), data.len() as u64);
+            assert!(stats.sent_wire() < stats.sent_raw() / 4, "{}", algorithm);
+        }
+    }
+
+    #[tokio::test]
+    async fn incompressible() {
+        let mut data = vec![0u8; 3 * MAX_CHUNK_DATA_SIZE + 123];
+        rand::thread_rng().fill_bytes(&mut data);
+        for algorithm in ALGORITHMS {
+            let (received, stats) = round_trip(algorithm, &data).await;
+            assert_eq!(received, data, "{}", algorithm);
+            // Sent as uncompressed chunks, with only the chunk headers added
+            assert!(stats.sent_wire() <= stats.sent_raw() + 16 * 8, "{}", algorithm);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let data = b"abcdefgh".repeat(1000);
+        for algorithm in ALGORITHMS {
+            let mut encoded = encode(algorithm, &data);
+            encoded.pop();
+            let err = read_encoded(algorithm, encoded).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{}", algorithm);
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted() {
+        let data = b"abcdefgh".repeat(1000);
+        for algorithm in ALGORITHMS {
+            let mut encoded = encode(algorithm, &data);
+            // First byte of the checksum, after the stream identifier of snappy
+            let start = match algorithm {
+                CompressAlgorithm::Snappy => CHUNK_HEADER_SIZE + STREAM_IDENTIFIER.len(),
+                _ => 0,
+            };
+            encoded[start + CHUNK_HEADER_SIZE] ^= 1;
+            let err = read_encoded(algorithm, encoded).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", algorithm);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized() {
+        for algorithm in ALGORITHMS {
+            let max = Codec::new(algorithm).unwrap().max_chunk_size();
+            let mut encoded = Vec::new();
+            put_chunk_header(&mut encoded, CHUNK_TYPE_UNCOMPRESSED, max + 1);
+            // Rejected by the header, before the chunk is buffered
+            let err = read_encoded(algorithm, encoded).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", algorithm);
+        }
+    }
+}
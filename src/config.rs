@@ -1,16 +1,21 @@
 use std::{
     fmt::{self, Display},
+    io::{self, ErrorKind},
     net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use tokio_kcp::KcpConfig;
 
-use crate::opt::PluginOpts;
+use crate::{firewall::Firewall, limit::Limits, opt::PluginOpts, transport::Transport, udp::DatagramLayers};
 
 #[derive(Clone, Debug)]
 pub enum ServerAddr {
     SocketAddr(SocketAddr),
     DomainName(String, u16),
+    /// Path of a unix domain socket
+    UnixSocket(PathBuf),
 }
 
 impl ServerAddr {
@@ -21,10 +26,40 @@ impl ServerAddr {
         }
     }
 
+    /// Address of `host` and `port`, refusing the path of a unix domain socket where only network addresses work
+    pub fn from_network_str(host: String, port: u16) -> io::Result<ServerAddr> {
+        if host.starts_with('/') {
+            return Err(network_required(Path::new(&host)));
+        }
+        Ok(ServerAddr::from_str(host, port))
+    }
+
+    /// Address of `SS_LOCAL_HOST` and `SS_LOCAL_PORT`, or the unix domain socket at the host if it is an absolute path
+    ///
+    /// Only the upstream of server and the listener of local may be unix domain sockets.
+    pub fn from_local_str(host: String, port: u16) -> ServerAddr {
+        if host.starts_with('/') {
+            return ServerAddr::UnixSocket(PathBuf::from(host));
+        }
+        ServerAddr::from_str(host, port)
+    }
+
+    /// Parse `HOST:PORT` or `[IPV6]:PORT`
+    pub fn from_host_port(s: &str) -> Option<ServerAddr> {
+        let (host, port) = s.rsplit_once(':')?;
+        let port = port.parse::<u16>().ok()?;
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() || host.starts_with('/') {
+            return None;
+        }
+        Some(ServerAddr::from_str(host.to_owned(), port))
+    }
+
     pub fn host(&self) -> String {
         match *self {
             ServerAddr::SocketAddr(ref addr) => addr.ip().to_string(),
             ServerAddr::DomainName(ref domain, _) => domain.clone(),
+            ServerAddr::UnixSocket(ref path) => path.display().to_string(),
         }
     }
 
@@ -32,15 +67,25 @@ impl ServerAddr {
         match *self {
             ServerAddr::SocketAddr(ref addr) => addr.port(),
             ServerAddr::DomainName(_, port) => port,
+            ServerAddr::UnixSocket(..) => 0,
         }
     }
 }
 
+/// Error of a unix domain socket at `path` where only network addresses work
+pub fn network_required(path: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!("unix domain socket {} is not a network address", path.display()),
+    )
+}
+
 impl Display for ServerAddr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ServerAddr::SocketAddr(ref addr) => addr.fmt(f),
             ServerAddr::DomainName(ref domain, ref port) => write!(f, "{}:{}", domain, port),
+            ServerAddr::UnixSocket(ref path) => path.display().fmt(f),
         }
     }
 }
@@ -51,4 +96,39 @@ pub struct Config {
     pub remote_addr: ServerAddr,
     pub kcp_config: KcpConfig,
     pub plugin_opts: PluginOpts,
+    /// Carries sessions instead of the transports in `transport` option
+    pub transport: Option<Arc<dyn Transport>>,
+    /// Layers that UDP datagrams of KCP sessions go through
+    pub datagram_layers: DatagramLayers,
+    /// Source address filter of server, built from `allowed_ips`, `denied_ips` and `ban_*` options if unset
+    pub firewall: Option<Arc<Firewall>>,
+    /// Admission control of server, built from `max_*` and `rate_*` options if unset
+    pub limits: Option<Arc<Limits>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_socket_paths() {
+        assert!(matches!(
+            ServerAddr::from_local_str("/run/ss.sock".to_owned(), 0),
+            ServerAddr::UnixSocket(ref path) if path == Path::new("/run/ss.sock")
+        ));
+        assert!(matches!(
+            ServerAddr::from_local_str("127.0.0.1".to_owned(), 8388),
+            ServerAddr::SocketAddr(..)
+        ));
+
+        let err = ServerAddr::from_network_str("/run/ss.sock".to_owned(), 4000).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(err.to_string().contains("/run/ss.sock"), "{}", err);
+        assert!(matches!(
+            ServerAddr::from_network_str("example.com".to_owned(), 4000),
+            Ok(ServerAddr::DomainName(..))
+        ));
+
+        assert!(ServerAddr::from_host_port("/run/ss.sock:80").is_none());
+    }
 }
@@ -0,0 +1,390 @@
+//! End-to-end encryption between local and server
+//!
+//! With `e2e_key`, KCP sessions are encrypted by local and server, so relays in between cannot read them. Each side
+//! starts by sending a random salt, then a tag proving that it has `e2e_key`. Keys of both directions and the tags are
+//! derived from `e2e_key` and both salts, client's first, with HKDF-SHA256 and a different info for each. Data is sent
+//! in ChaCha20-Poly1305 frames, with counters as nonces.
+//!
+//! ```plain
+//! +--------+------------+-----+
+//! | LENGTH | CIPHERTEXT | TAG |
+//! +--------+------------+-----+
+//! | 2 (BE) |            | 16  |
+//! +--------+------------+-----+
+//! ```
+//!
+//! `LENGTH` is the size of `CIPHERTEXT` and `TAG`, authenticated as associated data.
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures::ready;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time,
+};
+
+use crate::{
+    opt::PluginOpts,
+    session::{BoxedSessionStream, SessionStream},
+};
+
+const SALT_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const CONFIRM_SIZE: usize = 16;
+const LENGTH_SIZE: usize = 2;
+
+/// Maximum plaintext bytes in one frame
+const MAX_FRAME_DATA_SIZE: usize = 16384;
+
+const INFO_CLIENT: &[u8] = b"sskcp e2e client to server";
+const INFO_SERVER: &[u8] = b"sskcp e2e server to client";
+const INFO_CLIENT_CONFIRM: &[u8] = b"sskcp e2e client confirm";
+const INFO_SERVER_CONFIRM: &[u8] = b"sskcp e2e server confirm";
+
+/// Timeout for receiving the salt and the tag of the peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which end of the session, keys of the two directions are derived differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameCipher {
+    fn new(hkdf: &Hkdf<Sha256>, info: &[u8]) -> FrameCipher {
+        let mut okm = [0u8; 32];
+        hkdf.expand(info, &mut okm)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        FrameCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&okm)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8], buf: &mut Vec<u8>) {
+        let len = ((data.len() + TAG_SIZE) as u16).to_be_bytes();
+        let nonce = self.next_nonce();
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &len })
+            .expect("ChaCha20-Poly1305 encrypts frames of any size");
+
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(&ciphertext);
+    }
+
+    fn open(&mut self, len: [u8; LENGTH_SIZE], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &len,
+                },
+            )
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "e2e frame authentication failed"))
+    }
+}
+
+/// Encrypt a new KCP session if `e2e_key` is set
+pub async fn e2e_session<S>(stream: S, opts: &PluginOpts, role: Role) -> io::Result<BoxedSessionStream>
+where
+    S: SessionStream + 'static,
+{
+    let key = match opts.e2e_key {
+        Some(ref key) => key,
+        None => return Ok(Box::new(stream)),
+    };
+
+    match time::timeout(HANDSHAKE_TIMEOUT, EncryptedStream::handshake(stream, key, role)).await {
+        Ok(r) => Ok(Box::new(r?)),
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "e2e handshake timeout")),
+    }
+}
+
+/// Stream encrypted end-to-end
+pub struct EncryptedStream<S> {
+    stream: S,
+    send: FrameCipher,
+    recv: FrameCipher,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    read_buf: Vec<u8>,
+    decrypted: Vec<u8>,
+    decrypted_pos: usize,
+}
+
+impl<S> EncryptedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Exchange salts with the peer on `stream`, and check that it has the same `key`
+    pub async fn handshake(mut stream: S, key: &str, role: Role) -> io::Result<EncryptedStream<S>> {
+        let mut salt = [0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+        stream.write_all(&salt).await?;
+        stream.flush().await?;
+
+        let mut peer_salt = [0u8; SALT_SIZE];
+        stream.read_exact(&mut peer_salt).await?;
+
+        let mut salts = [0u8; SALT_SIZE * 2];
+        let (send_info, recv_info, confirm_info, peer_confirm_info) = match role {
+            Role::Client => {
+                salts[..SALT_SIZE].copy_from_slice(&salt);
+                salts[SALT_SIZE..].copy_from_slice(&peer_salt);
+                (INFO_CLIENT, INFO_SERVER, INFO_CLIENT_CONFIRM, INFO_SERVER_CONFIRM)
+            }
+            Role::Server => {
+                salts[..SALT_SIZE].copy_from_slice(&peer_salt);
+                salts[SALT_SIZE..].copy_from_slice(&salt);
+                (INFO_SERVER, INFO_CLIENT, INFO_SERVER_CONFIRM, INFO_CLIENT_CONFIRM)
+            }
+        };
+        let hkdf = Hkdf::<Sha256>::new(Some(&salts), key.as_bytes());
+
+        let mut confirm = [0u8; CONFIRM_SIZE];
+        hkdf.expand(confirm_info, &mut confirm)
+            .expect("16 bytes is a valid HKDF-SHA256 output length");
+        stream.write_all(&confirm).await?;
+        stream.flush().await?;
+
+        let mut expected = [0u8; CONFIRM_SIZE];
+        hkdf.expand(peer_confirm_info, &mut expected)
+            .expect("16 bytes is a valid HKDF-SHA256 output length");
+        let mut peer_confirm = [0u8; CONFIRM_SIZE];
+        stream.read_exact(&mut peer_confirm).await?;
+
+        // Compare in constant time
+        let diff = expected
+            .iter()
+            .zip(peer_confirm.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "e2e_key mismatch"));
+        }
+
+        Ok(EncryptedStream {
+            stream,
+            send: FrameCipher::new(&hkdf, send_info),
+            recv: FrameCipher::new(&hkdf, recv_info),
+            write_buf: Vec::new(),
+            write_pos: 0,
+            read_buf: Vec::new(),
+            decrypted: Vec::new(),
+            decrypted_pos: 0,
+        })
+    }
+}
+
+impl<S> EncryptedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncRead for EncryptedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.decrypted_pos < this.decrypted.len() {
+                let n = buf.remaining().min(this.decrypted.len() - this.decrypted_pos);
+                buf.put_slice(&this.decrypted[this.decrypted_pos..this.decrypted_pos + n]);
+                this.decrypted_pos += n;
+                return Ok(()).into();
+            }
+
+            if this.read_buf.len() >= LENGTH_SIZE {
+                let len = [this.read_buf[0], this.read_buf[1]];
+                let frame_len = u16::from_be_bytes(len) as usize;
+                if frame_len < TAG_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "e2e frame too short")).into();
+                }
+
+                if this.read_buf.len() >= LENGTH_SIZE + frame_len {
+                    this.decrypted = this
+                        .recv
+                        .open(len, &this.read_buf[LENGTH_SIZE..LENGTH_SIZE + frame_len])?;
+                    this.decrypted_pos = 0;
+                    this.read_buf.drain(..LENGTH_SIZE + frame_len);
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 16384];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+
+            let n = read_buf.filled().len();
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+            this.read_buf.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+impl<S> AsyncWrite for EncryptedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_buffered(cx))?;
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let n = buf.len().min(MAX_FRAME_DATA_SIZE);
+        this.send.seal(&buf[..n], &mut this.write_buf);
+
+        if let Poll::Ready(Err(err)) = this.poll_write_buffered(cx) {
+            return Err(err).into();
+        }
+
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, DuplexStream};
+
+    async fn handshake_pair(
+        client_key: &str,
+        server_key: &str,
+    ) -> (
+        io::Result<EncryptedStream<DuplexStream>>,
+        io::Result<EncryptedStream<DuplexStream>>,
+    ) {
+        let (client, server) = duplex(65536);
+        tokio::join!(
+            EncryptedStream::handshake(client, client_key, Role::Client),
+            EncryptedStream::handshake(server, server_key, Role::Server),
+        )
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (client, server) = handshake_pair("secret", "secret").await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let data = (0..100000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut received = vec![0u8; data.len()];
+        let (sent, read) = tokio::join!(
+            async {
+                client.write_all(&data).await?;
+                client.flush().await
+            },
+            server.read_exact(&mut received),
+        );
+        sent.unwrap();
+        read.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"reply").await.unwrap();
+        server.flush().await.unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"reply");
+    }
+
+    #[tokio::test]
+    async fn key_mismatch() {
+        let (client, server) = handshake_pair("secret", "other").await;
+        assert_eq!(client.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(server.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn directions_differ() {
+        let hkdf = Hkdf::<Sha256>::new(Some(&[1u8; SALT_SIZE * 2]), b"secret");
+        let mut client_send = FrameCipher::new(&hkdf, INFO_CLIENT);
+        let mut client_recv = FrameCipher::new(&hkdf, INFO_SERVER);
+        let mut server_recv = FrameCipher::new(&hkdf, INFO_CLIENT);
+
+        let mut frame = Vec::new();
+        client_send.seal(b"hello", &mut frame);
+        let len = [frame[0], frame[1]];
+        // A frame reflected back to its sender does not open
+        assert!(client_recv.open(len, &frame[LENGTH_SIZE..]).is_err());
+        assert_eq!(server_recv.open(len, &frame[LENGTH_SIZE..]).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn tampered_frame() {
+        let hkdf = Hkdf::<Sha256>::new(Some(&[1u8; SALT_SIZE * 2]), b"secret");
+        let mut send = FrameCipher::new(&hkdf, INFO_CLIENT);
+        let mut recv = FrameCipher::new(&hkdf, INFO_CLIENT);
+
+        let mut frame = Vec::new();
+        send.seal(b"hello", &mut frame);
+        frame[LENGTH_SIZE] ^= 1;
+        let len = [frame[0], frame[1]];
+        assert!(recv.open(len, &frame[LENGTH_SIZE..]).is_err());
+    }
+}
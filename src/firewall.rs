@@ -0,0 +1,284 @@
+//! Source address filter of server
+//!
+//! With `allowed_ips`, server only talks to clients in those networks. With `denied_ips`, it ignores clients in those
+//! networks. Datagrams of other clients are dropped by the UDP relay in front of the KCP listener, before any KCP
+//! state is created for them, and their TCP connections are closed as soon as they are accepted.
+//!
+//! With `ban_failures`, clients whose sessions fail their handshakes that many times within `ban_window` are banned
+//! for `ban_time`, and everything from them is dropped too. Bans are logged, and listed and removed by
+//! `sskcp-ctl bans` and `sskcp-ctl unban IP`.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use log::{info, warn};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::time::Instant;
+
+use crate::opt::PluginOpts;
+
+/// Default of `ban_window`, in seconds
+const DEFAULT_BAN_WINDOW: u64 = 60;
+
+/// Default of `ban_time`, in seconds
+const DEFAULT_BAN_TIME: u64 = 600;
+
+/// Addresses whose failures are counted at most, older windows are forgotten beyond this
+const MAX_TRACKED: usize = 4096;
+
+/// Networks of `allowed_ips` and `denied_ips` options, a `,` separated list of networks or addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpNetList(pub Vec<IpNet>);
+
+impl IpNetList {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl FromStr for IpNetList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpNetList, String> {
+        s.split(',')
+            .map(|s| match s.parse::<IpNet>() {
+                Ok(net) => Ok(net),
+                Err(..) => s
+                    .parse::<IpAddr>()
+                    .map(IpNet::from)
+                    .map_err(|_| format!("invalid network {:?}", s)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(IpNetList)
+    }
+}
+
+impl Display for IpNetList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, net) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            net.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for IpNetList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<IpNetList, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Failures of an address in the current window
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    window_start: Instant,
+}
+
+/// A banned address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    /// Seconds until the ban is lifted
+    pub remaining: u64,
+}
+
+/// Allowed and denied networks, and bans of addresses that failed too often
+#[derive(Debug)]
+pub struct Firewall {
+    allowed: Option<IpNetList>,
+    denied: Option<IpNetList>,
+    ban_failures: Option<u32>,
+    ban_window: Duration,
+    ban_time: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    /// Banned addresses, with when they are lifted
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Firewall {
+    /// Firewall of `allowed_ips`, `denied_ips` and `ban_*` options, `None` if it would let everything through
+    pub fn from_plugin_opts(opts: &PluginOpts) -> Option<Arc<Firewall>> {
+        let ban_failures = opts.ban_failures.filter(|&n| n > 0);
+        if opts.allowed_ips.is_none() && opts.denied_ips.is_none() && ban_failures.is_none() {
+            return None;
+        }
+
+        Some(Arc::new(Firewall {
+            allowed: opts.allowed_ips.clone(),
+            denied: opts.denied_ips.clone(),
+            ban_failures,
+            ban_window: Duration::from_secs(opts.ban_window.unwrap_or(DEFAULT_BAN_WINDOW)),
+            ban_time: Duration::from_secs(opts.ban_time.unwrap_or(DEFAULT_BAN_TIME)),
+            failures: Mutex::new(HashMap::new()),
+            bans: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Whether anything from `ip` may pass
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.allowed.as_ref().is_some_and(|nets| !nets.contains(&ip)) {
+            return false;
+        }
+        if self.denied.as_ref().is_some_and(|nets| nets.contains(&ip)) {
+            return false;
+        }
+
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(&until) if Instant::now() < until => false,
+            Some(..) => {
+                bans.remove(&ip);
+                info!("ban of {} lifted", ip);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Count a failed handshake of `ip`, banning it if it has failed `ban_failures` times within `ban_window`
+    pub fn record_failure(&self, ip: IpAddr) {
+        let max = match self.ban_failures {
+            Some(n) => n,
+            None => return,
+        };
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED {
+            failures.retain(|_, f| now - f.window_start < self.ban_window);
+        }
+
+        let f = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            window_start: now,
+        });
+        if now - f.window_start >= self.ban_window {
+            f.count = 0;
+            f.window_start = now;
+        }
+        f.count += 1;
+        if f.count < max {
+            return;
+        }
+        failures.remove(&ip);
+        drop(failures);
+
+        self.bans.lock().unwrap().insert(ip, now + self.ban_time);
+        warn!(
+            "banned {} for {}s after {} failed handshakes",
+            ip,
+            self.ban_time.as_secs(),
+            max
+        );
+    }
+
+    /// Addresses that are banned now
+    pub fn bans(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, until| *until > now);
+
+        let mut list = bans
+            .iter()
+            .map(|(&ip, &until)| BanInfo {
+                ip,
+                remaining: (until - now).as_secs(),
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|b| b.ip);
+        list
+    }
+
+    /// Lift the ban of `ip`, returns `false` if it is not banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        match self.bans.lock().unwrap().remove(&ip) {
+            Some(until) if until > now => {
+                info!("ban of {} lifted", ip);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firewall(opts: &str) -> Arc<Firewall> {
+        Firewall::from_plugin_opts(&PluginOpts::from_str(opts).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn allowed_and_denied() {
+        assert!(Firewall::from_plugin_opts(&PluginOpts::default()).is_none());
+
+        let fw = firewall("allowed_ips=10.0.0.0/8,fd00::/8&denied_ips=10.0.0.1");
+        assert!(fw.permits("10.1.2.3".parse().unwrap()));
+        assert!(fw.permits("fd00::1".parse().unwrap()));
+        assert!(!fw.permits("10.0.0.1".parse().unwrap()));
+        assert!(!fw.permits("192.168.0.1".parse().unwrap()));
+        // IPv4-mapped IPv6 addresses of dual-stack sockets are checked as IPv4
+        assert!(fw.permits("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!fw.permits("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_after_failures() {
+        let fw = firewall("ban_failures=3&ban_time=60");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        fw.record_failure(ip);
+        fw.record_failure(ip);
+        fw.record_failure(other);
+        assert!(fw.permits(ip));
+        assert!(fw.bans().is_empty());
+
+        fw.record_failure("::ffff:192.0.2.1".parse().unwrap());
+        assert!(!fw.permits(ip));
+        assert!(fw.permits(other));
+        let bans = fw.bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].ip, ip);
+
+        assert!(fw.unban(ip));
+        assert!(!fw.unban(ip));
+        assert!(fw.permits(ip));
+    }
+
+    #[tokio::test]
+    async fn failures_outside_window() {
+        let fw = firewall("ban_failures=2&ban_window=1");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        fw.record_failure(ip);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        fw.record_failure(ip);
+        assert!(fw.permits(ip));
+        fw.record_failure(ip);
+        assert!(!fw.permits(ip));
+    }
+}
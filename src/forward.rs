@@ -0,0 +1,168 @@
+//! Port forwarding mappings of local
+//!
+//! Each mapping listens on an address of local, and the server connects its connections to the mapping's target,
+//! which is sent in stream headers. All mappings share the pooled KCP sessions.
+//!
+//! Reverse mappings listen on ports of the server, and local connects their connections to the mapping's target.
+//! Local keeps a KCP session for them, and the server opens a stream in it for each connection, starting with the
+//! port (2 bytes, BE) that accepted it.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::ServerAddr;
+
+fn parse_server_addr(s: &str) -> Result<ServerAddr, String> {
+    ServerAddr::from_host_port(s.trim()).ok_or_else(|| format!("invalid address {:?}, should be HOST:PORT", s))
+}
+
+/// Connections accepted on `listen` are connected to `target` by the server, formatted as `LISTEN=TARGET`
+#[derive(Debug, Clone)]
+pub struct ForwardMapping {
+    pub listen: ServerAddr,
+    pub target: ServerAddr,
+}
+
+impl FromStr for ForwardMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ForwardMapping, String> {
+        let (listen, target) = s
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("invalid mapping {:?}, should be LISTEN=TARGET", s))?;
+        Ok(ForwardMapping {
+            listen: parse_server_addr(listen)?,
+            target: parse_server_addr(target)?,
+        })
+    }
+}
+
+impl Display for ForwardMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.listen, self.target)
+    }
+}
+
+/// Mappings of `forward` option, a `,` separated list of `LISTEN=TARGET`
+#[derive(Debug, Clone)]
+pub struct ForwardList(pub Vec<ForwardMapping>);
+
+impl FromStr for ForwardList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ForwardList, String> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(ForwardList)
+    }
+}
+
+impl Display for ForwardList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, mapping) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            mapping.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ForwardList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ForwardList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ForwardList, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Connections accepted by the server on `port` are connected to `target` by local, formatted as `PORT=TARGET`
+#[derive(Debug, Clone)]
+pub struct ReverseMapping {
+    pub port: u16,
+    pub target: ServerAddr,
+}
+
+impl FromStr for ReverseMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReverseMapping, String> {
+        let (port, target) = s
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("invalid mapping {:?}, should be PORT=TARGET", s))?;
+        Ok(ReverseMapping {
+            port: port.parse().map_err(|_| format!("invalid port in {:?}", s))?,
+            target: parse_server_addr(target)?,
+        })
+    }
+}
+
+impl Display for ReverseMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.port, self.target)
+    }
+}
+
+/// Mappings of `reverse` option, a `,` separated list of `PORT=TARGET`
+#[derive(Debug, Clone)]
+pub struct ReverseList(pub Vec<ReverseMapping>);
+
+impl ReverseList {
+    /// Target of connections accepted on `port`
+    pub fn target(&self, port: u16) -> Option<&ServerAddr> {
+        self.0.iter().find(|m| m.port == port).map(|m| &m.target)
+    }
+
+    pub fn ports(&self) -> Vec<u16> {
+        self.0.iter().map(|m| m.port).collect()
+    }
+}
+
+impl FromStr for ReverseList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReverseList, String> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(ReverseList)
+    }
+}
+
+impl Display for ReverseList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, mapping) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            mapping.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ReverseList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ReverseList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ReverseList, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,374 @@
+//! Proxy protocols that local accepts from its clients
+//!
+//! With `socks5` or `http`, clients tell local where to connect, and local sends it to the server in the stream
+//! header. With `raw`, local accepts plain connections, which the server connects to `SS_LOCAL_*`.
+
+use std::{
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+use crate::{config::ServerAddr, header::read_target, opt::PluginOpts};
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS5_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS5_PASSWORD_VERSION: u8 = 0x01;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+const SOCKS5_REPLY_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_REPLY_NOT_ALLOWED: u8 = 0x02;
+const SOCKS5_REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const SOCKS5_REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS5_REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS5_REPLY_TTL_EXPIRED: u8 = 0x06;
+const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Maximum length of an HTTP request head
+const MAX_HTTP_HEAD: usize = 8192;
+
+/// Timeout for a client to send its request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Protocol of connections accepted by local
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frontend {
+    /// Plain connections, relayed as they are
+    #[default]
+    Raw,
+    /// SOCKS5 `CONNECT`
+    Socks5,
+    /// HTTP `CONNECT`
+    Http,
+}
+
+/// Username and password required from clients
+#[derive(Debug, Clone)]
+pub struct FrontendAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl FrontendAuth {
+    /// `None` if clients connect without authentication
+    pub fn from_plugin_opts(opts: &PluginOpts) -> Option<FrontendAuth> {
+        opts.frontend_user.as_ref().map(|username| FrontendAuth {
+            username: username.clone(),
+            password: opts.frontend_password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+impl Frontend {
+    /// Read the request of a client, returns its destination. `None` for `Raw`.
+    pub async fn handshake<S>(self, stream: &mut S, auth: Option<&FrontendAuth>) -> io::Result<Option<ServerAddr>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let handshake = async {
+            match self {
+                Frontend::Raw => Ok(None),
+                Frontend::Socks5 => socks5_handshake(stream, auth).await.map(Some),
+                Frontend::Http => http_handshake(stream, auth).await.map(Some),
+            }
+        };
+        match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(r) => r,
+            Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "frontend handshake timeout")),
+        }
+    }
+
+    /// Tell the client that its destination is connected, or why it is not with `error`
+    pub async fn reply<S>(self, stream: &mut S, error: Option<&io::Error>) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        match self {
+            Frontend::Raw => Ok(()),
+            Frontend::Socks5 => {
+                let rep = match error {
+                    None => SOCKS5_REPLY_SUCCEEDED,
+                    Some(err) => match err.kind() {
+                        ErrorKind::PermissionDenied => SOCKS5_REPLY_NOT_ALLOWED,
+                        ErrorKind::NetworkUnreachable => SOCKS5_REPLY_NETWORK_UNREACHABLE,
+                        ErrorKind::HostUnreachable | ErrorKind::NotFound => SOCKS5_REPLY_HOST_UNREACHABLE,
+                        ErrorKind::ConnectionRefused => SOCKS5_REPLY_CONNECTION_REFUSED,
+                        ErrorKind::TimedOut => SOCKS5_REPLY_TTL_EXPIRED,
+                        _ => SOCKS5_REPLY_GENERAL_FAILURE,
+                    },
+                };
+                socks5_reply(stream, rep).await
+            }
+            Frontend::Http => {
+                let response: &[u8] = match error {
+                    None => b"HTTP/1.1 200 Connection Established\r\n\r\n",
+                    Some(err) => match err.kind() {
+                        ErrorKind::PermissionDenied => b"HTTP/1.1 403 Forbidden\r\n\r\n",
+                        ErrorKind::TimedOut => b"HTTP/1.1 504 Gateway Timeout\r\n\r\n",
+                        _ => b"HTTP/1.1 502 Bad Gateway\r\n\r\n",
+                    },
+                };
+                stream.write_all(response).await
+            }
+        }
+    }
+}
+
+/// Compare credentials in constant time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn socks5_reply<S>(stream: &mut S, rep: u8) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // BND.ADDR and BND.PORT are not known, 0.0.0.0:0
+    let reply = [SOCKS5_VERSION, rep, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    stream.write_all(&reply).await
+}
+
+async fn socks5_handshake<S>(stream: &mut S, auth: Option<&FrontendAuth>) -> io::Result<ServerAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != SOCKS5_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported socks version {}", version),
+        ));
+    }
+
+    let nmethods = stream.read_u8().await? as usize;
+    let mut methods = [0u8; 255];
+    stream.read_exact(&mut methods[..nmethods]).await?;
+    let methods = &methods[..nmethods];
+
+    let method = match auth {
+        Some(..) if methods.contains(&SOCKS5_AUTH_PASSWORD) => SOCKS5_AUTH_PASSWORD,
+        None if methods.contains(&SOCKS5_AUTH_NONE) => SOCKS5_AUTH_NONE,
+        _ => SOCKS5_AUTH_UNACCEPTABLE,
+    };
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+    match method {
+        SOCKS5_AUTH_UNACCEPTABLE => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "socks5 client offered no acceptable authentication method",
+            ));
+        }
+        SOCKS5_AUTH_PASSWORD => {
+            // RFC 1929
+            let version = stream.read_u8().await?;
+            if version != SOCKS5_PASSWORD_VERSION {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported socks5 password authentication version {}", version),
+                ));
+            }
+
+            let ulen = stream.read_u8().await? as usize;
+            let mut username = vec![0u8; ulen];
+            stream.read_exact(&mut username).await?;
+            let plen = stream.read_u8().await? as usize;
+            let mut password = vec![0u8; plen];
+            stream.read_exact(&mut password).await?;
+
+            let auth = auth.expect("password method without auth");
+            let accepted = constant_time_eq(&username, auth.username.as_bytes())
+                & constant_time_eq(&password, auth.password.as_bytes());
+            stream
+                .write_all(&[SOCKS5_PASSWORD_VERSION, if accepted { 0x00 } else { 0x01 }])
+                .await?;
+
+            if !accepted {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "socks5 authentication failed",
+                ));
+            }
+        }
+        _ => {}
+    }
+
+    let mut request = [0u8; 3];
+    stream.read_exact(&mut request).await?;
+    if request[0] != SOCKS5_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported socks version {} in request", request[0]),
+        ));
+    }
+
+    // Same encoding as the stream header
+    let target = read_target(stream).await?;
+
+    if request[1] != SOCKS5_CMD_CONNECT {
+        socks5_reply(stream, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported socks5 command {}", request[1]),
+        ));
+    }
+
+    trace!("socks5 connect {}", target);
+
+    Ok(target)
+}
+
+/// Read bytes until the end of an HTTP request head, without reading any further
+pub(crate) async fn read_http_head<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::with_capacity(512);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_HEAD {
+            return Err(io::Error::new(ErrorKind::InvalidData, "http request head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(head)
+}
+
+async fn http_handshake<S>(stream: &mut S, auth: Option<&FrontendAuth>) -> io::Result<ServerAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = read_http_head(stream).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(&head) {
+        Ok(httparse::Status::Complete(..)) => {}
+        Ok(httparse::Status::Partial) | Err(..) => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid http request"));
+        }
+    }
+
+    if request.method != Some("CONNECT") {
+        stream
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n")
+            .await?;
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported http method {:?}", request.method),
+        ));
+    }
+
+    if let Some(auth) = auth {
+        let expected = format!(
+            "Basic {}",
+            BASE64.encode(format!("{}:{}", auth.username, auth.password))
+        );
+        let accepted = request.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("Proxy-Authorization") && constant_time_eq(h.value, expected.as_bytes())
+        });
+
+        if !accepted {
+            stream
+                .write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"sskcp\"\r\n\r\n",
+                )
+                .await?;
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "http authentication failed",
+            ));
+        }
+    }
+
+    let target = match request.path.and_then(ServerAddr::from_host_port) {
+        Some(t) => t,
+        None => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid http connect target {:?}", request.path),
+            ));
+        }
+    };
+
+    trace!("http connect {}", target);
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+
+    fn auth() -> FrontendAuth {
+        FrontendAuth {
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_password() {
+        let (mut client, mut server) = duplex(1024);
+        let auth = auth();
+        let handshake = Frontend::Socks5.handshake(&mut server, Some(&auth));
+        let client = async {
+            client.write_all(&[5, 1, 2]).await.unwrap();
+            client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+            client.write_all(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80]).await.unwrap();
+            let mut reply = [0u8; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            reply
+        };
+        let (target, reply) = tokio::join!(handshake, client);
+        assert_eq!(reply, [5, 2, 1, 0]);
+        assert_eq!(target.unwrap().unwrap().to_string(), "10.0.0.1:80");
+    }
+
+    #[tokio::test]
+    async fn socks5_wrong_password() {
+        let (mut client, mut server) = duplex(1024);
+        let auth = auth();
+        let handshake = Frontend::Socks5.handshake(&mut server, Some(&auth));
+        let client = async {
+            client.write_all(&[5, 1, 2]).await.unwrap();
+            client.write_all(b"\x01\x05alice\x06secreT").await.unwrap();
+            let mut reply = [0u8; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            reply
+        };
+        let (target, reply) = tokio::join!(handshake, client);
+        assert_eq!(reply, [5, 2, 1, 1]);
+        assert_eq!(target.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn error_replies() {
+        let mut buf = Vec::new();
+        let refused = io::Error::from(ErrorKind::ConnectionRefused);
+        Frontend::Socks5.reply(&mut buf, Some(&refused)).await.unwrap();
+        assert_eq!(buf[1], SOCKS5_REPLY_CONNECTION_REFUSED);
+
+        let mut buf = Vec::new();
+        let denied = io::Error::from(ErrorKind::PermissionDenied);
+        Frontend::Http.reply(&mut buf, Some(&denied)).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 403 "));
+
+        let mut buf = Vec::new();
+        Frontend::Http.reply(&mut buf, None).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 200 "));
+    }
+}
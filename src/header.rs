@@ -0,0 +1,245 @@
+//! Stream header
+//!
+//! With `stream_header`, each stream starts with the address that the server should connect to, encoded like an
+//! address in SOCKS5.
+//!
+//! ```plain
+//! +------+----------+----------+
+//! | ATYP |   ADDR   |   PORT   |
+//! +------+----------+----------+
+//! |  1   | Variable |  2 (BE)  |
+//! +------+----------+----------+
+//! ```
+//!
+//! `ATYP` is `1` for a 4 bytes IPv4 address, `3` for a domain name with a length byte in front, `4` for a 16 bytes
+//! IPv6 address.
+//!
+//! `ATYP` `0`, without `ADDR` and `PORT`, sends the stream to `SS_LOCAL_HOST:SS_LOCAL_PORT` of the server. Local
+//! sends it for connections of its own listener when `forward` is set, as the server reads a header on every stream.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    config::{network_required, ServerAddr},
+    opt::PluginOpts,
+};
+
+const ATYP_DEFAULT: u8 = 0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN_NAME: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Check that `stream_header` is set with `allowed_targets`, so that the server is not an open proxy by mistake
+pub fn check_plugin_opts(opts: &PluginOpts) -> io::Result<()> {
+    if opts.stream_header.unwrap_or(false) && opts.allowed_targets.is_none() {
+        return Err(io::Error::other(
+            "stream_header requires allowed_targets, 0.0.0.0/0,::/0 allows every destination",
+        ));
+    }
+    Ok(())
+}
+
+/// Append `addr` to `buf`, in the header format
+pub fn encode_target(addr: &ServerAddr, buf: &mut Vec<u8>) -> io::Result<()> {
+    match *addr {
+        ServerAddr::SocketAddr(SocketAddr::V4(ref a)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&a.ip().octets());
+            buf.extend_from_slice(&a.port().to_be_bytes());
+        }
+        ServerAddr::SocketAddr(SocketAddr::V6(ref a)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&a.ip().octets());
+            buf.extend_from_slice(&a.port().to_be_bytes());
+        }
+        ServerAddr::DomainName(ref dname, port) => {
+            if dname.is_empty() || dname.len() > 255 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid domain name {:?}", dname),
+                ));
+            }
+            buf.push(ATYP_DOMAIN_NAME);
+            buf.push(dname.len() as u8);
+            buf.extend_from_slice(dname.as_bytes());
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    }
+    Ok(())
+}
+
+/// Write the header of a stream to `addr`
+pub async fn write_target<W>(writer: &mut W, addr: &ServerAddr) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(1 + 1 + 255 + 2);
+    encode_target(addr, &mut buf)?;
+    writer.write_all(&buf).await
+}
+
+/// Write the header of a stream to `addr`, or to `SS_LOCAL_*` of the server if `None`
+pub async fn write_header<W>(writer: &mut W, addr: Option<&ServerAddr>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    match addr {
+        Some(addr) => write_target(writer, addr).await,
+        None => writer.write_all(&[ATYP_DEFAULT]).await,
+    }
+}
+
+/// Read the header of a stream, `None` for `SS_LOCAL_*` of the server
+pub async fn read_header<R>(reader: &mut R) -> io::Result<Option<ServerAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    match reader.read_u8().await? {
+        ATYP_DEFAULT => Ok(None),
+        atyp => read_addr(reader, atyp).await.map(Some),
+    }
+}
+
+/// Read an address in the header format, which SOCKS5 requests also use
+pub async fn read_target<R>(reader: &mut R) -> io::Result<ServerAddr>
+where
+    R: AsyncRead + Unpin,
+{
+    let atyp = reader.read_u8().await?;
+    read_addr(reader, atyp).await
+}
+
+async fn read_addr<R>(reader: &mut R, atyp: u8) -> io::Result<ServerAddr>
+where
+    R: AsyncRead + Unpin,
+{
+    match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            reader.read_exact(&mut ip).await?;
+            let port = reader.read_u16().await?;
+            Ok(ServerAddr::SocketAddr(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            reader.read_exact(&mut ip).await?;
+            let port = reader.read_u16().await?;
+            Ok(ServerAddr::SocketAddr(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        ATYP_DOMAIN_NAME => {
+            let len = reader.read_u8().await? as usize;
+            let mut dname = vec![0u8; len];
+            reader.read_exact(&mut dname).await?;
+            let port = reader.read_u16().await?;
+
+            match String::from_utf8(dname) {
+                Ok(dname) if !dname.is_empty() => Ok(ServerAddr::DomainName(dname, port)),
+                _ => Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid domain name in stream header",
+                )),
+            }
+        }
+        atyp => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid address type {} in stream header", atyp),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_header_requires_allowed_targets() {
+        let mut opts = PluginOpts {
+            stream_header: Some(true),
+            ..Default::default()
+        };
+        assert!(check_plugin_opts(&opts).is_err());
+        opts.allowed_targets = Some("0.0.0.0/0,::/0".parse().unwrap());
+        assert!(check_plugin_opts(&opts).is_ok());
+    }
+
+    #[tokio::test]
+    async fn target_round_trip() {
+        let targets = [
+            ("1.2.3.4:80", &[ATYP_IPV4, 1, 2, 3, 4, 0, 80][..]),
+            (
+                "[2001:db8::1]:443",
+                &[
+                    ATYP_IPV6, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x01, 0xbb,
+                ][..],
+            ),
+            (
+                "example.com:8080",
+                &[
+                    ATYP_DOMAIN_NAME,
+                    11,
+                    b'e',
+                    b'x',
+                    b'a',
+                    b'm',
+                    b'p',
+                    b'l',
+                    b'e',
+                    b'.',
+                    b'c',
+                    b'o',
+                    b'm',
+                    0x1f,
+                    0x90,
+                ][..],
+            ),
+        ];
+
+        for (s, bytes) in targets {
+            let addr = ServerAddr::from_host_port(s).unwrap();
+            let mut buf = Vec::new();
+            write_target(&mut buf, &addr).await.unwrap();
+            assert_eq!(buf, bytes, "{}", s);
+
+            let mut reader = &buf[..];
+            assert_eq!(read_target(&mut reader).await.unwrap().to_string(), s);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_targets() {
+        let addr = ServerAddr::DomainName("a".repeat(256), 80);
+        assert!(write_target(&mut Vec::new(), &addr).await.is_err());
+
+        for bytes in [
+            &[2u8, 1, 2, 3, 4, 0, 80][..],
+            &[ATYP_DOMAIN_NAME, 0, 0, 80],
+            &[ATYP_IPV4, 1, 2],
+        ] {
+            assert!(read_target(&mut &bytes[..]).await.is_err());
+        }
+        // Only stream headers send streams to the default upstream
+        assert!(read_target(&mut &[ATYP_DEFAULT][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn default_upstream_header() {
+        let mut buf = Vec::new();
+        write_header(&mut buf, None).await.unwrap();
+        assert_eq!(buf, [ATYP_DEFAULT]);
+        assert!(read_header(&mut &buf[..]).await.unwrap().is_none());
+
+        let addr = ServerAddr::from_host_port("1.2.3.4:80").unwrap();
+        let mut buf = Vec::new();
+        write_header(&mut buf, Some(&addr)).await.unwrap();
+        assert_eq!(buf, [ATYP_IPV4, 1, 2, 3, 4, 0, 80]);
+        let target = read_header(&mut &buf[..]).await.unwrap().unwrap();
+        assert_eq!(target.to_string(), "1.2.3.4:80");
+    }
+}
@@ -0,0 +1,113 @@
+//! Idle timeouts
+//!
+//! With `stream_idle_timeout`, streams are closed when nothing has been relayed in either direction for that long.
+//! With `session_idle_timeout`, sessions are closed when they have had no streams for that long, see
+//! [`Session::idle`](crate::admin::Session::idle).
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant},
+};
+
+/// Timeout of `secs` seconds, `None` if unset or `0`
+pub fn idle_timeout(secs: Option<u64>) -> Option<Duration> {
+    match secs {
+        Some(0) | None => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Stream that records when it last read or wrote
+struct Active<'a, S> {
+    stream: &'a mut S,
+    started: Instant,
+    /// Milliseconds since `started`
+    last_active: &'a AtomicU64,
+}
+
+impl<S> Active<'_, S> {
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_active.store(elapsed, Ordering::Relaxed);
+    }
+}
+
+impl<S> AsyncRead for Active<'_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.stream).poll_read(cx, buf);
+        if result.is_ready() {
+            this.touch();
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for Active<'_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.stream).poll_write(cx, buf);
+        if result.is_ready() {
+            this.touch();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Same as `tokio::io::copy_bidirectional`, failing with `TimedOut` if nothing is relayed for `timeout`
+pub async fn copy_bidirectional_idle<A, B>(a: &mut A, b: &mut B, timeout: Option<Duration>) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return tokio::io::copy_bidirectional(a, b).await,
+    };
+
+    // Everything relayed is read from or written to `a`
+    let started = Instant::now();
+    let last_active = AtomicU64::new(0);
+    let mut a = Active {
+        stream: a,
+        started,
+        last_active: &last_active,
+    };
+
+    let watchdog = async {
+        loop {
+            let last_active = started + Duration::from_millis(last_active.load(Ordering::Relaxed));
+            if last_active.elapsed() >= timeout {
+                return;
+            }
+            time::sleep_until(last_active + timeout).await;
+        }
+    };
+
+    tokio::select! {
+        r = tokio::io::copy_bidirectional(&mut a, b) => r,
+        _ = watchdog => Err(io::Error::new(ErrorKind::TimedOut, "stream idle timeout")),
+    }
+}
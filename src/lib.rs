@@ -1,9 +1,32 @@
 //! KCP proxy for ShadowSocks
 
+pub mod access_log;
+pub mod admin;
+pub mod allowlist;
+pub mod compress;
 pub mod config;
+pub mod crypto;
+pub mod firewall;
+pub mod forward;
+pub mod frontend;
+pub mod header;
+pub mod idle;
+pub mod limit;
 pub mod local;
+pub mod mux;
 pub mod opt;
+pub mod proxy_protocol;
+pub mod ratelimit;
+pub mod redir;
+pub mod relay;
+pub mod resume;
 pub mod server;
+pub mod session;
+pub mod smux;
 mod sys;
+pub mod transport;
+pub mod udp;
+pub mod unix_socket;
+pub mod user;
 
 pub use self::sys::adjust_nofile;
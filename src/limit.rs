@@ -0,0 +1,288 @@
+//! Admission control of server
+//!
+//! Sessions over `max_sessions` or `max_sessions_per_ip` are closed as soon as they are accepted, before their
+//! handshakes. KCP sessions are refused by the UDP relay instead, before the KCP listener sees them, so that their
+//! clients do not get accepted again with every packet they retransmit. Streams over `max_streams_per_session` are
+//! reset when they are opened. Streams that would dial an upstream while `max_dials` dials are in progress are reset
+//! too. Each refusal is counted, and shown by `sskcp-ctl sessions`.
+//!
+//! Admitted sessions get the rate limiters of `rate_limit_per_session` and `rate_limit_per_ip`, see [`ratelimit`].
+//!
+//! [`ratelimit`]: crate::ratelimit
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    opt::PluginOpts,
+    ratelimit::{RateLimiter, SessionRate},
+};
+
+/// Sessions from an IP address
+#[derive(Debug)]
+struct IpSessions {
+    sessions: usize,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Debug, Default)]
+struct SessionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, IpSessions>,
+}
+
+/// Rate and burst of a rate limit, in bytes
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    rate: u64,
+    burst: u64,
+}
+
+impl Rate {
+    /// Rate of `rate` KiB/s and `burst` KiB, one second of `rate` by default
+    fn from_kib(rate: Option<u64>, burst: Option<u64>) -> Option<Rate> {
+        match rate {
+            Some(0) | None => None,
+            Some(rate) => Some(Rate {
+                rate: rate * 1024,
+                burst: burst.unwrap_or(rate).max(1) * 1024,
+            }),
+        }
+    }
+
+    fn limiter(self) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(self.rate, self.burst))
+    }
+}
+
+#[derive(Debug, Default)]
+struct RefusedCounters {
+    sessions: AtomicU64,
+    sessions_per_ip: AtomicU64,
+    streams: AtomicU64,
+    dials: AtomicU64,
+}
+
+/// Requests refused by each limit
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RefusedCounts {
+    pub sessions: u64,
+    pub sessions_per_ip: u64,
+    pub streams: u64,
+    pub dials: u64,
+}
+
+/// Limits of sessions, streams and upstream dials, unlimited by default
+#[derive(Debug, Default)]
+pub struct Limits {
+    max_sessions: Option<usize>,
+    max_sessions_per_ip: Option<usize>,
+    max_streams_per_session: Option<usize>,
+    dials: Option<Arc<Semaphore>>,
+    rate_per_session: Option<Rate>,
+    rate_per_ip: Option<Rate>,
+    sessions: Arc<Mutex<SessionCounts>>,
+    refused: RefusedCounters,
+}
+
+impl Limits {
+    pub fn from_plugin_opts(opts: &PluginOpts) -> Limits {
+        Limits {
+            max_sessions: opts.max_sessions,
+            max_sessions_per_ip: opts.max_sessions_per_ip,
+            max_streams_per_session: opts.max_streams_per_session,
+            dials: opts.max_dials.map(|n| Arc::new(Semaphore::new(n))),
+            rate_per_session: Rate::from_kib(opts.rate_limit_per_session, opts.rate_burst_per_session),
+            rate_per_ip: Rate::from_kib(opts.rate_limit_per_ip, opts.rate_burst_per_ip),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the number of sessions is limited, by `max_sessions` or `max_sessions_per_ip`
+    pub fn limits_sessions(&self) -> bool {
+        self.max_sessions.is_some() || self.max_sessions_per_ip.is_some()
+    }
+
+    /// Admit a session from `ip`, which is counted until the permit drops. The permit carries its rate limiters.
+    pub fn admit_session(&self, ip: IpAddr) -> Option<SessionPermit> {
+        let mut counts = self.sessions.lock().unwrap();
+
+        if self.max_sessions.is_some_and(|max| counts.total >= max) {
+            self.refused.sessions.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let per_ip = counts.per_ip.get(&ip).map_or(0, |s| s.sessions);
+        if self.max_sessions_per_ip.is_some_and(|max| per_ip >= max) {
+            self.refused.sessions_per_ip.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        counts.total += 1;
+        let ip_sessions = counts.per_ip.entry(ip).or_insert_with(|| IpSessions {
+            sessions: 0,
+            rate_limiter: self.rate_per_ip.map(Rate::limiter),
+        });
+        ip_sessions.sessions += 1;
+
+        let limiters = self
+            .rate_per_session
+            .map(Rate::limiter)
+            .into_iter()
+            .chain(ip_sessions.rate_limiter.clone())
+            .collect();
+
+        Some(SessionPermit {
+            sessions: self.sessions.clone(),
+            ip,
+            rate: SessionRate::new(limiters),
+        })
+    }
+
+    /// Admit a new stream of a session that has `streams` open streams
+    pub fn admit_stream(&self, streams: usize) -> bool {
+        if self.max_streams_per_session.is_some_and(|max| streams >= max) {
+            self.refused.streams.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Admit an upstream dial, which is in progress until the permit drops
+    pub fn admit_dial(&self) -> Option<DialPermit> {
+        match self.dials {
+            Some(ref dials) => match dials.clone().try_acquire_owned() {
+                Ok(permit) => Some(DialPermit { _permit: Some(permit) }),
+                Err(..) => {
+                    self.refused.dials.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+            },
+            None => Some(DialPermit { _permit: None }),
+        }
+    }
+
+    pub fn refused(&self) -> RefusedCounts {
+        RefusedCounts {
+            sessions: self.refused.sessions.load(Ordering::Relaxed),
+            sessions_per_ip: self.refused.sessions_per_ip.load(Ordering::Relaxed),
+            streams: self.refused.streams.load(Ordering::Relaxed),
+            dials: self.refused.dials.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// An admitted session
+#[derive(Debug)]
+pub struct SessionPermit {
+    sessions: Arc<Mutex<SessionCounts>>,
+    ip: IpAddr,
+    rate: SessionRate,
+}
+
+impl SessionPermit {
+    /// Rate limiters of the session
+    pub fn rate(&self) -> &SessionRate {
+        &self.rate
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.sessions.lock().unwrap();
+        counts.total -= 1;
+        if let Some(s) = counts.per_ip.get_mut(&self.ip) {
+            s.sessions -= 1;
+            if s.sessions == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// An admitted upstream dial
+#[derive(Debug)]
+pub struct DialPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(opts: &str) -> Limits {
+        Limits::from_plugin_opts(&PluginOpts::from_str(opts).unwrap())
+    }
+
+    #[test]
+    fn sessions() {
+        let limits = limits("max_sessions=3&max_sessions_per_ip=2");
+        let a = "192.0.2.1".parse().unwrap();
+        let b = "192.0.2.2".parse().unwrap();
+
+        let a1 = limits.admit_session(a).unwrap();
+        let a2 = limits.admit_session(a).unwrap();
+        assert!(limits.admit_session(a).is_none());
+        let b1 = limits.admit_session(b).unwrap();
+        assert!(limits.admit_session(b).is_none());
+
+        // Dropped permits give their places back
+        drop(a1);
+        let b2 = limits.admit_session(b).unwrap();
+        assert!(limits.admit_session(a).is_none());
+        drop(a2);
+        assert!(limits.admit_session(b).is_none());
+        let a3 = limits.admit_session(a).unwrap();
+
+        let refused = limits.refused();
+        assert_eq!(refused.sessions, 2);
+        assert_eq!(refused.sessions_per_ip, 2);
+
+        drop((a3, b1, b2));
+        let counts = limits.sessions.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+    }
+
+    #[test]
+    fn streams() {
+        let limits = limits("max_streams_per_session=2");
+        assert!(limits.admit_stream(0));
+        assert!(limits.admit_stream(1));
+        assert!(!limits.admit_stream(2));
+        assert!(!limits.admit_stream(3));
+        assert_eq!(limits.refused().streams, 2);
+    }
+
+    #[test]
+    fn dials() {
+        let limits = limits("max_dials=2");
+        let d1 = limits.admit_dial().unwrap();
+        let _d2 = limits.admit_dial().unwrap();
+        assert!(limits.admit_dial().is_none());
+
+        drop(d1);
+        let _d3 = limits.admit_dial().unwrap();
+        assert!(limits.admit_dial().is_none());
+        assert_eq!(limits.refused().dials, 2);
+    }
+
+    #[test]
+    fn unlimited() {
+        let limits = Limits::default();
+        assert!(!limits.limits_sessions());
+        let ip = "192.0.2.1".parse().unwrap();
+        let permits = (0..100).map(|_| limits.admit_session(ip).unwrap()).collect::<Vec<_>>();
+        assert!(permits.iter().all(|p| p.rate().is_unlimited()));
+        assert!(limits.admit_stream(1000));
+        assert!((0..100).all(|_| limits.admit_dial().is_some()));
+    }
+}
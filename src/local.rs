@@ -1,39 +1,179 @@
-use std::{cell::RefCell, collections::LinkedList, io, io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::LinkedList,
+    fmt::Display,
+    future::Future,
+    io,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use futures::StreamExt;
+use futures::{future, StreamExt};
 use log::{debug, error, info, trace};
 use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
     net::{lookup_host, TcpListener, TcpStream},
     time,
 };
-use tokio_kcp::KcpStream;
-use tokio_yamux::{Config as YamuxConfig, Control as YamuxControl, Error as YamuxError, Session as YamuxSession};
 
 use crate::{
-    config::{Config, ServerAddr},
-    opt::create_outbound_kcp,
+    access_log::{AccessLog, AccessLogged},
+    admin::{start_admin, CountedStream, Session, SessionHandle, SessionRegistry},
+    config::{network_required, Config, ServerAddr},
+    crypto::{e2e_session, Role},
+    forward::ForwardMapping,
+    frontend::{Frontend, FrontendAuth},
+    header::write_header,
+    idle::{copy_bidirectional_idle, idle_timeout},
+    limit::Limits,
+    mux::{MuxControl, MuxError, MuxMode, MuxSession, MuxStream, PlainStream},
+    opt::create_outbound_tcp,
+    redir,
+    resume::{self, Connector},
+    session::{client_session_with, BoxedSessionStream, Preamble, MAX_REVERSE_PORTS},
+    transport::{quic::connect_quic_session, TransportKind, TransportList},
+    udp::{check_plugin_opts, stats::KcpStats},
+    user::{self, client_user_session},
 };
 
+#[cfg(unix)]
+use crate::{udp::client::rebind_on_signal, unix_socket::bind_unix_listener};
+
+/// Timeout for receiving the header of a stream opened by the server
+const REVERSE_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Index in `transport` of the transport that worked last time
+static TRANSPORT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
 /// Local mode
 ///
 /// ```plain
 ///              TCP Loopback                KCP (UDP)
 /// [SS-Client] <------------> [SSKCP-Local] --------> REMOTE
 /// ```
+///
+/// With `admin_socket`, returns when sessions have been drained.
 pub async fn start_proxy(config: Config) -> io::Result<()> {
     debug!("start local proxy with {:?}", config);
 
+    check_plugin_opts(&config.plugin_opts)?;
+    user::check_plugin_opts(&config.plugin_opts)?;
+    resume::check_plugin_opts(&config.plugin_opts)?;
+    if config.plugin_opts.redir.is_some() && config.plugin_opts.frontend.is_some_and(|f| f != Frontend::Raw) {
+        return Err(io::Error::other("redir does not work with frontend"));
+    }
+    // Switching to the next transport relies on the reply to the session preamble, which smux does not have
+    if !config.plugin_opts.mux.unwrap_or_default().has_preamble()
+        && config.plugin_opts.transport.as_ref().is_some_and(|t| t.0.len() > 1)
+    {
+        return Err(io::Error::other(
+            "a list of transports is not available with smux1 or smux2",
+        ));
+    }
+
+    let access_log = AccessLog::from_plugin_opts(&config.plugin_opts)?;
+    let sessions = Arc::new(SessionRegistry::new(
+        access_log,
+        Arc::new(Limits::default()),
+        None,
+        None,
+    ));
+    if let Some(ref path) = config.plugin_opts.admin_socket {
+        start_admin(path, sessions.clone(), &config.plugin_opts).await?;
+    }
+
+    #[cfg(unix)]
+    if config.plugin_opts.roaming.unwrap_or(false) {
+        tokio::spawn(rebind_on_signal());
+    }
+
     let config = Arc::new(config);
 
-    let listener = match config.local_addr {
-        ServerAddr::SocketAddr(sa) => TcpListener::bind(sa).await?,
-        ServerAddr::DomainName(ref dname, port) => TcpListener::bind((dname.as_str(), port)).await?,
+    if let Some(ref forward) = config.plugin_opts.forward {
+        for mapping in &forward.0 {
+            let listener = bind_listener(&mapping.listen).await?;
+            info!(
+                "KCP local forwarding {} to {}",
+                listener.local_addr().unwrap(),
+                mapping.target
+            );
+            tokio::spawn(serve_forward(
+                config.clone(),
+                sessions.clone(),
+                listener,
+                mapping.clone(),
+            ));
+        }
+    }
+
+    if let Some(ref reverse) = config.plugin_opts.reverse {
+        if config.plugin_opts.mux.unwrap_or_default() != MuxMode::Yamux {
+            return Err(io::Error::other("reverse requires mux=yamux"));
+        }
+        if reverse.0.len() > MAX_REVERSE_PORTS {
+            return Err(io::Error::other(format!(
+                "reverse supports at most {} ports",
+                MAX_REVERSE_PORTS
+            )));
+        }
+        tokio::spawn(serve_reverse(config.clone(), sessions.clone()));
+    }
+
+    // Reverse tunnels and non-multiplexed streams need session streams, which QUIC connections do not have
+    if transport_kinds(&config).iter().all(|&t| t == TransportKind::Quic)
+        && (config.plugin_opts.reverse.is_some() || !config.plugin_opts.mux.unwrap_or_default().is_multiplexed())
+    {
+        return Err(io::Error::other(
+            "reverse and mux=false require a transport other than quic",
+        ));
+    }
+
+    match config.local_addr {
+        ServerAddr::UnixSocket(ref path) if config.plugin_opts.redir.is_none() => {
+            serve_unix(&config, &sessions, path).await?
+        }
+        _ => serve_tcp(&config, &sessions).await?,
+    }
+
+    info!("draining {} sessions", sessions.list().len());
+    sessions.drained().await;
+    info!("drained");
+    Ok(())
+}
+
+/// Accept clients on `SS_LOCAL_*`, until draining
+async fn serve_tcp(config: &Arc<Config>, sessions: &Arc<SessionRegistry>) -> io::Result<()> {
+    let listener = match config.plugin_opts.redir {
+        None => bind_listener(&config.local_addr).await?,
+        Some(ty) => {
+            let addr = match config.local_addr {
+                ServerAddr::SocketAddr(sa) => sa,
+                ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await?.next() {
+                    Some(sa) => sa,
+                    None => return Err(io::Error::other("lookup_host resolved to empty")),
+                },
+                ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+            };
+            info!("KCP local accepts {:?} connections", ty);
+            redir::bind_listener(addr, ty).await?
+        }
     };
 
     info!("KCP local listening on {}", listener.local_addr().unwrap());
 
     loop {
-        let (stream, peer_addr) = match listener.accept().await {
+        let accepted = tokio::select! {
+            r = listener.accept() => r,
+            _ = sessions.draining() => break,
+        };
+
+        let (stream, peer_addr) = match accepted {
             Ok(s) => s,
             Err(err) => {
                 error!("accept failed with error: {}", err);
@@ -45,47 +185,373 @@ pub async fn start_proxy(config: Config) -> io::Result<()> {
         debug!("accepted {}", peer_addr);
 
         let config = config.clone();
+        let sessions = sessions.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_client(&config, stream, peer_addr).await {
+            if let Err(err) = handle_client(&config, &sessions, stream, peer_addr).await {
                 error!("failed to handle client {}, error: {}", peer_addr, err);
             }
         });
     }
+
+    Ok(())
 }
 
-async fn connect_server(config: &Config) -> io::Result<KcpStream> {
-    let kcp_config = &config.kcp_config;
+/// Accept clients on the unix domain socket at `path`, until draining
+#[cfg(unix)]
+async fn serve_unix(config: &Arc<Config>, sessions: &Arc<SessionRegistry>, path: &Path) -> io::Result<()> {
+    let listener = bind_unix_listener(path, &config.plugin_opts)?;
 
-    match config.remote_addr {
-        ServerAddr::SocketAddr(sa) => create_outbound_kcp(kcp_config, sa, &config.plugin_opts)
-            .await
-            .map_err(Into::into),
-        ServerAddr::DomainName(ref dname, port) => {
-            let mut result = None;
+    info!("KCP local listening on {}", path.display());
 
-            for addr in lookup_host((dname.as_str(), port)).await? {
-                match create_outbound_kcp(kcp_config, addr, &config.plugin_opts).await {
-                    Ok(s) => {
-                        result = Some(Ok(s));
-                        break;
-                    }
-                    Err(err) => {
-                        result = Some(Err(err));
-                    }
+    // Clients of unix domain sockets are usually unnamed, they are shown by the path they connected to
+    let peer_addr = path.display().to_string();
+    loop {
+        let accepted = tokio::select! {
+            r = listener.accept() => r,
+            _ = sessions.draining() => break,
+        };
+
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        debug!("accepted {}", peer_addr);
+
+        let config = config.clone();
+        let sessions = sessions.clone();
+        let peer_addr = peer_addr.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_frontend(&config, &sessions, stream, &peer_addr).await {
+                error!("failed to handle client {}, error: {}", peer_addr, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_config: &Arc<Config>, _sessions: &Arc<SessionRegistry>, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "unix domain sockets are only supported on unix",
+    ))
+}
+
+async fn bind_listener(addr: &ServerAddr) -> io::Result<TcpListener> {
+    match *addr {
+        ServerAddr::SocketAddr(sa) => TcpListener::bind(sa).await,
+        ServerAddr::DomainName(ref dname, port) => TcpListener::bind((dname.as_str(), port)).await,
+        ServerAddr::UnixSocket(ref path) => Err(network_required(path)),
+    }
+}
+
+/// Keep a session that the server opens streams of reverse tunnels in, until draining
+async fn serve_reverse(config: Arc<Config>, sessions: Arc<SessionRegistry>) {
+    let ports = config
+        .plugin_opts
+        .reverse
+        .as_ref()
+        .map(|r| r.ports())
+        .unwrap_or_default();
+
+    while !sessions.is_draining() {
+        match open_reverse_session(&config, &ports).await {
+            Ok((mux_session, kcp_stats)) => {
+                let session = sessions.register(&config.remote_addr, None, kcp_stats);
+                drive_session(&config, mux_session, &session, None).await;
+                if !sessions.is_draining() {
+                    error!("reverse tunnel session closed, reconnecting");
                 }
             }
+            Err(err) => {
+                error!("reverse tunnel session failed, error: {}", err);
+            }
+        }
+
+        time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn open_reverse_session(config: &Arc<Config>, ports: &[u16]) -> io::Result<(MuxSession, Option<Arc<KcpStats>>)> {
+    let mut request = Preamble::from_plugin_opts(&config.plugin_opts);
+    request.reverse = ports.to_vec();
+    let (session_stream, accepted, kcp_stats) = open_session_with(config, request).await?;
+
+    for port in ports {
+        if accepted.reverse.contains(port) {
+            info!("reverse tunnel on server port {} registered", port);
+        } else {
+            error!("reverse tunnel on server port {} declined by server", port);
+        }
+    }
+
+    Ok((MuxSession::new_client(session_stream, MuxMode::Yamux), kcp_stats))
+}
+
+/// Drive a client session until it closes, or has had no streams for `session_idle_timeout`, handling streams opened
+/// by the server
+async fn drive_session(
+    config: &Arc<Config>,
+    mut mux_session: MuxSession,
+    session: &SessionHandle,
+    session_idle_timeout: Option<Duration>,
+) {
+    let closing = session.closing();
+    tokio::pin!(closing);
+    let idle = async {
+        match session_idle_timeout {
+            Some(timeout) => session.idle(timeout).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(idle);
+
+    loop {
+        let next = tokio::select! {
+            r = mux_session.next() => r,
+            _ = &mut closing => {
+                debug!("session {} closed", session.id());
+                mux_session.abort();
+                break;
+            }
+            _ = &mut idle => {
+                debug!("session {} idle, closing", session.id());
+                mux_session.abort();
+                break;
+            }
+        };
+
+        match next {
+            Some(Ok(stream)) => {
+                let stream = session.session().counted(stream);
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_reverse_stream(&config, stream).await {
+                        error!("failed to handle reverse tunnel stream, error: {}", err);
+                    }
+                });
+            }
+            Some(Err(e)) => {
+                error!("mux connection aborted with connection error: {}", e);
+                break;
+            }
+            None => {
+                trace!("mux client session closed");
+                break;
+            }
+        }
+    }
+}
+
+/// Connect a stream opened by the server to the target of its reverse mapping
+async fn handle_reverse_stream(config: &Config, stream: CountedStream<MuxStream>) -> io::Result<()> {
+    let entry = stream.session().access_entry(stream.get_ref().id());
+    let mut stream = AccessLogged::new(stream, entry);
+    let result = relay_reverse_stream(config, &mut stream).await;
+    stream.finish(&result);
+    result
+}
+
+async fn relay_reverse_stream(config: &Config, stream: &mut AccessLogged<CountedStream<MuxStream>>) -> io::Result<()> {
+    let port = match time::timeout(REVERSE_HEADER_TIMEOUT, stream.read_u16()).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "reverse tunnel header timeout")),
+    };
+
+    let target = match config.plugin_opts.reverse.as_ref().and_then(|r| r.target(port)) {
+        Some(t) => t,
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("no reverse tunnel on server port {}", port),
+            ))
+        }
+    };
+
+    trace!("reverse tunnel on server port {} connecting to {}", port, target);
+    stream.set_upstream(target);
+
+    let mut local_stream = match *target {
+        ServerAddr::SocketAddr(ref a) => create_outbound_tcp(a, &config.plugin_opts).await?,
+        ServerAddr::DomainName(ref dname, port) => {
+            create_outbound_tcp((dname.as_str(), port), &config.plugin_opts).await?
+        }
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    };
+
+    let stream_idle_timeout = idle_timeout(config.plugin_opts.stream_idle_timeout);
+    copy_bidirectional_idle(stream, &mut local_stream, stream_idle_timeout)
+        .await
+        .map(|_| ())
+}
+
+/// Accept connections of a forwarding mapping, until draining
+async fn serve_forward(
+    config: Arc<Config>,
+    sessions: Arc<SessionRegistry>,
+    listener: TcpListener,
+    mapping: ForwardMapping,
+) {
+    loop {
+        let accepted = tokio::select! {
+            r = listener.accept() => r,
+            _ = sessions.draining() => break,
+        };
+
+        let (stream, peer_addr) = match accepted {
+            Ok(s) => s,
+            Err(err) => {
+                error!("accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        debug!("accepted {} for {}", peer_addr, mapping.target);
+
+        let config = config.clone();
+        let sessions = sessions.clone();
+        let target = mapping.target.clone();
+        tokio::spawn(async move {
+            if let Err(err) = relay_client(&config, &sessions, stream, peer_addr, Frontend::Raw, Some(target)).await {
+                error!("failed to handle client {}, error: {}", peer_addr, err);
+            }
+        });
+    }
+}
 
-            match result {
-                None => Err(io::Error::new(ErrorKind::Other, "lookup_host resolved to empty")),
-                Some(Ok(s)) => Ok(s),
-                Some(Err(err)) => Err(err.into()),
+/// Connect to the server with `transport`, encrypted if `e2e_key` is set and authenticated if `user` is set, with
+/// statistics of the KCP session if any
+async fn connect_server_with(
+    config: &Config,
+    kind: TransportKind,
+) -> io::Result<(BoxedSessionStream, Option<Arc<KcpStats>>)> {
+    let transport = match config.transport {
+        Some(ref transport) => &**transport,
+        None => match kind.transport() {
+            Some(transport) => transport,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "quic transport does not carry sessions",
+                ))
+            }
+        },
+    };
+
+    let (stream, kcp_stats) = transport.connect_with_stats(config).await?;
+    let stream = e2e_session(stream, &config.plugin_opts, Role::Client).await?;
+    let stream = client_user_session(stream, &config.plugin_opts).await?;
+    Ok((stream, kcp_stats))
+}
+
+/// Transports in `transport` option, replaced by the transport of the library user if set
+fn transport_kinds(config: &Config) -> Vec<TransportKind> {
+    match config.transport {
+        Some(..) => TransportList::default().0,
+        None => config.plugin_opts.transport.clone().unwrap_or_default().0,
+    }
+}
+
+/// Connect to the server with the transport that worked last time
+async fn connect_server(config: &Config) -> io::Result<BoxedSessionStream> {
+    let transports = transport_kinds(config);
+    let transport = transports[TRANSPORT_INDEX.load(Ordering::Relaxed) % transports.len()];
+    connect_server_with(config, transport).await.map(|(stream, _)| stream)
+}
+
+/// Try transports in `transport` in order, starting from the one that worked last time
+///
+/// QUIC connections have no session stream, they are skipped if `needs_session` is set.
+async fn with_transports<T, F, Fut>(config: &Config, needs_session: bool, mut connect: F) -> io::Result<T>
+where
+    F: FnMut(TransportKind) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let transports = transport_kinds(config);
+    let start = TRANSPORT_INDEX.load(Ordering::Relaxed) % transports.len();
+
+    let mut last_err = None;
+    for i in 0..transports.len() {
+        let index = (start + i) % transports.len();
+        let transport = transports[index];
+
+        if needs_session && transport == TransportKind::Quic {
+            continue;
+        }
+
+        match connect(transport).await {
+            Ok(r) => {
+                // Switch only if the one that worked last time failed
+                if last_err.is_some() {
+                    info!("switched to transport {}", transport);
+                    TRANSPORT_INDEX.store(index, Ordering::Relaxed);
+                }
+                return Ok(r);
+            }
+            Err(err) => {
+                if transports.len() > 1 {
+                    error!("transport {} failed, error: {}", transport, err);
+                }
+                last_err = Some(err);
             }
         }
     }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no transport carries sessions")))
+}
+
+/// Connect to the server and start a session, requesting parameters in `request`
+async fn open_session_with(
+    config: &Arc<Config>,
+    request: Preamble,
+) -> io::Result<(BoxedSessionStream, Preamble, Option<Arc<KcpStats>>)> {
+    with_transports(config, true, |transport| {
+        let request = request.clone();
+        async move {
+            let (stream, kcp_stats) = connect_server_with(config, transport).await?;
+            let (session_stream, accepted) =
+                client_session_with(stream, &config.plugin_opts, request, server_connector(config)).await?;
+            Ok((session_stream, accepted, kcp_stats))
+        }
+    })
+    .await
+}
+
+/// Connect to the server and start a session
+async fn open_session(config: &Arc<Config>) -> io::Result<(BoxedSessionStream, Option<Arc<KcpStats>>)> {
+    let request = Preamble::from_plugin_opts(&config.plugin_opts);
+    open_session_with(config, request)
+        .await
+        .map(|(stream, _, kcp_stats)| (stream, kcp_stats))
+}
+
+/// Connect to the server and start a multiplexed session, or a QUIC connection
+async fn open_mux_session(config: &Arc<Config>) -> io::Result<(MuxSession, Option<Arc<KcpStats>>)> {
+    let mux_mode = config.plugin_opts.mux.unwrap_or_default();
+    with_transports(config, false, |transport| async move {
+        if transport == TransportKind::Quic {
+            let conn = connect_quic_session(config).await?;
+            return Ok((MuxSession::Quic(conn), None));
+        }
+
+        let (stream, kcp_stats) = connect_server_with(config, transport).await?;
+        let request = Preamble::from_plugin_opts(&config.plugin_opts);
+        let (session_stream, _) =
+            client_session_with(stream, &config.plugin_opts, request, server_connector(config)).await?;
+        Ok((MuxSession::new_client(session_stream, mux_mode), kcp_stats))
+    })
+    .await
 }
 
 struct ConnectionPool {
-    conns: LinkedList<YamuxControl>,
+    conns: LinkedList<(MuxControl, Arc<Session>)>,
 }
 
 thread_local! {
@@ -94,72 +560,319 @@ thread_local! {
     });
 }
 
-async fn handle_client(config: &Config, mut stream: TcpStream, _peer_addr: SocketAddr) -> io::Result<()> {
+/// Connects new KCP sessions for resumable sessions
+fn server_connector(config: &Arc<Config>) -> Connector {
+    let config = config.clone();
+    resume::connector(move || {
+        let config = config.clone();
+        async move { connect_server(&config).await }
+    })
+}
+
+async fn handle_client(
+    config: &Arc<Config>,
+    sessions: &Arc<SessionRegistry>,
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+) -> io::Result<()> {
+    // Destinations of redirected connections are known without a handshake
+    if let Some(ty) = config.plugin_opts.redir {
+        let addr = redir::original_destination(&stream, ty)?;
+        trace!("client {} redirected from {}", peer_addr, addr);
+        let target = Some(ServerAddr::SocketAddr(addr));
+        return relay_client(config, sessions, stream, peer_addr, Frontend::Raw, target).await;
+    }
+
+    handle_frontend(config, sessions, stream, peer_addr).await
+}
+
+/// Handshake with a client in `frontend`, then relay it
+async fn handle_frontend<S, P>(
+    config: &Arc<Config>,
+    sessions: &Arc<SessionRegistry>,
+    mut stream: S,
+    peer_addr: P,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    P: Display,
+{
+    let frontend = config.plugin_opts.frontend.unwrap_or_default();
+    let auth = FrontendAuth::from_plugin_opts(&config.plugin_opts);
+    let target = frontend.handshake(&mut stream, auth.as_ref()).await?;
+    relay_client(config, sessions, stream, peer_addr, frontend, target).await
+}
+
+/// Relay a client through a stream to the server, which connects to `target`, or `SS_LOCAL_*` without a target
+async fn relay_client<S, P>(
+    config: &Arc<Config>,
+    sessions: &Arc<SessionRegistry>,
+    mut stream: S,
+    peer_addr: P,
+    frontend: Frontend,
+    target: Option<ServerAddr>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    P: Display,
+{
+    // Sessions of plain streams are closed here, others by `drive_session`
+    let conn = if config.plugin_opts.mux.unwrap_or_default().is_multiplexed() {
+        open_mux_stream(config, sessions).await.map(|c| (c, None))
+    } else {
+        open_plain_stream(config, sessions).await.map(|(c, s)| (c, Some(s)))
+    };
+
+    let (mut conn, session) = match conn {
+        Ok(c) => c,
+        Err(err) => {
+            let _ = frontend.reply(&mut stream, Some(&err)).await;
+            return Err(err);
+        }
+    };
+
+    let entry = conn.session().access_entry(conn.get_ref().id());
+    let mut stream = AccessLogged::new(stream, entry);
+    stream.set_client(peer_addr);
+    if let Some(ref target) = target {
+        stream.set_upstream(target);
+    }
+
+    // With `forward`, the server reads a header on every stream, the one of a raw connection names `SS_LOCAL_*`
+    let header = target.is_some() || config.plugin_opts.forward.is_some();
+    let result = async {
+        if header {
+            if let Err(err) = write_header(&mut conn, target.as_ref()).await {
+                let _ = frontend.reply(stream.get_mut(), Some(&err)).await;
+                return Err(err);
+            }
+        }
+
+        frontend.reply(stream.get_mut(), None).await?;
+
+        let stream_idle_timeout = idle_timeout(config.plugin_opts.stream_idle_timeout);
+        let relay = copy_bidirectional_idle(&mut stream, &mut conn, stream_idle_timeout);
+        match session {
+            Some(ref session) => tokio::select! {
+                r = relay => r.map(|_| ()),
+                _ = session.closing() => Err(io::Error::new(ErrorKind::ConnectionAborted, "session closed")),
+            },
+            None => relay.await.map(|_| ()),
+        }
+    }
+    .await;
+
+    stream.finish(&result);
+    result
+}
+
+/// Open a stream in one of the pooled sessions, or in a new session
+async fn open_mux_stream(
+    config: &Arc<Config>,
+    sessions: &Arc<SessionRegistry>,
+) -> io::Result<CountedStream<MuxStream>> {
     // Take one valid connection
-    let mut conn = loop {
-        let yamux_conn = CONNECTION_POOL.with(|pool| {
+    loop {
+        let mux_conn = CONNECTION_POOL.with(|pool| {
             let pool = &mut pool.borrow_mut().conns;
             pool.pop_front()
         });
 
-        if let Some(mut yamux_control) = yamux_conn {
-            match yamux_control.open_stream().await {
+        if let Some((mut mux_control, session)) = mux_conn {
+            // Closed when idle or killed
+            if session.is_closed() {
+                trace!("mux connection of closed session {} removed", session.id());
+                continue;
+            }
+
+            match mux_control.open_stream().await {
                 Ok(s) => {
-                    trace!("yamux connection opened {:?}", s);
+                    trace!("mux connection opened {:?}", s);
 
+                    let s = session.counted(s);
                     CONNECTION_POOL.with(|pool| {
-                        pool.borrow_mut().conns.push_back(yamux_control);
+                        pool.borrow_mut().conns.push_back((mux_control, session));
                     });
 
-                    break s;
+                    return Ok(s);
                 }
-                Err(YamuxError::StreamsExhausted) => {
+                Err(MuxError::StreamsExhausted) => {
                     // Return it back to CONNECTION_POOL, then create a new connection
                     CONNECTION_POOL.with(|pool| {
-                        pool.borrow_mut().conns.push_back(yamux_control);
+                        pool.borrow_mut().conns.push_back((mux_control, session));
                     });
                 }
                 Err(err) => {
-                    error!("yamux connection open error: {}", err);
-                    drop(yamux_control);
+                    error!("mux connection open error: {}", err);
+                    drop(mux_control);
                 }
             };
         }
 
         // Make a new connection
-        let kcp_conn = match connect_server(config).await {
-            Ok(c) => c,
-            Err(err) => {
-                error!("kcp server connect error, error: {}", err);
-                continue;
-            }
-        };
-        let mut yamux_session = YamuxSession::new_client(kcp_conn, YamuxConfig::default());
-        let yamux_control = yamux_session.control();
+        let (mux_session, kcp_stats) = open_mux_session(config).await?;
+        let mux_control = mux_session.control();
+        let session = sessions.register(&config.remote_addr, None, kcp_stats);
+        let pooled = (mux_control, session.session().clone());
 
+        let config = config.clone();
         tokio::spawn(async move {
-            loop {
-                match yamux_session.next().await {
-                    Some(Ok(..)) => {}
-                    Some(Err(e)) => {
-                        error!("yamux connection aborted with connection error: {}", e);
-                        break;
-                    }
-                    None => {
-                        trace!("yamux client session closed");
-                        break;
-                    }
-                }
-            }
+            let session_idle_timeout = idle_timeout(config.plugin_opts.session_idle_timeout);
+            drive_session(&config, mux_session, &session, session_idle_timeout).await
         });
 
         CONNECTION_POOL.with(|pool| {
             let pool = &mut pool.borrow_mut().conns;
-            pool.push_front(yamux_control);
+            pool.push_front(pooled);
         });
 
         trace!("kcp connection opened");
+    }
+}
+
+/// Open a dedicated KCP session for one stream
+async fn open_plain_stream(
+    config: &Arc<Config>,
+    sessions: &Arc<SessionRegistry>,
+) -> io::Result<(CountedStream<MuxStream>, SessionHandle)> {
+    let (session_stream, kcp_stats) = open_session(config).await?;
+
+    trace!("kcp connection opened");
+
+    let session = sessions.register(&config.remote_addr, None, kcp_stats);
+    let stream = session
+        .session()
+        .counted(MuxStream::Plain(PlainStream::new(session_stream)));
+    Ok((stream, session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future::BoxFuture;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{
+        opt::PluginOpts,
+        server,
+        transport::{Transport, TransportListener},
+        udp::DatagramLayers,
     };
 
-    tokio::io::copy_bidirectional(&mut conn, &mut stream).await.map(|_| ())
+    /// Sessions in TCP connections to `SS_REMOTE_*`, without `tcp_port` that the built-in transport listens on
+    #[derive(Debug)]
+    struct RemoteTcpTransport;
+
+    impl Transport for RemoteTcpTransport {
+        fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<BoxedSessionStream>> {
+            Box::pin(async move {
+                let stream = TcpStream::connect(config.remote_addr.to_string()).await?;
+                Ok(Box::new(stream) as BoxedSessionStream)
+            })
+        }
+
+        fn listen<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+            Box::pin(async move {
+                let listener = TcpListener::bind(config.remote_addr.to_string()).await?;
+                Ok(Box::new(listener) as Box<dyn TransportListener>)
+            })
+        }
+    }
+
+    impl TransportListener for TcpListener {
+        fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedSessionStream, SocketAddr)>> {
+            Box::pin(async move {
+                let (stream, peer_addr) = TcpListener::accept(self).await?;
+                Ok((Box::new(stream) as BoxedSessionStream, peer_addr))
+            })
+        }
+    }
+
+    async fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Accept connections, replying to the 5 bytes of each one with `tag` and the same bytes
+    async fn tagged_echo(tag: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 5];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(&[tag]).await;
+                        let _ = stream.write_all(&buf).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Config of local or server, carrying sessions in TCP connections to `remote_addr`
+    fn tcp_config(local_addr: SocketAddr, remote_addr: SocketAddr, opts: &str) -> Config {
+        let plugin_opts = PluginOpts::from_str(opts).unwrap();
+        Config {
+            local_addr: ServerAddr::SocketAddr(local_addr),
+            remote_addr: ServerAddr::SocketAddr(remote_addr),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: Some(Arc::new(RemoteTcpTransport)),
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        }
+    }
+
+    async fn connect_listening(addr: SocketAddr) -> TcpStream {
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(s) => return s,
+                Err(..) => time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }
+
+    /// Send `hello` to `addr` once it is listening, and read the reply
+    async fn round_trip(addr: SocketAddr) -> Vec<u8> {
+        let mut stream = connect_listening(addr).await;
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = vec![0u8; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn raw_listener_with_forward() {
+        let upstream = tagged_echo(b'u').await;
+        let target = tagged_echo(b't').await;
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], free_port().await));
+        let listen_addr = SocketAddr::from(([127, 0, 0, 1], free_port().await));
+        let forward_addr = SocketAddr::from(([127, 0, 0, 1], free_port().await));
+
+        let server_config = tcp_config(
+            upstream,
+            remote_addr,
+            &format!("mux=false&stream_header=true&allowed_targets={}", target),
+        );
+        let local_config = tcp_config(
+            listen_addr,
+            remote_addr,
+            &format!("mux=false&forward={}={}", forward_addr, target),
+        );
+        tokio::spawn(server::start_proxy(server_config));
+        drop(connect_listening(remote_addr).await);
+        tokio::spawn(start_proxy(local_config));
+
+        assert_eq!(round_trip(forward_addr).await, b"thello");
+        assert_eq!(round_trip(listen_addr).await, b"uhello");
+    }
 }
@@ -0,0 +1,410 @@
+//! Stream multiplexers over a KCP session, and streams of QUIC connections
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Stream};
+use quinn::{Connection as QuicConnection, VarInt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_yamux::{
+    Config as YamuxConfig, Control as YamuxControl, Error as YamuxError, Session as YamuxSession,
+    StreamHandle as YamuxStream,
+};
+
+use crate::{
+    session::BoxedSessionStream,
+    smux::{Config as SmuxConfig, Control as SmuxControl, Session as SmuxSession, Stream as SmuxStream},
+    transport::quic::{open_quic_stream, QuicSession, QuicStream},
+};
+
+/// Multiplexer protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MuxMode {
+    /// yamux, the default
+    #[default]
+    #[serde(alias = "true")]
+    Yamux,
+    /// smux version 1, compatible with kcptun
+    Smux1,
+    /// smux version 2, compatible with kcptun with `-smuxver 2`
+    Smux2,
+    /// One KCP session for each stream
+    #[serde(rename = "false", alias = "none")]
+    None,
+}
+
+impl MuxMode {
+    /// Whether sessions start with a preamble. kcptun doesn't know about it.
+    pub fn has_preamble(self) -> bool {
+        matches!(self, MuxMode::Yamux | MuxMode::None)
+    }
+
+    /// Whether one KCP session carries multiple streams
+    pub fn is_multiplexed(self) -> bool {
+        !matches!(self, MuxMode::None)
+    }
+}
+
+impl Display for MuxMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MuxMode::Yamux => f.write_str("yamux"),
+            MuxMode::Smux1 => f.write_str("smux1"),
+            MuxMode::Smux2 => f.write_str("smux2"),
+            MuxMode::None => f.write_str("false"),
+        }
+    }
+}
+
+/// Error of opening a stream
+#[derive(Debug)]
+pub enum MuxError {
+    /// Session cannot open more streams, but it is still usable
+    StreamsExhausted,
+    /// Session is broken
+    Io(io::Error),
+}
+
+impl Display for MuxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MuxError::StreamsExhausted => f.write_str("streams exhausted"),
+            MuxError::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<MuxError> for io::Error {
+    fn from(err: MuxError) -> io::Error {
+        match err {
+            MuxError::StreamsExhausted => io::Error::other("streams exhausted"),
+            MuxError::Io(err) => err,
+        }
+    }
+}
+
+/// Multiplexer session, yields streams opened by the remote
+pub enum MuxSession {
+    Yamux(YamuxSession<BoxedSessionStream>),
+    Smux(SmuxSession),
+    Quic(QuicSession),
+}
+
+impl MuxSession {
+    /// Create a client session. `mode` must be a multiplexer.
+    pub fn new_client(stream: BoxedSessionStream, mode: MuxMode) -> MuxSession {
+        match mode {
+            MuxMode::Yamux => MuxSession::Yamux(YamuxSession::new_client(stream, YamuxConfig::default())),
+            MuxMode::Smux1 => MuxSession::Smux(SmuxSession::new_client(stream, SmuxConfig::new(1))),
+            MuxMode::Smux2 => MuxSession::Smux(SmuxSession::new_client(stream, SmuxConfig::new(2))),
+            MuxMode::None => unreachable!("session of non-multiplexed mode"),
+        }
+    }
+
+    /// Create a server session. `mode` must be a multiplexer.
+    pub fn new_server(stream: BoxedSessionStream, mode: MuxMode) -> MuxSession {
+        match mode {
+            MuxMode::Yamux => MuxSession::Yamux(YamuxSession::new_server(stream, YamuxConfig::default())),
+            MuxMode::Smux1 => MuxSession::Smux(SmuxSession::new_server(stream, SmuxConfig::new(1))),
+            MuxMode::Smux2 => MuxSession::Smux(SmuxSession::new_server(stream, SmuxConfig::new(2))),
+            MuxMode::None => unreachable!("session of non-multiplexed mode"),
+        }
+    }
+
+    pub fn control(&self) -> MuxControl {
+        match *self {
+            MuxSession::Yamux(ref s) => MuxControl::Yamux(s.control()),
+            MuxSession::Smux(ref s) => MuxControl::Smux(s.control()),
+            MuxSession::Quic(ref s) => MuxControl::Quic(s.connection()),
+        }
+    }
+
+    /// Close the session without waiting for the remote, its streams fail
+    pub fn abort(self) {
+        // Others close with the stream below them, QUIC connections live while any stream does
+        if let MuxSession::Quic(ref s) = self {
+            s.connection().close(VarInt::from_u32(0), b"");
+        }
+    }
+}
+
+impl Stream for MuxSession {
+    type Item = io::Result<MuxStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match *self.get_mut() {
+            MuxSession::Yamux(ref mut s) => Pin::new(s).poll_next(cx).map(|r| r.map(|r| r.map(MuxStream::Yamux))),
+            MuxSession::Smux(ref mut s) => Pin::new(s).poll_next(cx).map(|r| r.map(|r| r.map(MuxStream::Smux))),
+            MuxSession::Quic(ref mut s) => Pin::new(s).poll_next(cx).map(|r| r.map(|r| r.map(MuxStream::Quic))),
+        }
+    }
+}
+
+/// Handle for opening streams in a session
+#[derive(Clone)]
+pub enum MuxControl {
+    Yamux(YamuxControl),
+    Smux(SmuxControl),
+    Quic(QuicConnection),
+}
+
+impl MuxControl {
+    pub async fn open_stream(&mut self) -> Result<MuxStream, MuxError> {
+        match *self {
+            MuxControl::Yamux(ref mut c) => match c.open_stream().await {
+                Ok(s) => Ok(MuxStream::Yamux(s)),
+                Err(YamuxError::StreamsExhausted) => Err(MuxError::StreamsExhausted),
+                Err(err) => Err(MuxError::Io(err.into())),
+            },
+            MuxControl::Smux(ref mut c) => c.open_stream().await.map(MuxStream::Smux).map_err(MuxError::Io),
+            MuxControl::Quic(ref c) => open_quic_stream(c).await.map(MuxStream::Quic).map_err(MuxError::Io),
+        }
+    }
+
+    pub async fn close(&mut self) {
+        match *self {
+            MuxControl::Yamux(ref mut c) => c.close().await,
+            MuxControl::Smux(ref mut c) => c.close().await,
+            MuxControl::Quic(ref c) => c.close(VarInt::from_u32(0), b""),
+        }
+    }
+}
+
+/// A multiplexed stream
+#[derive(Debug)]
+pub enum MuxStream {
+    Yamux(YamuxStream),
+    Smux(SmuxStream),
+    Quic(QuicStream),
+    Plain(PlainStream),
+}
+
+impl MuxStream {
+    /// Stream ID in the session, always `0` for non-multiplexed streams
+    pub fn id(&self) -> u32 {
+        match *self {
+            MuxStream::Yamux(ref s) => s.id(),
+            MuxStream::Smux(ref s) => s.id(),
+            MuxStream::Quic(ref s) => s.id(),
+            MuxStream::Plain(..) => 0,
+        }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            MuxStream::Yamux(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            MuxStream::Smux(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            MuxStream::Quic(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            MuxStream::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match *self.get_mut() {
+            MuxStream::Yamux(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            MuxStream::Smux(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            MuxStream::Quic(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            MuxStream::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            MuxStream::Yamux(ref mut s) => Pin::new(s).poll_flush(cx),
+            MuxStream::Smux(ref mut s) => Pin::new(s).poll_flush(cx),
+            MuxStream::Quic(ref mut s) => Pin::new(s).poll_flush(cx),
+            MuxStream::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            MuxStream::Yamux(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            MuxStream::Smux(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            MuxStream::Quic(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            MuxStream::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Stream of a non-multiplexed session
+///
+/// KCP cannot close one direction of a session, so data is sent in frames with a 2 bytes length, and an empty frame
+/// marks the end of the stream. The session itself is closed when both directions are finished and it is dropped.
+///
+/// ```plain
+/// +--------+----------+
+/// | LENGTH |   DATA   |
+/// +--------+----------+
+/// | 2 (BE) |  LENGTH  |
+/// +--------+----------+
+/// ```
+pub struct PlainStream {
+    stream: BoxedSessionStream,
+    read_header: [u8; 2],
+    read_header_pos: usize,
+    read_remaining: usize,
+    read_eof: bool,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    write_eof: bool,
+}
+
+impl fmt::Debug for PlainStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PlainStream")
+            .field("read_eof", &self.read_eof)
+            .field("write_eof", &self.write_eof)
+            .finish()
+    }
+}
+
+impl PlainStream {
+    pub fn new(stream: BoxedSessionStream) -> PlainStream {
+        PlainStream {
+            stream,
+            read_header: [0u8; 2],
+            read_header_pos: 0,
+            read_remaining: 0,
+            read_eof: false,
+            read_buf: vec![0u8; 16384],
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_eof: false,
+        }
+    }
+
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+
+        Ok(()).into()
+    }
+}
+
+impl AsyncRead for PlainStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.read_eof {
+                return Ok(()).into();
+            }
+
+            if this.read_remaining > 0 {
+                let max = buf.remaining().min(this.read_remaining).min(this.read_buf.len());
+                let mut read_buf = ReadBuf::new(&mut this.read_buf[..max]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into()).into();
+                }
+                buf.put_slice(read_buf.filled());
+                this.read_remaining -= n;
+                return Ok(()).into();
+            }
+
+            while this.read_header_pos < this.read_header.len() {
+                let mut read_buf = ReadBuf::new(&mut this.read_header[this.read_header_pos..]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into()).into();
+                }
+                this.read_header_pos += n;
+            }
+
+            this.read_header_pos = 0;
+            this.read_remaining = u16::from_be_bytes(this.read_header) as usize;
+            if this.read_remaining == 0 {
+                this.read_eof = true;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PlainStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_buffered(cx))?;
+
+        if this.write_eof {
+            return Err(ErrorKind::BrokenPipe.into()).into();
+        }
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let n = buf.len().min(u16::MAX as usize);
+        this.write_buf.extend_from_slice(&(n as u16).to_be_bytes());
+        this.write_buf.extend_from_slice(&buf[..n]);
+
+        if let Poll::Ready(Err(err)) = this.poll_write_buffered(cx) {
+            return Err(err).into();
+        }
+
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_buffered(cx))?;
+        if !this.write_eof {
+            this.write_eof = true;
+            this.write_buf.extend_from_slice(&[0u8; 2]);
+            ready!(this.poll_write_buffered(cx))?;
+        }
+
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opt::PluginOpts;
+
+    #[test]
+    fn mux_mode_round_trip() {
+        for mode in [MuxMode::Yamux, MuxMode::Smux1, MuxMode::Smux2, MuxMode::None] {
+            let opts = PluginOpts::from_str(&format!("mux={}", mode)).unwrap();
+            assert_eq!(opts.mux, Some(mode));
+            assert_eq!(serde_urlencoded::to_string(&opts).unwrap(), format!("mux={}", mode));
+        }
+    }
+
+    #[test]
+    fn mux_mode_aliases() {
+        assert_eq!(PluginOpts::from_str("mux=true").unwrap().mux, Some(MuxMode::Yamux));
+        assert_eq!(PluginOpts::from_str("mux=none").unwrap().mux, Some(MuxMode::None));
+    }
+}
@@ -1,14 +1,90 @@
 use std::{
+    fmt::{self, Display},
     io,
     net::{IpAddr, SocketAddr},
+    str::FromStr,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_urlencoded::{self, de::Error as DeError, ser::Error as SerError};
 use tokio::net::{self, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket};
 use tokio_kcp::{KcpConfig, KcpNoDelayConfig, KcpStream};
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+use crate::{
+    allowlist::TargetList,
+    compress::CompressAlgorithm,
+    firewall::IpNetList,
+    forward::{ForwardList, ReverseList},
+    frontend::Frontend,
+    mux::MuxMode,
+    proxy_protocol::ProxyProtocol,
+    redir::RedirType,
+    relay::RelayMode,
+    transport::TransportList,
+    udp::{MultipathMode, PathList},
+    unix_socket::UnixSocketMode,
+};
+
+/// Inclusive range of ports, formatted as `START-END`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PortRange, String> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (s.trim(), s.trim()),
+        };
+
+        let start = start
+            .parse::<u16>()
+            .map_err(|err| format!("invalid port range {}, {}", s, err))?;
+        let end = end
+            .parse::<u16>()
+            .map_err(|err| format!("invalid port range {}, {}", s, err))?;
+        if start > end {
+            return Err(format!("invalid port range {}, start > end", s));
+        }
+
+        Ok(PortRange { start, end })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PortRange, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, Clone)]
 pub struct PluginOpts {
     pub mtu: Option<usize>,
     pub nodelay: Option<bool>,
@@ -18,6 +94,124 @@ pub struct PluginOpts {
     pub sndwnd: Option<u16>,
     pub rcvwnd: Option<u16>,
     pub stream: Option<bool>,
+    /// Compress the tunnel with this algorithm, negotiated in the session preamble
+    pub compress: Option<CompressAlgorithm>,
+    /// Stream multiplexer of KCP sessions
+    pub mux: Option<MuxMode>,
+    /// Streams start with a header carrying their destination, which server connects to instead of `SS_LOCAL_*`
+    pub stream_header: Option<bool>,
+    /// Server only connects to these destinations for stream headers
+    pub allowed_targets: Option<TargetList>,
+    /// Server starts upstream connections with a PROXY protocol header carrying the client's address
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Protocol of connections accepted by local. `socks5` and `http` send destinations in stream headers
+    pub frontend: Option<Frontend>,
+    /// Username required by `socks5` and `http` frontends
+    pub frontend_user: Option<String>,
+    /// Password required by `socks5` and `http` frontends
+    pub frontend_password: Option<String>,
+    /// Local also listens on each of these addresses, and server connects their connections to their targets
+    pub forward: Option<ForwardList>,
+    /// Server listens on each of these ports, and local connects their connections to their targets
+    pub reverse: Option<ReverseList>,
+    /// Ports that server listens on for `reverse` of clients
+    pub reverse_port_range: Option<PortRange>,
+    /// Local accepts connections redirected by iptables, and sends their original destinations in stream headers
+    pub redir: Option<RedirType>,
+    /// Sessions survive KCP sessions dying, by connecting again and sending what the peer has missed
+    pub resume: Option<bool>,
+    /// Seconds that a resumable session waits for a new KCP session
+    pub resume_timeout: Option<u64>,
+    /// Streams are closed when nothing has been relayed for this duration (seconds)
+    pub stream_idle_timeout: Option<u64>,
+    /// Sessions are closed when they have had no streams for this duration (seconds)
+    pub session_idle_timeout: Option<u64>,
+    /// Server accepts at most this many sessions
+    pub max_sessions: Option<usize>,
+    /// Server accepts at most this many sessions from each IP address
+    pub max_sessions_per_ip: Option<usize>,
+    /// Server accepts at most this many open streams in each session
+    pub max_streams_per_session: Option<usize>,
+    /// Server dials at most this many upstream connections at the same time
+    pub max_dials: Option<usize>,
+    /// Server relays at most this many KiB per second in each direction of each session
+    pub rate_limit_per_session: Option<u64>,
+    /// Bursts that `rate_limit_per_session` allows (KiB)
+    pub rate_burst_per_session: Option<u64>,
+    /// Server relays at most this many KiB per second in each direction of all sessions from each IP address
+    pub rate_limit_per_ip: Option<u64>,
+    /// Bursts that `rate_limit_per_ip` allows (KiB)
+    pub rate_burst_per_ip: Option<u64>,
+    /// JSON file of users that server authenticates sessions to, with their keys, quotas and rate limits
+    pub users: Option<String>,
+    /// File that server saves the usage of `users` to, `USERS.usage` by default
+    pub users_usage: Option<String>,
+    /// User that local authenticates sessions as
+    pub user: Option<String>,
+    /// Key of `user`
+    pub user_key: Option<String>,
+    /// Server only accepts clients in these networks
+    pub allowed_ips: Option<IpNetList>,
+    /// Server ignores clients in these networks
+    pub denied_ips: Option<IpNetList>,
+    /// Server bans clients whose sessions fail their handshakes this many times within `ban_window`
+    pub ban_failures: Option<u32>,
+    /// Window that `ban_failures` are counted in (seconds)
+    pub ban_window: Option<u64>,
+    /// How long clients are banned (seconds)
+    pub ban_time: Option<u64>,
+    /// Transports that client tries in order, server accepts `tcp` and `ws` on `tcp_port`, `quic` on `quic_port`, if set
+    pub transport: Option<TransportList>,
+    /// TCP port of `tcp` and `ws` transports
+    pub tcp_port: Option<u16>,
+    /// Path of WebSocket handshakes, checked by server if set
+    pub ws_path: Option<String>,
+    /// `Host` of WebSocket handshakes
+    pub ws_host: Option<String>,
+    /// Client wraps WebSocket in TLS
+    pub tls: Option<bool>,
+    /// Server name that client sends and verifies in TLS handshakes
+    pub tls_sni: Option<String>,
+    /// UDP port of `quic` transport
+    pub quic_port: Option<u16>,
+    /// Certificate of `quic` transport, pinned by client
+    pub quic_cert: Option<String>,
+    /// Private key of `quic_cert`, server only
+    pub quic_key: Option<String>,
+    /// Secret that local and server encrypt KCP sessions with, so relays in between cannot read them
+    pub e2e_key: Option<String>,
+    /// How `sskcp-relay` forwards to the next hop
+    pub relay_mode: Option<RelayMode>,
+    /// Server listens on all ports in this range, client hops between them
+    pub server_port_range: Option<PortRange>,
+    /// Maximum KCP sessions that the UDP relay of server relays at once
+    pub max_peers: Option<usize>,
+    /// Client hops to another port in `server_port_range` in this interval (seconds)
+    pub hop_interval: Option<u64>,
+    /// Client hops to another port if nothing was received in this duration (seconds) while it was sending
+    pub hop_timeout: Option<u64>,
+    /// Client sends over all of these interfaces or addresses
+    pub multipath: Option<PathList>,
+    /// Server accepts multipath clients
+    pub accept_multipath: Option<bool>,
+    /// How client chooses paths for sending in `multipath`
+    pub multipath_mode: Option<MultipathMode>,
+    /// Client rebinds its sockets when the network changes, server moves sessions to the client's new address
+    pub roaming: Option<bool>,
+    /// Secret that authenticates datagrams in multipath and roaming mode, required by `roaming`
+    pub session_key: Option<String>,
+    /// Track statistics of KCP sessions, logged when they close
+    pub kcp_stats: Option<bool>,
+    /// File that relayed streams are recorded in, one JSON object per line, or `-` for stdout
+    pub access_log: Option<String>,
+    /// Size of `access_log` files that they are rotated at (MiB)
+    pub access_log_max_size: Option<u64>,
+    /// File mode of the unix domain socket that local listens on, in octal
+    pub unix_socket_mode: Option<UnixSocketMode>,
+    /// Owner of the unix domain socket that local listens on, `USER`, `USER:GROUP` or `:GROUP`
+    pub unix_socket_owner: Option<String>,
+    /// Unix domain socket that accepts admin commands
+    pub admin_socket: Option<String>,
     /// Set `SO_MARK` socket option for outbound sockets
     #[cfg(any(target_os = "linux", target_os = "android"))]
     pub outbound_fwmark: Option<u32>,
@@ -30,6 +224,28 @@ pub struct PluginOpts {
     pub outbound_bind_addr: Option<IpAddr>,
 }
 
+/// Formatted like `SS_PLUGIN_OPTIONS`, with secrets redacted so that they stay out of logs
+impl fmt::Debug for PluginOpts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut opts = self.clone();
+        for secret in [
+            &mut opts.session_key,
+            &mut opts.e2e_key,
+            &mut opts.user_key,
+            &mut opts.frontend_password,
+        ] {
+            if secret.is_some() {
+                *secret = Some("REDACTED".to_owned());
+            }
+        }
+
+        match opts.to_string() {
+            Ok(s) => write!(f, "PluginOpts({})", s),
+            Err(..) => f.debug_struct("PluginOpts").finish_non_exhaustive(),
+        }
+    }
+}
+
 impl PluginOpts {
     pub fn from_str(opt: &str) -> Result<PluginOpts, DeError> {
         serde_urlencoded::from_str(opt)
@@ -71,8 +287,8 @@ impl PluginOpts {
     }
 }
 
-/// Create a TcpStream for connecting to outbound address `addr`
-pub async fn create_outbound_kcp(config: &KcpConfig, addr: SocketAddr, opts: &PluginOpts) -> io::Result<KcpStream> {
+/// Create a UdpSocket for sending to outbound address `addr`
+pub async fn create_outbound_udp(addr: SocketAddr, opts: &PluginOpts) -> io::Result<UdpSocket> {
     let socket = if let Some(addr) = opts.outbound_bind_addr {
         UdpSocket::bind(SocketAddr::new(addr, 0)).await?
     } else {
@@ -107,6 +323,13 @@ pub async fn create_outbound_kcp(config: &KcpConfig, addr: SocketAddr, opts: &Pl
         crate::sys::set_ip_unicast_if(&socket, addr, iface)?;
     }
 
+    Ok(socket)
+}
+
+/// Create a KcpStream for connecting to outbound address `addr`
+pub async fn create_outbound_kcp(config: &KcpConfig, addr: SocketAddr, opts: &PluginOpts) -> io::Result<KcpStream> {
+    let socket = create_outbound_udp(addr, opts).await?;
+
     KcpStream::connect_with_socket(config, socket, addr)
         .await
         .map_err(Into::into)
@@ -161,3 +384,25 @@ pub async fn create_outbound_tcp<A: ToSocketAddrs>(addr: A, opts: &PluginOpts) -
 
     Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "dns resolve to none")))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let opts = PluginOpts::from_str(
+            "mtu=1350&mux=smux2&transport=kcp,ws&allowed_targets=10.0.0.0/8:443&reverse=8080=127.0.0.1:80&\
+             session_key=k1&e2e_key=k2&user=alice&user_key=k3&frontend_user=bob&frontend_password=k4",
+        )
+        .unwrap();
+        let debug = format!("{:?}", opts);
+        assert!(debug.contains("mtu=1350"), "{}", debug);
+        assert!(debug.contains("user=alice"), "{}", debug);
+        assert!(debug.contains("mux=smux2"), "{}", debug);
+        assert!(debug.contains("e2e_key=REDACTED"), "{}", debug);
+        for secret in ["k1", "k2", "k3", "k4"] {
+            assert!(!debug.contains(secret), "{}", debug);
+        }
+    }
+}
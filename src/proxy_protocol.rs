@@ -0,0 +1,157 @@
+//! PROXY protocol of upstream connections
+//!
+//! With `proxy_protocol`, server starts each upstream TCP connection with a HAProxy PROXY protocol header, so that the
+//! upstream sees the address of the client that opened the stream, instead of the server's own address. The header
+//! carries the client's address as source, and the upstream's address as destination. Destinations that clients name
+//! in stream headers are not upstreams, and do not get the header.
+//!
+//! ```plain
+//! v1: PROXY TCP4 203.0.113.7 127.0.0.1 41234 8388\r\n
+//!
+//! v2: +-----------+---------+--------+--------+----------+----------+----------+----------+
+//!     | SIGNATURE | VER_CMD |  FAM   |  LEN   | SRC ADDR | DST ADDR | SRC PORT | DST PORT |
+//!     +-----------+---------+--------+--------+----------+----------+----------+----------+
+//!     |    12     |  0x21   | 0x11 / | 2 (BE) |  4 / 16  |  4 / 16  |  2 (BE)  |  2 (BE)  |
+//!     |           |         | 0x21   |        |          |          |          |          |
+//!     +-----------+---------+--------+--------+----------+----------+----------+----------+
+//! ```
+//!
+//! Both addresses are sent as IPv6 if either of them is. Upstreams on unix domain sockets have no address, they get
+//! `PROXY UNKNOWN\r\n` in v1, and the `LOCAL` command (`0x20`) without addresses (`AF_UNSPEC`, `0x00`) in v2.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2, `PROXY` command
+const V2_VERSION_COMMAND: u8 = 0x21;
+/// Version 2, `LOCAL` command
+const V2_VERSION_LOCAL: u8 = 0x20;
+
+/// Unknown protocol, without addresses
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+/// TCP over IPv4
+const V2_FAMILY_TCP4: u8 = 0x11;
+/// TCP over IPv6
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// Version of the PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Human readable header
+    V1,
+    /// Binary header
+    V2,
+}
+
+impl ProxyProtocol {
+    /// Header of a connection from `src` to `dst`
+    pub fn header(self, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (src_ip, dst_ip) = same_family(src.ip(), dst.ip());
+
+        match self {
+            ProxyProtocol::V1 => {
+                let family = match src_ip {
+                    IpAddr::V4(..) => "TCP4",
+                    IpAddr::V6(..) => "TCP6",
+                };
+                format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family,
+                    src_ip,
+                    dst_ip,
+                    src.port(),
+                    dst.port()
+                )
+                .into_bytes()
+            }
+            ProxyProtocol::V2 => {
+                let mut buf = Vec::with_capacity(16 + 36);
+                buf.extend_from_slice(V2_SIGNATURE);
+                buf.push(V2_VERSION_COMMAND);
+                match (src_ip, dst_ip) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        buf.push(V2_FAMILY_TCP4);
+                        buf.extend_from_slice(&12u16.to_be_bytes());
+                        buf.extend_from_slice(&s.octets());
+                        buf.extend_from_slice(&d.octets());
+                    }
+                    (s, d) => {
+                        buf.push(V2_FAMILY_TCP6);
+                        buf.extend_from_slice(&36u16.to_be_bytes());
+                        buf.extend_from_slice(&to_ipv6(s).octets());
+                        buf.extend_from_slice(&to_ipv6(d).octets());
+                    }
+                }
+                buf.extend_from_slice(&src.port().to_be_bytes());
+                buf.extend_from_slice(&dst.port().to_be_bytes());
+                buf
+            }
+        }
+    }
+
+    /// Header of a connection without addresses, like one to a unix domain socket
+    pub fn unknown_header(self) -> Vec<u8> {
+        match self {
+            ProxyProtocol::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            ProxyProtocol::V2 => {
+                let mut buf = Vec::with_capacity(16);
+                buf.extend_from_slice(V2_SIGNATURE);
+                buf.push(V2_VERSION_LOCAL);
+                buf.push(V2_FAMILY_UNSPEC);
+                buf.extend_from_slice(&0u16.to_be_bytes());
+                buf
+            }
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// `src` and `dst` in the same family, IPv6 if either of them is
+fn same_family(src: IpAddr, dst: IpAddr) -> (IpAddr, IpAddr) {
+    match (src.to_canonical(), dst.to_canonical()) {
+        (s @ IpAddr::V4(..), d @ IpAddr::V4(..)) => (s, d),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_headers() {
+        let src = "203.0.113.7:41234".parse().unwrap();
+        assert_eq!(
+            ProxyProtocol::V1.header(src, "127.0.0.1:8388".parse().unwrap()),
+            b"PROXY TCP4 203.0.113.7 127.0.0.1 41234 8388\r\n"
+        );
+        assert_eq!(
+            ProxyProtocol::V1.header(src, "[::1]:8388".parse().unwrap()),
+            b"PROXY TCP6 ::ffff:203.0.113.7 ::1 41234 8388\r\n"
+        );
+        assert_eq!(ProxyProtocol::V1.unknown_header(), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_headers() {
+        let header = ProxyProtocol::V2.header("203.0.113.7:41234".parse().unwrap(), "127.0.0.1:8388".parse().unwrap());
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 203, 0, 113, 7, 127, 0, 0, 1, 0xa1, 0x12, 0x20, 0xc4]);
+        assert_eq!(header, expected);
+
+        let header = ProxyProtocol::V2.unknown_header();
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(header, expected);
+    }
+}
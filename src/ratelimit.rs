@@ -0,0 +1,290 @@
+//! Bandwidth limits of server
+//!
+//! Each session, and all sessions from the same IP address, may have a token bucket for each direction. Streams read
+//! from clients only as fast as their buckets allow, and write to clients only as fast as their buckets allow, so that
+//! multiplexers and KCP flow control push back on the sender instead of anything being dropped.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+/// Bytes that a blocked stream waits for at least, so that it does not wake for every byte
+const MIN_GRANT: usize = 4096;
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket of bytes
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Bucket that fills with `rate` bytes per second, up to `burst` bytes, starting full. `rate` must not be `0`.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = (now - state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.updated = now;
+    }
+
+    /// Bytes that may pass now
+    pub fn available(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.tokens.max(0.0) as usize
+    }
+
+    /// Time until `n` bytes may pass, at most the time to fill the bucket
+    pub fn wait(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        let missing = (n as f64).min(self.burst) - state.tokens;
+        Duration::from_secs_f64(missing.max(0.0) / self.rate)
+    }
+
+    /// Take `n` bytes, which may overdraw the bucket if other streams took some meanwhile
+    pub fn consume(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.tokens -= n as f64;
+    }
+}
+
+/// Token buckets of both directions
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes from clients
+    up: TokenBucket,
+    /// Bytes to clients
+    down: TokenBucket,
+}
+
+impl RateLimiter {
+    /// Limit both directions to `rate` bytes per second, with bursts of `burst` bytes
+    pub fn new(rate: u64, burst: u64) -> RateLimiter {
+        RateLimiter {
+            up: TokenBucket::new(rate, burst),
+            down: TokenBucket::new(rate, burst),
+        }
+    }
+}
+
+/// Rate limiters that all streams of a session go through, none for unlimited sessions
+#[derive(Debug, Clone, Default)]
+pub struct SessionRate(Vec<Arc<RateLimiter>>);
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Up,
+    Down,
+}
+
+impl SessionRate {
+    pub fn new(limiters: Vec<Arc<RateLimiter>>) -> SessionRate {
+        SessionRate(limiters)
+    }
+
+    /// Also go through `limiter`
+    pub fn with(mut self, limiter: Arc<RateLimiter>) -> SessionRate {
+        self.0.push(limiter);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn buckets(&self, dir: Direction) -> impl Iterator<Item = &TokenBucket> {
+        self.0.iter().map(move |l| match dir {
+            Direction::Up => &l.up,
+            Direction::Down => &l.down,
+        })
+    }
+
+    /// Bytes of `want` that may pass in `dir`, waiting on `sleep` until some may
+    fn poll_grant(
+        &self,
+        cx: &mut Context<'_>,
+        dir: Direction,
+        want: usize,
+        sleep: &mut Option<Pin<Box<Sleep>>>,
+    ) -> Poll<usize> {
+        loop {
+            if let Some(s) = sleep {
+                futures::ready!(s.as_mut().poll(cx));
+                *sleep = None;
+            }
+
+            let grant = self.buckets(dir).map(|b| b.available()).fold(want, usize::min);
+            if grant > 0 {
+                return Poll::Ready(grant);
+            }
+
+            let chunk = want.min(MIN_GRANT);
+            let wait = self.buckets(dir).map(|b| b.wait(chunk)).max().unwrap_or_default();
+            *sleep = Some(Box::pin(time::sleep(wait)));
+        }
+    }
+
+    fn consume(&self, dir: Direction, n: usize) {
+        for bucket in self.buckets(dir) {
+            bucket.consume(n);
+        }
+    }
+}
+
+/// Stream of a client, read and written within the rate limits of its session
+pub struct RateLimited<S> {
+    stream: S,
+    rate: SessionRate,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimited<S> {
+    pub fn new(stream: S, rate: SessionRate) -> RateLimited<S> {
+        RateLimited {
+            stream,
+            rate,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+}
+
+impl<S> AsyncRead for RateLimited<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.rate.is_unlimited() || buf.remaining() == 0 {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        }
+
+        let grant = futures::ready!(this
+            .rate
+            .poll_grant(cx, Direction::Up, buf.remaining(), &mut this.read_sleep));
+
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(grant));
+        futures::ready!(Pin::new(&mut this.stream).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+
+        this.rate.consume(Direction::Up, n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for RateLimited<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.rate.is_unlimited() || buf.is_empty() {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+
+        let grant = futures::ready!(this
+            .rate
+            .poll_grant(cx, Direction::Down, buf.len(), &mut this.write_sleep));
+
+        let n = futures::ready!(Pin::new(&mut this.stream).poll_write(cx, &buf[..grant]))?;
+        this.rate.consume(Direction::Down, n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket_refill() {
+        let bucket = TokenBucket::new(1000, 4000);
+        assert_eq!(bucket.available(), 4000);
+        assert_eq!(bucket.wait(4000), Duration::ZERO);
+
+        bucket.consume(4000);
+        assert_eq!(bucket.available(), 0);
+        assert_eq!(bucket.wait(1000), Duration::from_secs(1));
+
+        time::advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.available(), 500);
+        assert_eq!(bucket.wait(1000), Duration::from_millis(500));
+
+        // Never fuller than the burst
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.available(), 4000);
+        assert_eq!(bucket.wait(10000), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket_overdraw() {
+        let bucket = TokenBucket::new(1000, 4000);
+        bucket.consume(6000);
+        assert_eq!(bucket.available(), 0);
+        // Paying back the 2000 bytes overdrawn comes first
+        assert_eq!(bucket.wait(1000), Duration::from_secs(3));
+
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(bucket.available(), 0);
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(bucket.available(), 1000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_read() {
+        let (mut client, server) = duplex(64 * 1024);
+        client.write_all(&[0u8; 3000]).await.unwrap();
+
+        let rate = SessionRate::default().with(Arc::new(RateLimiter::new(1000, 1000)));
+        let mut stream = RateLimited::new(server, rate);
+        let start = Instant::now();
+        let mut buf = [0u8; 3000];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        // The first 1000 bytes are the burst, the rest take a second each
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+}
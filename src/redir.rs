@@ -0,0 +1,57 @@
+//! Transparent proxy, accepting connections redirected by iptables
+//!
+//! Only available on Linux.
+
+use std::{io, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How connections are redirected to the local listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirType {
+    /// iptables `REDIRECT`, destination is read with `SO_ORIGINAL_DST`
+    Redirect,
+    /// iptables `TPROXY`, destination is the local address of the connection
+    TProxy,
+}
+
+/// Listen for redirected connections on `addr`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub async fn bind_listener(addr: SocketAddr, ty: RedirType) -> io::Result<TcpListener> {
+    use tokio::net::TcpSocket;
+
+    if ty == RedirType::Redirect {
+        return TcpListener::bind(addr).await;
+    }
+
+    let socket = match addr {
+        SocketAddr::V4(..) => TcpSocket::new_v4()?,
+        SocketAddr::V6(..) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    crate::sys::set_ip_transparent(&socket, addr)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Listen for redirected connections on `addr`
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub async fn bind_listener(_addr: SocketAddr, _ty: RedirType) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxy is only supported on Linux",
+    ))
+}
+
+/// Destination of a redirected connection
+pub fn original_destination(stream: &TcpStream, ty: RedirType) -> io::Result<SocketAddr> {
+    match ty {
+        RedirType::TProxy => stream.local_addr(),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        RedirType::Redirect => crate::sys::get_original_destination(stream, stream.local_addr()?),
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        RedirType::Redirect => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
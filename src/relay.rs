@@ -0,0 +1,302 @@
+//! Relay between clients and the next hop, for chaining KCP paths through a midpoint
+//!
+//! ```plain
+//!        KCP (UDP)                 KCP (UDP)
+//! CLIENT ---------> [SSKCP-Relay] ---------> NEXT HOP
+//! ```
+//!
+//! In `kcp` mode, KCP sessions are terminated on the relay, and each one is forwarded in a new KCP session, so both legs
+//! have their own KCP parameters. In `raw` mode, datagrams are forwarded as they are.
+//!
+//! Sessions encrypted with `e2e_key` cannot be read by the relay in either mode. In `raw` mode, datagrams are unwrapped
+//! with the datagram layers of the leg they came from, and wrapped with those of the other leg.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, trace};
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, time};
+
+use crate::{
+    config::{network_required, Config, ServerAddr},
+    opt::create_outbound_udp,
+    transport::{kcp::listen_addrs, transport_of},
+    udp::{check_plugin_opts, check_server_plugin_opts, server::DEFAULT_MAX_PEERS, DatagramLayers, RECV_ERROR_BACKOFF},
+};
+
+/// How the relay forwards to the next hop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    /// Terminate KCP sessions, and forward them in new KCP sessions
+    #[default]
+    Kcp,
+    /// Forward datagrams
+    Raw,
+}
+
+/// Relay mode
+///
+/// Accepts clients on `inbound.remote_addr`, and forwards them to `outbound.remote_addr`. KCP parameters of each leg
+/// come from its own `plugin_opts`.
+pub async fn start_proxy(inbound: Config, outbound: Config) -> io::Result<()> {
+    debug!("start relay with {:?}, next hop {:?}", inbound, outbound);
+
+    check_server_plugin_opts(&inbound.plugin_opts)?;
+    check_plugin_opts(&outbound.plugin_opts)?;
+
+    let mode = inbound.plugin_opts.relay_mode.unwrap_or_default();
+    info!("KCP relay forwarding to {} in {:?} mode", outbound.remote_addr, mode);
+
+    match mode {
+        RelayMode::Kcp => relay_kcp(inbound, outbound).await,
+        RelayMode::Raw => relay_raw(inbound, outbound).await,
+    }
+}
+
+async fn relay_kcp(inbound: Config, outbound: Config) -> io::Result<()> {
+    let mut listener = transport_of(&inbound).listen(&inbound).await?;
+    let outbound = Arc::new(outbound);
+
+    loop {
+        let (mut stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        debug!("accepted {}", peer_addr);
+
+        let outbound = outbound.clone();
+        tokio::spawn(async move {
+            let mut next = match transport_of(&outbound).connect(&outbound).await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("failed to connect next hop for {}, error: {}", peer_addr, err);
+                    return;
+                }
+            };
+
+            match tokio::io::copy_bidirectional(&mut stream, &mut next).await {
+                Ok((up, down)) => trace!("relay {} finished, {} bytes up, {} bytes down", peer_addr, up, down),
+                Err(err) => debug!("relay {} finished with error: {}", peer_addr, err),
+            }
+        });
+    }
+}
+
+struct RawPeer {
+    socket: UdpSocket,
+    last_active: Mutex<Instant>,
+}
+
+impl RawPeer {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+}
+
+type RawPeers = Arc<Mutex<HashMap<(usize, SocketAddr), Arc<RawPeer>>>>;
+
+/// Unwrap a datagram with the layers of the leg that it came from, and wrap it with the layers of the other leg
+fn convert_datagram<'a>(
+    datagram: &'a [u8],
+    from: &DatagramLayers,
+    to: &DatagramLayers,
+    bufs: &'a mut (Vec<u8>, Vec<u8>),
+) -> Option<&'a [u8]> {
+    let (unwrapped, wrapped) = bufs;
+    let datagram = from.unwrap(datagram, unwrapped)?;
+    Some(to.wrap(datagram, wrapped))
+}
+
+async fn relay_raw(inbound: Config, outbound: Config) -> io::Result<()> {
+    let next_addr = match outbound.remote_addr {
+        ServerAddr::SocketAddr(sa) => sa,
+        ServerAddr::DomainName(ref dname, port) => {
+            match tokio::net::lookup_host((dname.as_str(), port)).await?.next() {
+                Some(sa) => sa,
+                None => return Err(io::Error::other("lookup_host resolved to empty")),
+            }
+        }
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    };
+
+    let mut sockets = Vec::new();
+    for addr in listen_addrs(&inbound).await? {
+        sockets.push(Arc::new(UdpSocket::bind(addr).await?));
+    }
+    info!("KCP relay listening on {}", sockets[0].local_addr()?);
+
+    let inbound = Arc::new(inbound);
+    let outbound = Arc::new(outbound);
+    let expire = inbound.kcp_config.session_expire;
+    let peers: RawPeers = Arc::new(Mutex::new(HashMap::new()));
+
+    let mut tasks = Vec::with_capacity(sockets.len());
+    for (index, socket) in sockets.into_iter().enumerate() {
+        let inbound = inbound.clone();
+        let outbound = outbound.clone();
+        let peers = peers.clone();
+        tasks.push(tokio::spawn(async move {
+            raw_recv_loop(socket, index, next_addr, inbound, outbound, expire, peers).await
+        }));
+    }
+
+    for task in tasks {
+        if let Err(err) = task.await {
+            error!("relay task failed: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Forward datagrams received on the `index`th listening socket to the next hop
+async fn raw_recv_loop(
+    socket: Arc<UdpSocket>,
+    index: usize,
+    next_addr: SocketAddr,
+    inbound: Arc<Config>,
+    outbound: Arc<Config>,
+    expire: Duration,
+    peers: RawPeers,
+) {
+    let max_peers = inbound.plugin_opts.max_peers.unwrap_or(DEFAULT_MAX_PEERS);
+    let mut buf = vec![0u8; 65536];
+    let mut layer_bufs = (Vec::new(), Vec::new());
+
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(err) => {
+                error!("relay recv error: {}", err);
+                time::sleep(RECV_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let datagram = match convert_datagram(
+            &buf[..n],
+            &inbound.datagram_layers,
+            &outbound.datagram_layers,
+            &mut layer_bufs,
+        ) {
+            Some(d) => d,
+            None => {
+                trace!("relay dropped {} bytes from {}", n, addr);
+                continue;
+            }
+        };
+
+        let peer = peers.lock().unwrap().get(&(index, addr)).cloned();
+        let peer = match peer {
+            Some(p) => p,
+            None => {
+                if peers.lock().unwrap().len() >= max_peers {
+                    trace!("relay dropped new peer {}, {} peers", addr, max_peers);
+                    continue;
+                }
+
+                let peer_socket = match create_outbound_udp(next_addr, &outbound.plugin_opts).await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("failed to create socket to next hop for {}, error: {}", addr, err);
+                        continue;
+                    }
+                };
+                if let Err(err) = peer_socket.connect(next_addr).await {
+                    error!("failed to connect next hop {} for {}, error: {}", next_addr, addr, err);
+                    continue;
+                }
+
+                let peer = Arc::new(RawPeer {
+                    socket: peer_socket,
+                    last_active: Mutex::new(Instant::now()),
+                });
+                peers.lock().unwrap().insert((index, addr), peer.clone());
+
+                debug!("relay new peer {}", addr);
+
+                let socket = socket.clone();
+                let reply_peer = peer.clone();
+                let peers = peers.clone();
+                let inbound = inbound.clone();
+                let outbound = outbound.clone();
+                tokio::spawn(async move {
+                    raw_reply_loop(socket, addr, &reply_peer, &outbound, &inbound, expire).await;
+                    peers.lock().unwrap().remove(&(index, addr));
+                    debug!("relay peer {} expired", addr);
+                });
+
+                peer
+            }
+        };
+
+        peer.touch();
+
+        if let Err(err) = peer.socket.send(datagram).await {
+            debug!("relay send to next hop for {} error: {}", addr, err);
+        }
+    }
+}
+
+/// Send datagrams from the next hop back to `addr`, until the peer is idle for `expire`
+async fn raw_reply_loop(
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    peer: &RawPeer,
+    outbound: &Config,
+    inbound: &Config,
+    expire: Duration,
+) {
+    let mut buf = vec![0u8; 65536];
+    let mut layer_bufs = (Vec::new(), Vec::new());
+
+    loop {
+        let n = match time::timeout(expire, peer.socket.recv(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => {
+                error!("relay recv from next hop for {} error: {}", addr, err);
+                time::sleep(RECV_ERROR_BACKOFF).await;
+                continue;
+            }
+            Err(..) => {
+                if peer.idle() >= expire {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        peer.touch();
+
+        let datagram = match convert_datagram(
+            &buf[..n],
+            &outbound.datagram_layers,
+            &inbound.datagram_layers,
+            &mut layer_bufs,
+        ) {
+            Some(d) => d,
+            None => {
+                trace!("relay dropped {} bytes from next hop for {}", n, addr);
+                continue;
+            }
+        };
+        if let Err(err) = socket.send_to(datagram, addr).await {
+            debug!("relay send to {} error: {}", addr, err);
+        }
+    }
+}
@@ -0,0 +1,617 @@
+//! Resumable sessions
+//!
+//! A resumable session keeps the bytes it has sent until the peer acknowledges them. When the KCP session below it
+//! dies, the client connects a new one and presents the session's ticket in the preamble. Both sides tell each other
+//! how many bytes they have received, and send the rest again, so the multiplexer above never notices.
+//!
+//! ```plain
+//! +------+------------------+
+//! | TYPE | BODY             |
+//! +------+------------------+
+//! |  1   | LEN(2) DATA(LEN) |  Data
+//! |  2   | OFFSET(8)        |  All bytes before OFFSET are received, also sent as a keepalive
+//! |  3   | OFFSET(8)        |  First frame on a new KCP session, all bytes before OFFSET are received
+//! |  4   |                  |  End of session
+//! +------+------------------+
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use log::{debug, error, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{
+    mux::MuxMode,
+    opt::PluginOpts,
+    session::{resume_session, BoxedSessionStream},
+};
+
+const FRAME_DATA: u8 = 1;
+const FRAME_ACK: u8 = 2;
+const FRAME_RESUME: u8 = 3;
+const FRAME_FIN: u8 = 4;
+
+const MAX_FRAME_DATA: usize = 16384;
+/// Stop reading from the multiplexer when this many bytes are not acknowledged
+const MAX_UNACKED: usize = 4 * 1024 * 1024;
+/// Stop reading from KCP when this many bytes are not taken by the multiplexer
+const MAX_RECV_BUFFER: usize = 1024 * 1024;
+/// Acknowledge after receiving this many bytes
+const ACK_THRESHOLD: u64 = 65536;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// KCP session is considered dead if nothing was received in this duration
+const DEAD_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default duration for resuming a session, in seconds
+pub const DEFAULT_RESUME_TIMEOUT: u64 = 30;
+
+pub const TICKET_LEN: usize = 24;
+
+/// Check that `resume` is not set with smux, which does not carry the session preamble
+pub fn check_plugin_opts(opts: &PluginOpts) -> io::Result<()> {
+    if opts.resume.unwrap_or(false) && matches!(opts.mux, Some(MuxMode::Smux1 | MuxMode::Smux2)) {
+        return Err(io::Error::other("resume is not available with smux1 or smux2"));
+    }
+    Ok(())
+}
+
+/// Identifies a resumable session, issued by the server
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ResumeTicket {
+    id: u64,
+    secret: [u8; 16],
+}
+
+impl ResumeTicket {
+    fn generate() -> ResumeTicket {
+        ResumeTicket {
+            id: rand::random(),
+            secret: rand::random(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; TICKET_LEN] {
+        let mut buf = [0u8; TICKET_LEN];
+        buf[..8].copy_from_slice(&self.id.to_be_bytes());
+        buf[8..].copy_from_slice(&self.secret);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<ResumeTicket> {
+        if buf.len() != TICKET_LEN {
+            return None;
+        }
+
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&buf[..8]);
+        let mut secret = [0u8; 16];
+        secret.copy_from_slice(&buf[8..]);
+
+        Some(ResumeTicket {
+            id: u64::from_be_bytes(id),
+            secret,
+        })
+    }
+}
+
+impl fmt::Debug for ResumeTicket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResumeTicket({:016x})", self.id)
+    }
+}
+
+/// Connects a new KCP session to the server
+pub type Connector = Arc<dyn Fn() -> BoxFuture<'static, io::Result<BoxedSessionStream>> + Send + Sync>;
+
+struct ResumeEntry {
+    secret: [u8; 16],
+    tx: mpsc::Sender<BoxedSessionStream>,
+}
+
+/// Resumable sessions of a server
+#[derive(Default)]
+pub struct ResumeRegistry {
+    sessions: Mutex<HashMap<u64, ResumeEntry>>,
+}
+
+impl ResumeRegistry {
+    fn register(&self) -> (ResumeTicket, mpsc::Receiver<BoxedSessionStream>) {
+        let (tx, rx) = mpsc::channel(1);
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let mut ticket = ResumeTicket::generate();
+        while sessions.contains_key(&ticket.id) {
+            ticket = ResumeTicket::generate();
+        }
+
+        sessions.insert(
+            ticket.id,
+            ResumeEntry {
+                secret: ticket.secret,
+                tx,
+            },
+        );
+        (ticket, rx)
+    }
+
+    /// Channel for handing a new KCP session to the session of `ticket`
+    pub(crate) fn find(&self, ticket: &ResumeTicket) -> Option<mpsc::Sender<BoxedSessionStream>> {
+        let sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get(&ticket.id)?;
+
+        // Compare in constant time
+        let diff = entry
+            .secret
+            .iter()
+            .zip(ticket.secret.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return None;
+        }
+        Some(entry.tx.clone())
+    }
+
+    fn remove(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+}
+
+enum Frame {
+    Data(Vec<u8>),
+    Ack(u64),
+    Resume(u64),
+    Fin,
+}
+
+async fn read_frame<R>(reader: &mut R) -> io::Result<Frame>
+where
+    R: AsyncRead + Unpin,
+{
+    match reader.read_u8().await? {
+        FRAME_DATA => {
+            let len = reader.read_u16().await? as usize;
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data).await?;
+            Ok(Frame::Data(data))
+        }
+        FRAME_ACK => Ok(Frame::Ack(reader.read_u64().await?)),
+        FRAME_RESUME => Ok(Frame::Resume(reader.read_u64().await?)),
+        FRAME_FIN => Ok(Frame::Fin),
+        t => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid resumable frame type {}", t),
+        )),
+    }
+}
+
+fn encode_data(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.push(FRAME_DATA);
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn encode_offset(buf: &mut Vec<u8>, frame_type: u8, offset: u64) {
+    buf.push(frame_type);
+    buf.extend_from_slice(&offset.to_be_bytes());
+}
+
+/// KCP session under a resumable session, with frames read by a task of its own
+struct Transport {
+    writer: WriteHalf<BoxedSessionStream>,
+    frames: mpsc::Receiver<io::Result<Frame>>,
+    reader: JoinHandle<()>,
+}
+
+impl Transport {
+    fn new(stream: BoxedSessionStream) -> Transport {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let (tx, frames) = mpsc::channel(64);
+
+        let reader = tokio::spawn(async move {
+            loop {
+                let frame = read_frame(&mut reader).await;
+                let failed = frame.is_err();
+                if tx.send(frame).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Transport { writer, frames, reader }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf).await?;
+        self.writer.flush().await
+    }
+
+    async fn next_frame(&mut self) -> io::Result<Frame> {
+        match self.frames.recv().await {
+            Some(frame) => frame,
+            None => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+enum Role {
+    Client {
+        ticket: ResumeTicket,
+        connector: Connector,
+    },
+    Server {
+        id: u64,
+        registry: Arc<ResumeRegistry>,
+        resume_rx: mpsc::Receiver<BoxedSessionStream>,
+    },
+}
+
+struct Driver {
+    role: Role,
+    timeout: Duration,
+    app_read: ReadHalf<DuplexStream>,
+    app_write: WriteHalf<DuplexStream>,
+    /// Bytes sent but not acknowledged, starting at `acked`
+    unacked: VecDeque<u8>,
+    acked: u64,
+    sent: u64,
+    /// Bytes received but not taken by the multiplexer
+    recv_buf: VecDeque<u8>,
+    received: u64,
+    ack_sent: u64,
+    last_received: Instant,
+}
+
+/// Wait for a new KCP session handed over by `server_session`
+async fn next_resume(role: &mut Role) -> Option<BoxedSessionStream> {
+    match *role {
+        Role::Server { ref mut resume_rx, .. } => resume_rx.recv().await,
+        Role::Client { .. } => futures::future::pending().await,
+    }
+}
+
+impl Driver {
+    fn ticket_id(&self) -> u64 {
+        match self.role {
+            Role::Client { ref ticket, .. } => ticket.id,
+            Role::Server { id, .. } => id,
+        }
+    }
+
+    async fn run(mut self, stream: BoxedSessionStream) {
+        let id = self.ticket_id();
+        let mut transport = Transport::new(stream);
+
+        loop {
+            match self.serve(&mut transport).await {
+                Ok(()) => {
+                    trace!("resumable session {:016x} finished", id);
+                    break;
+                }
+                Err(err) => {
+                    debug!("resumable session {:016x} lost its kcp session, error: {}", id, err);
+                    drop(transport);
+
+                    match self.reattach().await {
+                        Some(t) => {
+                            debug!("resumable session {:016x} resumed", id);
+                            transport = t;
+                        }
+                        None => {
+                            error!("resumable session {:016x} failed to resume in {:?}", id, self.timeout);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Role::Server { id, ref registry, .. } = self.role {
+            registry.remove(id);
+        }
+    }
+
+    /// Relay between the multiplexer and `transport`, until the session finishes or `transport` fails
+    async fn serve(&mut self, transport: &mut Transport) -> io::Result<()> {
+        let mut app_buf = vec![0u8; MAX_FRAME_DATA];
+        let mut frame_buf = Vec::with_capacity(MAX_FRAME_DATA + 3);
+        let mut keepalive = time::interval(KEEPALIVE_INTERVAL);
+
+        loop {
+            let app_readable = self.unacked.len() < MAX_UNACKED;
+            let app_writable = !self.recv_buf.is_empty();
+            let transport_readable = self.recv_buf.len() < MAX_RECV_BUFFER;
+
+            tokio::select! {
+                r = self.app_read.read(&mut app_buf), if app_readable => {
+                    let n = match r {
+                        Ok(0) | Err(..) => return self.finish(transport).await,
+                        Ok(n) => n,
+                    };
+
+                    self.unacked.extend(&app_buf[..n]);
+                    self.sent += n as u64;
+
+                    frame_buf.clear();
+                    encode_data(&mut frame_buf, &app_buf[..n]);
+                    transport.write(&frame_buf).await?;
+                }
+                r = self.app_write.write(self.recv_buf.as_slices().0), if app_writable => {
+                    match r {
+                        Ok(n) if n > 0 => {
+                            self.recv_buf.drain(..n);
+                        }
+                        _ => return self.finish(transport).await,
+                    }
+                }
+                r = transport.next_frame(), if transport_readable => {
+                    self.last_received = Instant::now();
+
+                    match r? {
+                        Frame::Data(data) => {
+                            self.received += data.len() as u64;
+                            self.recv_buf.extend(&data);
+
+                            if self.received - self.ack_sent >= ACK_THRESHOLD {
+                                self.send_ack(transport, &mut frame_buf).await?;
+                            }
+                        }
+                        Frame::Ack(offset) => self.acknowledge(offset)?,
+                        Frame::Resume(..) => {
+                            trace!("resumable session {:016x} ignored unexpected resume", self.ticket_id());
+                        }
+                        Frame::Fin => {
+                            // Hand the rest to the multiplexer before closing
+                            let (front, back) = self.recv_buf.as_slices();
+                            let _ = self.app_write.write_all(front).await;
+                            let _ = self.app_write.write_all(back).await;
+                            let _ = self.app_write.shutdown().await;
+                            return Ok(());
+                        }
+                    }
+                }
+                Some(stream) = next_resume(&mut self.role) => {
+                    // Client connected again before this side noticed
+                    *transport = Transport::new(stream);
+                    self.handshake(transport).await?;
+                }
+                _ = keepalive.tick() => {
+                    if self.last_received.elapsed() >= DEAD_TIMEOUT {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "received nothing"));
+                    }
+                    self.send_ack(transport, &mut frame_buf).await?;
+                }
+            }
+        }
+    }
+
+    async fn send_ack(&mut self, transport: &mut Transport, frame_buf: &mut Vec<u8>) -> io::Result<()> {
+        frame_buf.clear();
+        encode_offset(frame_buf, FRAME_ACK, self.received);
+        transport.write(frame_buf).await?;
+        self.ack_sent = self.received;
+        Ok(())
+    }
+
+    async fn finish(&mut self, transport: &mut Transport) -> io::Result<()> {
+        let _ = transport.write(&[FRAME_FIN]).await;
+        let _ = transport.writer.shutdown().await;
+        Ok(())
+    }
+
+    /// Forget bytes before `offset`, which the peer has received
+    fn acknowledge(&mut self, offset: u64) -> io::Result<()> {
+        if offset <= self.acked {
+            return Ok(());
+        }
+        if offset > self.sent {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("acknowledged {} bytes, only {} were sent", offset, self.sent),
+            ));
+        }
+
+        self.unacked.drain(..(offset - self.acked) as usize);
+        self.acked = offset;
+        Ok(())
+    }
+
+    /// Exchange offsets on a new KCP session, and send again what the peer has missed
+    async fn handshake(&mut self, transport: &mut Transport) -> io::Result<()> {
+        let mut frame_buf = Vec::with_capacity(MAX_FRAME_DATA + 3);
+        encode_offset(&mut frame_buf, FRAME_RESUME, self.received);
+        transport.write(&frame_buf).await?;
+        self.ack_sent = self.received;
+
+        let offset = match time::timeout(HANDSHAKE_TIMEOUT, transport.next_frame()).await {
+            Ok(Ok(Frame::Resume(offset))) => offset,
+            Ok(Ok(..)) => return Err(io::Error::new(ErrorKind::InvalidData, "expecting resume frame")),
+            Ok(Err(err)) => return Err(err),
+            Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "resume handshake timeout")),
+        };
+        if offset < self.acked {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("peer resumed from {}, {} were acknowledged", offset, self.acked),
+            ));
+        }
+        self.acknowledge(offset)?;
+
+        trace!(
+            "resumable session {:016x} sending {} bytes again",
+            self.ticket_id(),
+            self.unacked.len()
+        );
+
+        let unacked = self.unacked.make_contiguous();
+        for chunk in unacked.chunks(MAX_FRAME_DATA) {
+            frame_buf.clear();
+            encode_data(&mut frame_buf, chunk);
+            transport.writer.write_all(&frame_buf).await?;
+        }
+        transport.writer.flush().await?;
+
+        self.last_received = Instant::now();
+        Ok(())
+    }
+
+    /// Get a new KCP session in time
+    async fn reattach(&mut self) -> Option<Transport> {
+        let deadline = Instant::now() + self.timeout;
+
+        while Instant::now() < deadline {
+            let stream = match self.role {
+                Role::Client {
+                    ref ticket,
+                    ref connector,
+                } => {
+                    let ticket = *ticket;
+                    let connect = async { resume_session(connector().await?, ticket).await };
+                    match time::timeout_at(deadline, connect).await {
+                        Ok(Ok(Some(stream))) => stream,
+                        Ok(Ok(None)) => {
+                            debug!("resumable session {:016x} was declined by server", ticket.id);
+                            return None;
+                        }
+                        Ok(Err(err)) => {
+                            debug!("resumable session {:016x} reconnect error: {}", ticket.id, err);
+                            time::sleep_until((Instant::now() + RECONNECT_INTERVAL).min(deadline)).await;
+                            continue;
+                        }
+                        Err(..) => return None,
+                    }
+                }
+                Role::Server { ref mut resume_rx, .. } => match time::timeout_at(deadline, resume_rx.recv()).await {
+                    Ok(Some(stream)) => stream,
+                    _ => return None,
+                },
+            };
+
+            let mut transport = Transport::new(stream);
+            match self.handshake(&mut transport).await {
+                Ok(()) => return Some(transport),
+                Err(err) => {
+                    debug!("resumable session {:016x} handshake error: {}", self.ticket_id(), err);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn spawn_driver(role: Role, stream: BoxedSessionStream, timeout: Duration) -> BoxedSessionStream {
+    let (app, driver_end) = tokio::io::duplex(65536);
+    let (app_read, app_write) = tokio::io::split(driver_end);
+
+    let driver = Driver {
+        role,
+        timeout,
+        app_read,
+        app_write,
+        unacked: VecDeque::new(),
+        acked: 0,
+        sent: 0,
+        recv_buf: VecDeque::new(),
+        received: 0,
+        ack_sent: 0,
+        last_received: Instant::now(),
+    };
+    tokio::spawn(driver.run(stream));
+
+    Box::new(app)
+}
+
+/// Resumable session of a client, on a KCP session that the server has issued `ticket` for
+pub fn client_stream(
+    stream: BoxedSessionStream,
+    ticket: ResumeTicket,
+    connector: Connector,
+    timeout: Duration,
+) -> BoxedSessionStream {
+    spawn_driver(Role::Client { ticket, connector }, stream, timeout)
+}
+
+/// Register a resumable session of a server, for issuing its ticket before it starts
+pub fn server_register(registry: &Arc<ResumeRegistry>) -> (ResumeTicket, PendingSession) {
+    let (ticket, resume_rx) = registry.register();
+    let pending = PendingSession {
+        id: ticket.id,
+        registry: registry.clone(),
+        resume_rx: Some(resume_rx),
+    };
+    (ticket, pending)
+}
+
+/// Registered resumable session of a server, removed from the registry if it never starts
+pub struct PendingSession {
+    id: u64,
+    registry: Arc<ResumeRegistry>,
+    resume_rx: Option<mpsc::Receiver<BoxedSessionStream>>,
+}
+
+impl PendingSession {
+    /// Start the session on `stream`
+    pub fn start(mut self, stream: BoxedSessionStream, timeout: Duration) -> BoxedSessionStream {
+        let role = Role::Server {
+            id: self.id,
+            registry: self.registry.clone(),
+            resume_rx: self.resume_rx.take().expect("session started twice"),
+        };
+        spawn_driver(role, stream, timeout)
+    }
+}
+
+impl Drop for PendingSession {
+    fn drop(&mut self) {
+        if self.resume_rx.is_some() {
+            self.registry.remove(self.id);
+        }
+    }
+}
+
+/// Build a `Connector` from an async function
+pub fn connector<F, Fut>(f: F) -> Connector
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = io::Result<BoxedSessionStream>> + Send + 'static,
+{
+    Arc::new(move || f().boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_with_smux() {
+        let mut opts = PluginOpts {
+            resume: Some(true),
+            ..Default::default()
+        };
+        assert!(check_plugin_opts(&opts).is_ok());
+        opts.mux = Some(MuxMode::Smux2);
+        assert!(check_plugin_opts(&opts).is_err());
+        opts.resume = None;
+        assert!(check_plugin_opts(&opts).is_ok());
+    }
+}
@@ -1,41 +1,131 @@
-use std::{io, marker::Unpin, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    io::{self, ErrorKind},
+    marker::Unpin,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
-use futures::StreamExt;
-use log::{debug, error, info};
+use futures::{future, StreamExt};
+use log::{debug, error, info, trace};
+use quinn::Endpoint;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
     time,
 };
-use tokio_kcp::KcpListener;
-use tokio_yamux::{Config as YamuxConfig, Session as YamuxSession};
 
 use crate::{
+    access_log::{AccessLog, AccessLogged},
+    admin::{start_admin, CountedStream, SessionHandle, SessionRegistry},
     config::{Config, ServerAddr},
+    crypto::{e2e_session, Role},
+    firewall::Firewall,
+    header::{self, read_header},
+    idle::{copy_bidirectional_idle, idle_timeout},
+    limit::{Limits, SessionPermit},
+    mux::{MuxControl, MuxError, MuxSession, MuxStream, PlainStream},
     opt::create_outbound_tcp,
+    proxy_protocol::ProxyProtocol,
+    ratelimit::{RateLimited, SessionRate},
+    resume::{self, ResumeRegistry},
+    session::{server_session, SessionStream},
+    transport::{
+        quic::{accept_quic_session, bind_quic_endpoint},
+        transport_of, TcpTransport, Transport, TransportListener,
+    },
+    udp::{check_server_plugin_opts, stats::KcpStats},
+    user::{self, server_user_session, Users},
 };
 
+/// Timeout for receiving the header of a new stream
+const STREAM_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Local mode
 ///
 /// ```plain
 ///        KCP (UDP)                 TCP Loopback
 /// CLIENT ---------> [SSKCP-Server] <----------> [SS-Server]
 /// ```
+///
+/// With `tcp_port`, sessions are also accepted over TCP and WebSocket. With `quic_port`, streams are also accepted in
+/// QUIC connections. With `admin_socket`, returns when sessions have been drained.
 pub async fn start_proxy(config: Config) -> io::Result<()> {
     debug!("start server proxy with {:?}", config);
 
+    let mut config = config;
+    if config.firewall.is_none() {
+        config.firewall = Firewall::from_plugin_opts(&config.plugin_opts);
+    }
+    if config.limits.is_none() {
+        config.limits = Some(Arc::new(Limits::from_plugin_opts(&config.plugin_opts)));
+    }
     let config = Arc::new(config);
 
-    let mut listener = match config.remote_addr {
-        ServerAddr::SocketAddr(sa) => KcpListener::bind(config.kcp_config, sa).await?,
-        ServerAddr::DomainName(ref dname, port) => KcpListener::bind(config.kcp_config, (dname.as_str(), port)).await?,
-    };
+    let opts = &config.plugin_opts;
+    check_server_plugin_opts(opts)?;
+    user::check_plugin_opts(opts)?;
+    resume::check_plugin_opts(opts)?;
+    header::check_plugin_opts(opts)?;
 
-    info!("KCP server listening on {}", listener.local_addr().unwrap());
+    let listener = transport_of(&config).listen(&config).await?;
 
-    let yamux_config = YamuxConfig::default();
+    let resume_registry = Arc::new(ResumeRegistry::default());
+    let sessions = Arc::new(SessionRegistry::new(
+        AccessLog::from_plugin_opts(opts)?,
+        config.limits.clone().unwrap_or_default(),
+        Users::from_plugin_opts(opts)?,
+        config.firewall.clone(),
+    ));
 
+    if let Some(ref path) = opts.admin_socket {
+        start_admin(path, sessions.clone(), opts).await?;
+    }
+
+    if opts.tcp_port.is_some() {
+        let tcp_listener = TcpTransport.listen(&config).await?;
+        tokio::spawn(serve_sessions(
+            config.clone(),
+            tcp_listener,
+            resume_registry.clone(),
+            sessions.clone(),
+        ));
+    }
+
+    if opts.quic_port.is_some() {
+        let endpoint = bind_quic_endpoint(&config).await?;
+        info!("QUIC server listening on {}", endpoint.local_addr()?);
+        tokio::spawn(serve_quic(config.clone(), endpoint, sessions.clone()));
+    }
+
+    serve_sessions(config.clone(), listener, resume_registry, sessions.clone()).await;
+
+    info!("draining {} sessions", sessions.list().len());
+    sessions.drained().await;
+    info!("drained");
+
+    if let Some(users) = sessions.users() {
+        users.save_usage()?;
+    }
+    Ok(())
+}
+
+/// Accept sessions on `listener` until draining
+async fn serve_sessions(
+    config: Arc<Config>,
+    mut listener: Box<dyn TransportListener>,
+    resume_registry: Arc<ResumeRegistry>,
+    sessions: Arc<SessionRegistry>,
+) {
     loop {
-        let (stream, peer_addr) = match listener.accept().await {
+        let accepted = tokio::select! {
+            r = listener.accept_with_stats() => r,
+            _ = sessions.draining() => break,
+        };
+
+        let (stream, peer_addr, kcp_stats, permit) = match accepted {
             Ok(s) => s,
             Err(err) => {
                 error!("accept failed with error: {}", err);
@@ -44,49 +134,572 @@ pub async fn start_proxy(config: Config) -> io::Result<()> {
             }
         };
 
+        // Closing the session refuses it
+        if !permits(&config, peer_addr) {
+            debug!("refused session of {}, not permitted by firewall", peer_addr);
+            continue;
+        }
+        // Sessions of the UDP relay have been admitted by it already
+        let permit = match permit.or_else(|| sessions.limits().admit_session(peer_addr.ip())) {
+            Some(p) => p,
+            None => {
+                debug!("refused session of {}, too many sessions", peer_addr);
+                continue;
+            }
+        };
+
         debug!("accepted {}", peer_addr);
 
         let config = config.clone();
-        let mut yamux_stream = YamuxSession::new_server(stream, yamux_config.clone());
+        let resume_registry = resume_registry.clone();
+        let sessions = sessions.clone();
         tokio::spawn(async move {
-            loop {
-                let stream = match yamux_stream.next().await {
-                    Some(Ok(stream)) => stream,
-                    Some(Err(err)) => {
-                        error!("yamux channel {} error: {}", peer_addr, err);
-                        break;
-                    }
-                    None => {
-                        debug!("yamux channel {} closed", peer_addr);
-                        break;
-                    }
-                };
-
-                debug!("yamux accepted stream from {}", peer_addr);
-
-                let config = config.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = handle_client(&config, stream, peer_addr).await {
-                        error!("failed to handle client {}, error: {}", peer_addr, err);
-                    }
-                });
+            handle_session(config, stream, peer_addr, kcp_stats, permit, resume_registry, sessions).await;
+        });
+    }
+}
+
+/// Whether the firewall lets sessions of `peer_addr` in
+fn permits(config: &Config, peer_addr: SocketAddr) -> bool {
+    config.firewall.as_ref().is_none_or(|f| f.permits(peer_addr.ip()))
+}
+
+/// Count a failed handshake of `peer_addr` towards its ban
+fn record_failure(config: &Config, peer_addr: SocketAddr) {
+    if let Some(ref firewall) = config.firewall {
+        firewall.record_failure(peer_addr.ip());
+    }
+}
+
+/// Serve a session of `peer_addr`, carried by any transport
+async fn handle_session<S>(
+    config: Arc<Config>,
+    stream: S,
+    peer_addr: SocketAddr,
+    kcp_stats: Option<Arc<KcpStats>>,
+    permit: SessionPermit,
+    resume_registry: Arc<ResumeRegistry>,
+    sessions: Arc<SessionRegistry>,
+) where
+    S: SessionStream + 'static,
+{
+    let mux_mode = config.plugin_opts.mux.unwrap_or_default();
+
+    let stream = match e2e_session(stream, &config.plugin_opts, Role::Server).await {
+        Ok(s) => s,
+        Err(err) => {
+            error!("kcp session {} e2e handshake error: {}", peer_addr, err);
+            record_failure(&config, peer_addr);
+            return;
+        }
+    };
+
+    let (stream, user) = match sessions.users() {
+        Some(users) => match server_user_session(stream, users).await {
+            Ok((s, user)) => (s, Some(user)),
+            Err(err) => {
+                error!("kcp session {} user authentication error: {}", peer_addr, err);
+                // Disabled users and users over their quota have the right key
+                if user::is_unauthorized(&err) {
+                    record_failure(&config, peer_addr);
+                }
+                return;
+            }
+        },
+        None => (stream, None),
+    };
+
+    let (session_stream, preamble, reverse_listeners) = match server_session(stream, &config, &resume_registry).await {
+        Ok(Some(s)) => s,
+        Ok(None) => {
+            debug!("kcp session {} resumed a session", peer_addr);
+            return;
+        }
+        Err(err) => {
+            error!("kcp session {} handshake error: {}", peer_addr, err);
+            record_failure(&config, peer_addr);
+            return;
+        }
+    };
+
+    let session = sessions.register(peer_addr, user.as_ref().map(|u| u.id()), kcp_stats);
+
+    // Sessions of a user also go through the rate limiter of the user
+    let mut rate = permit.rate().clone();
+    if let Some(limiter) = user.as_ref().and_then(|u| u.rate_limiter()) {
+        rate = rate.with(limiter.clone());
+    }
+
+    if !mux_mode.is_multiplexed() {
+        let stream = session
+            .session()
+            .counted(MuxStream::Plain(PlainStream::new(session_stream)));
+        tokio::select! {
+            r = handle_client(&config, stream, peer_addr, rate.clone()) => {
+                if let Err(err) = r {
+                    error!("failed to handle client {}, error: {}", peer_addr, err);
+                }
+            }
+            _ = session.closing() => debug!("session {} of {} closed", session.id(), peer_addr),
+        }
+        return;
+    }
+
+    let mux_session = MuxSession::new_server(session_stream, mux_mode);
+
+    let reverse_tasks = reverse_listeners
+        .into_iter()
+        .map(|(port, listener)| {
+            tokio::spawn(serve_reverse_port(
+                config.clone(),
+                mux_session.control(),
+                port,
+                listener,
+                peer_addr,
+                rate.clone(),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    // Sessions of reverse tunnels wait for connections to their ports
+    let session_idle_timeout = match preamble.reverse.is_empty() {
+        true => idle_timeout(config.plugin_opts.session_idle_timeout),
+        false => None,
+    };
+    serve_streams(&config, mux_session, peer_addr, &session, session_idle_timeout, &rate).await;
+
+    for task in reverse_tasks {
+        task.abort();
+    }
+}
+
+/// Handle streams opened by `peer_addr` until its session closes, or has had no streams for `session_idle_timeout`
+async fn serve_streams(
+    config: &Arc<Config>,
+    mut mux_session: MuxSession,
+    peer_addr: SocketAddr,
+    session: &SessionHandle,
+    session_idle_timeout: Option<Duration>,
+    rate: &SessionRate,
+) {
+    let closing = session.closing();
+    tokio::pin!(closing);
+    let idle = async {
+        match session_idle_timeout {
+            Some(timeout) => session.idle(timeout).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(idle);
+
+    loop {
+        let next = tokio::select! {
+            r = mux_session.next() => r,
+            _ = &mut closing => {
+                debug!("session {} of {} closed", session.id(), peer_addr);
+                mux_session.abort();
+                break;
+            }
+            _ = &mut idle => {
+                debug!("session {} of {} idle, closing", session.id(), peer_addr);
+                mux_session.abort();
+                break;
+            }
+        };
+
+        let stream = match next {
+            Some(Ok(stream)) => {
+                // Dropping the stream resets it
+                if !session.registry().limits().admit_stream(session.streams()) {
+                    debug!("refused stream of {}, too many streams", peer_addr);
+                    continue;
+                }
+                session.session().counted(stream)
+            }
+            Some(Err(err)) => {
+                error!("mux channel {} error: {}", peer_addr, err);
+                break;
+            }
+            None => {
+                debug!("mux channel {} closed", peer_addr);
+                break;
+            }
+        };
+
+        debug!("mux accepted stream from {}", peer_addr);
+
+        let config = config.clone();
+        let rate = rate.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_client(&config, stream, peer_addr, rate).await {
+                error!("failed to handle client {}, error: {}", peer_addr, err);
             }
         });
     }
 }
 
-async fn handle_client<S>(config: &Config, mut stream: S, _peer_addr: SocketAddr) -> io::Result<()>
+/// Accept connections of the `quic` transport on `quic_port`, until draining
+async fn serve_quic(config: Arc<Config>, endpoint: Endpoint, sessions: Arc<SessionRegistry>) {
+    loop {
+        let incoming = tokio::select! {
+            r = endpoint.accept() => match r {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = sessions.draining() => break,
+        };
+
+        let peer_addr = incoming.remote_address();
+        if !permits(&config, peer_addr) {
+            debug!("ignored quic {}, not permitted by firewall", peer_addr);
+            incoming.ignore();
+            continue;
+        }
+        let permit = match sessions.limits().admit_session(peer_addr.ip()) {
+            Some(p) => p,
+            None => {
+                debug!("refused quic {}, too many sessions", peer_addr);
+                incoming.refuse();
+                continue;
+            }
+        };
+
+        debug!("accepted quic {}", peer_addr);
+
+        let config = config.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let session = match accept_quic_session(incoming).await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("quic connection {} handshake error: {}", peer_addr, err);
+                    record_failure(&config, peer_addr);
+                    return;
+                }
+            };
+            let handle = sessions.register(peer_addr, None, None);
+            let session_idle_timeout = idle_timeout(config.plugin_opts.session_idle_timeout);
+            serve_streams(
+                &config,
+                MuxSession::Quic(session),
+                peer_addr,
+                &handle,
+                session_idle_timeout,
+                permit.rate(),
+            )
+            .await;
+            drop(permit);
+        });
+    }
+}
+
+/// Accept connections on `port` for a reverse tunnel of `peer_addr`, opening a stream in its session for each one
+async fn serve_reverse_port(
+    config: Arc<Config>,
+    control: MuxControl,
+    port: u16,
+    listener: TcpListener,
+    peer_addr: SocketAddr,
+    rate: SessionRate,
+) {
+    info!("reverse tunnel of {} listening on port {}", peer_addr, port);
+
+    let stream_idle_timeout = idle_timeout(config.plugin_opts.stream_idle_timeout);
+
+    loop {
+        let (mut stream, client_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        debug!("reverse tunnel of {} accepted {}", peer_addr, client_addr);
+
+        let mut control = control.clone();
+        let rate = rate.clone();
+        tokio::spawn(async move {
+            let mut conn = match control.open_stream().await {
+                Ok(s) => RateLimited::new(s, rate),
+                Err(MuxError::StreamsExhausted) => {
+                    error!(
+                        "reverse tunnel of {} dropped {}, streams exhausted",
+                        peer_addr, client_addr
+                    );
+                    return;
+                }
+                Err(err) => {
+                    error!("reverse tunnel of {} failed to open stream, error: {}", peer_addr, err);
+                    return;
+                }
+            };
+
+            let result = async {
+                conn.write_all(&port.to_be_bytes()).await?;
+                copy_bidirectional_idle(&mut stream, &mut conn, stream_idle_timeout).await
+            }
+            .await;
+
+            if let Err(err) = result {
+                error!(
+                    "reverse tunnel of {} relaying {}, error: {}",
+                    peer_addr, client_addr, err
+                );
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    config: &Config,
+    stream: CountedStream<MuxStream>,
+    peer_addr: SocketAddr,
+    rate: SessionRate,
+) -> io::Result<()> {
+    let registry = stream.session().registry().clone();
+    let entry = stream.session().access_entry(stream.get_ref().id());
+    let mut stream = AccessLogged::new(RateLimited::new(stream, rate), entry);
+    let result = relay_client(config, &mut stream, peer_addr, registry.limits()).await;
+    stream.finish(&result);
+    result
+}
+
+/// Connect a stream to its target, or `SS_LOCAL_*` without one, and relay it
+async fn relay_client<S>(
+    config: &Config,
+    stream: &mut AccessLogged<S>,
+    peer_addr: SocketAddr,
+    limits: &Limits,
+) -> io::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut local_stream = match config.local_addr {
-        ServerAddr::SocketAddr(ref a) => create_outbound_tcp(a, &config.plugin_opts).await?,
-        ServerAddr::DomainName(ref dname, port) => {
+    let stream_header = config.plugin_opts.stream_header.unwrap_or(false);
+    let target = if stream_header {
+        match time::timeout(STREAM_HEADER_TIMEOUT, read_header(stream)).await {
+            Ok(r) => r?,
+            Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "stream header timeout")),
+        }
+    } else {
+        None
+    };
+    let default_upstream = target.is_none();
+    let target = target.unwrap_or_else(|| config.local_addr.clone());
+
+    trace!("stream from {} connecting to {}", peer_addr, target);
+    stream.set_upstream(&target);
+
+    // Destinations from clients are checked, SS_LOCAL_* is trusted
+    let allowed_targets = match config.plugin_opts.allowed_targets {
+        Some(ref allowed) if !default_upstream => Some(allowed),
+        _ => None,
+    };
+    // Only SS_LOCAL_* expects the PROXY protocol header, other destinations would read it as data
+    let proxy_protocol = config.plugin_opts.proxy_protocol.filter(|_| default_upstream);
+
+    let permit = match limits.admit_dial() {
+        Some(p) => p,
+        None => return Err(io::Error::new(ErrorKind::ConnectionRefused, "too many upstream dials")),
+    };
+    let local_stream = match (allowed_targets, target) {
+        (Some(allowed), ref target) => allowed.connect(target, &config.plugin_opts).await?,
+        (None, ServerAddr::SocketAddr(ref a)) => create_outbound_tcp(a, &config.plugin_opts).await?,
+        (None, ServerAddr::DomainName(ref dname, port)) => {
             create_outbound_tcp((dname.as_str(), port), &config.plugin_opts).await?
         }
+        #[cfg(unix)]
+        (None, ServerAddr::UnixSocket(ref path)) => {
+            let local_stream = UnixStream::connect(path).await?;
+            drop(permit);
+            return relay_upstream(config, stream, local_stream, peer_addr, None, proxy_protocol).await;
+        }
+        #[cfg(not(unix))]
+        (None, ServerAddr::UnixSocket(..)) => {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "unix domain sockets are only supported on unix",
+            ));
+        }
     };
 
-    tokio::io::copy_bidirectional(&mut stream, &mut local_stream)
+    drop(permit);
+
+    let upstream_addr = local_stream.peer_addr()?;
+    relay_upstream(
+        config,
+        stream,
+        local_stream,
+        peer_addr,
+        Some(upstream_addr),
+        proxy_protocol,
+    )
+    .await
+}
+
+/// Relay a stream to its connected upstream, at `upstream_addr` unless it is a unix domain socket, starting with a
+/// header of `proxy_protocol` if set
+async fn relay_upstream<S, U>(
+    config: &Config,
+    stream: &mut S,
+    mut local_stream: U,
+    peer_addr: SocketAddr,
+    upstream_addr: Option<SocketAddr>,
+    proxy_protocol: Option<ProxyProtocol>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(version) = proxy_protocol {
+        // Unix domain sockets have no address
+        let header = match upstream_addr {
+            Some(upstream_addr) => version.header(peer_addr, upstream_addr),
+            None => version.unknown_header(),
+        };
+        local_stream.write_all(&header).await?;
+    }
+
+    let stream_idle_timeout = idle_timeout(config.plugin_opts.stream_idle_timeout);
+    copy_bidirectional_idle(stream, &mut local_stream, stream_idle_timeout)
         .await
         .map(|_| ())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use crate::{header::write_header, opt::PluginOpts, udp::DatagramLayers};
+
+    /// First bytes that the upstream receives for a stream of `hello`, after a stream header if `header` is set, naming
+    /// the upstream if `true` or the default upstream if `false`
+    async fn upstream_receives(opts: &str, header: Option<bool>) -> Vec<u8> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+
+        let plugin_opts = PluginOpts::from_str(opts).unwrap();
+        let config = Config {
+            local_addr: ServerAddr::SocketAddr(upstream_addr),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        };
+
+        let (mut client, server) = duplex(1024);
+        if let Some(named) = header {
+            let target = ServerAddr::SocketAddr(upstream_addr);
+            write_header(&mut client, Some(&target).filter(|_| named))
+                .await
+                .unwrap();
+        }
+        client.write_all(b"hello").await.unwrap();
+
+        let peer_addr = "203.0.113.7:41234".parse().unwrap();
+        let mut stream = AccessLogged::new(server, None);
+        let limits = Limits::default();
+        let relay = relay_client(&config, &mut stream, peer_addr, &limits);
+        let accept = async {
+            let (mut upstream_stream, _) = upstream.accept().await.unwrap();
+            let mut buf = vec![0u8; 64];
+            let n = upstream_stream.read(&mut buf).await.unwrap();
+            buf.truncate(n);
+            buf
+        };
+
+        tokio::select! {
+            _ = relay => panic!("relay ended before the upstream received anything"),
+            buf = accept => buf,
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_header_to_upstream() {
+        let received = upstream_receives("proxy_protocol=v1", None).await;
+        assert!(received.starts_with(b"PROXY TCP4 203.0.113.7 127.0.0.1 41234 "));
+    }
+
+    #[tokio::test]
+    async fn proxy_header_to_default_upstream() {
+        // Not in allowed_targets, SS_LOCAL_* is trusted
+        let received = upstream_receives(
+            "proxy_protocol=v1&stream_header=true&allowed_targets=10.0.0.0/8",
+            Some(false),
+        )
+        .await;
+        assert!(received.starts_with(b"PROXY TCP4 203.0.113.7 127.0.0.1 41234 "));
+    }
+
+    /// First bytes that an upstream on a unix domain socket receives for a stream of `hello`
+    #[cfg(unix)]
+    async fn unix_upstream_receives(opts: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("sskcp-upstream-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let upstream = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let plugin_opts = PluginOpts::from_str(opts).unwrap();
+        let config = Config {
+            local_addr: ServerAddr::UnixSocket(path.clone()),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        };
+
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"hello").await.unwrap();
+
+        let peer_addr = "203.0.113.7:41234".parse().unwrap();
+        let mut stream = AccessLogged::new(server, None);
+        let limits = Limits::default();
+        let relay = relay_client(&config, &mut stream, peer_addr, &limits);
+        let accept = async {
+            let (mut upstream_stream, _) = upstream.accept().await.unwrap();
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"hello") {
+                let mut chunk = [0u8; 64];
+                let n = upstream_stream.read(&mut chunk).await.unwrap();
+                assert!(n > 0);
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            buf
+        };
+
+        let received = tokio::select! {
+            _ = relay => panic!("relay ended before the upstream received anything"),
+            buf = accept => buf,
+        };
+        std::fs::remove_file(&path).unwrap();
+        received
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unknown_proxy_header_to_unix_upstream() {
+        let received = unix_upstream_receives("proxy_protocol=v1").await;
+        assert_eq!(received, b"PROXY UNKNOWN\r\nhello");
+
+        let received = unix_upstream_receives("proxy_protocol=v2").await;
+        assert_eq!(received, b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00hello");
+    }
+
+    #[tokio::test]
+    async fn no_proxy_header_to_stream_header_target() {
+        let received = upstream_receives(
+            "proxy_protocol=v1&stream_header=true&allowed_targets=127.0.0.1/32",
+            Some(true),
+        )
+        .await;
+        assert_eq!(received, b"hello");
+    }
+}
@@ -0,0 +1,594 @@
+//! KCP session setup
+//!
+//! A client may start a KCP session with a preamble for negotiating session parameters, before the multiplexer
+//! starts. The server replies with the parameters it accepted. Sessions without a preamble are accepted with
+//! default parameters, so clients that do not need negotiation stay compatible.
+//!
+//! ```plain
+//! +-------+---------+---------------------------------------+
+//! | MAGIC | VERSION | OPTIONS                               |
+//! +-------+---------+---------------------------------------+
+//! | SKCP  |    1    | TYPE(1) LEN(1) VALUE(LEN) ... TYPE(0) |
+//! +-------+---------+---------------------------------------+
+//! ```
+//!
+//! The first byte of a yamux frame is always `0`, which cannot be confused with `MAGIC`. Sessions multiplexed by smux
+//! never have a preamble, for compatibility with kcptun, so both sides must be configured with the same parameters.
+//!
+//! A client asks for a resumable session with an empty `RESUME` option, and the server replies with a ticket. A client
+//! that lost its KCP session presents the ticket on a new one, and the server replies with the same ticket if the
+//! session is still there.
+//!
+//! A client asks the server to listen on some ports with a `REVERSE` option, carrying 2 bytes (BE) for each port. The
+//! server replies with the ports it accepted and is listening on, and opens a stream to the client for each connection
+//! accepted on them.
+
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::{debug, error, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+    time,
+};
+
+use crate::{
+    compress::{CompressAlgorithm, CompressedStream},
+    config::{network_required, Config, ServerAddr},
+    opt::PluginOpts,
+    resume::{self, Connector, ResumeRegistry, ResumeTicket, DEFAULT_RESUME_TIMEOUT},
+};
+
+const PREAMBLE_MAGIC: &[u8] = b"SKCP";
+const PREAMBLE_VERSION: u8 = 1;
+
+const OPTION_END: u8 = 0;
+const OPTION_COMPRESS: u8 = 1;
+const OPTION_RESUME: u8 = 2;
+const OPTION_REVERSE: u8 = 3;
+
+/// Maximum number of ports in a `REVERSE` option
+pub const MAX_REVERSE_PORTS: usize = 127;
+
+/// Timeout for receiving the preamble of a new session
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Byte stream of an established session, ready for the multiplexer
+pub trait SessionStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> SessionStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+pub type BoxedSessionStream = Box<dyn SessionStream>;
+
+/// `RESUME` option of the session preamble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeOption {
+    /// Client asks for a resumable session
+    New,
+    /// Ticket of a resumable session
+    Ticket(ResumeTicket),
+}
+
+/// Parameters negotiated in the session preamble
+#[derive(Debug, Clone, Default)]
+pub struct Preamble {
+    pub compress: CompressAlgorithm,
+    pub resume: Option<ResumeOption>,
+    /// Ports that the server listens on for reverse tunnels
+    pub reverse: Vec<u16>,
+}
+
+impl Preamble {
+    /// Parameters that a client requests with `opts`
+    pub fn from_plugin_opts(opts: &PluginOpts) -> Preamble {
+        Preamble {
+            compress: opts.compress.unwrap_or_default(),
+            resume: if opts.resume.unwrap_or(false) {
+                Some(ResumeOption::New)
+            } else {
+                None
+            },
+            reverse: Vec::new(),
+        }
+    }
+
+    /// Whether the session requires a preamble
+    pub fn is_required(&self) -> bool {
+        self.compress != CompressAlgorithm::None || self.resume.is_some() || !self.reverse.is_empty()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16);
+        buf.extend_from_slice(PREAMBLE_MAGIC);
+        buf.push(PREAMBLE_VERSION);
+
+        if self.compress != CompressAlgorithm::None {
+            buf.push(OPTION_COMPRESS);
+            buf.push(1);
+            buf.push(self.compress.as_u8());
+        }
+
+        match self.resume {
+            Some(ResumeOption::New) => {
+                buf.push(OPTION_RESUME);
+                buf.push(0);
+            }
+            Some(ResumeOption::Ticket(ref ticket)) => {
+                buf.push(OPTION_RESUME);
+                buf.push(resume::TICKET_LEN as u8);
+                buf.extend_from_slice(&ticket.to_bytes());
+            }
+            None => {}
+        }
+
+        if !self.reverse.is_empty() {
+            buf.push(OPTION_REVERSE);
+            buf.push((self.reverse.len() * 2) as u8);
+            for port in &self.reverse {
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+
+        buf.push(OPTION_END);
+        buf
+    }
+
+    /// Read a preamble, with `MAGIC` already consumed
+    async fn read_after_magic<S>(stream: &mut S) -> io::Result<Preamble>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let version = stream.read_u8().await?;
+        if version != PREAMBLE_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported preamble version {}", version),
+            ));
+        }
+
+        let mut preamble = Preamble::default();
+        loop {
+            let option = stream.read_u8().await?;
+            if option == OPTION_END {
+                break;
+            }
+
+            let len = stream.read_u8().await? as usize;
+            let mut value = [0u8; 255];
+            stream.read_exact(&mut value[..len]).await?;
+            let value = &value[..len];
+
+            match option {
+                OPTION_COMPRESS if len == 1 => {
+                    // Unknown algorithms are declined in the reply
+                    preamble.compress = CompressAlgorithm::from_u8(value[0]).unwrap_or_default();
+                }
+                OPTION_RESUME if len == 0 => {
+                    preamble.resume = Some(ResumeOption::New);
+                }
+                OPTION_RESUME => {
+                    preamble.resume = ResumeTicket::from_bytes(value).map(ResumeOption::Ticket);
+                }
+                OPTION_REVERSE if len & 1 == 0 => {
+                    preamble.reverse = value.chunks(2).map(|p| u16::from_be_bytes([p[0], p[1]])).collect();
+                }
+                _ => {
+                    trace!("preamble ignored option {} with {} bytes", option, len);
+                }
+            }
+        }
+
+        Ok(preamble)
+    }
+
+    async fn read_from<S>(stream: &mut S) -> io::Result<Preamble>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic).await?;
+        if magic != PREAMBLE_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid preamble magic"));
+        }
+        Preamble::read_after_magic(stream).await
+    }
+
+    async fn write_to<S>(&self, stream: &mut S) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        stream.write_all(&self.encode()).await?;
+        stream.flush().await
+    }
+}
+
+/// Stream with some bytes that were read ahead put back in front of it
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    prefix_pos: usize,
+    stream: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, stream: S) -> Rewind<S> {
+        Rewind {
+            prefix,
+            prefix_pos: 0,
+            stream,
+        }
+    }
+}
+
+impl<S> AsyncRead for Rewind<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.prefix_pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.prefix_pos);
+            buf.put_slice(&this.prefix[this.prefix_pos..this.prefix_pos + n]);
+            this.prefix_pos += n;
+            return Ok(()).into();
+        }
+
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for Rewind<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+fn wrap_session<S>(stream: S, preamble: &Preamble) -> io::Result<BoxedSessionStream>
+where
+    S: SessionStream + 'static,
+{
+    match preamble.compress {
+        CompressAlgorithm::None => Ok(Box::new(stream)),
+        algorithm => Ok(Box::new(CompressedStream::new(stream, algorithm)?)),
+    }
+}
+
+fn resume_timeout(opts: &PluginOpts) -> Duration {
+    Duration::from_secs(opts.resume_timeout.unwrap_or(DEFAULT_RESUME_TIMEOUT))
+}
+
+async fn read_reply<S>(stream: &mut S) -> io::Result<Preamble>
+where
+    S: AsyncRead + Unpin,
+{
+    match time::timeout(PREAMBLE_TIMEOUT, Preamble::read_from(stream)).await {
+        Ok(r) => r,
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "preamble reply timeout")),
+    }
+}
+
+/// Start a client session on `stream`, sending the preamble if `opts` requires one
+///
+/// Resumable sessions connect new KCP sessions with `connector`.
+pub async fn client_session<S>(stream: S, opts: &PluginOpts, connector: Connector) -> io::Result<BoxedSessionStream>
+where
+    S: SessionStream + 'static,
+{
+    client_session_with(stream, opts, Preamble::from_plugin_opts(opts), connector)
+        .await
+        .map(|(stream, _)| stream)
+}
+
+/// Start a client session on `stream`, requesting parameters in `request`
+///
+/// Returns the parameters that the server accepted.
+pub async fn client_session_with<S>(
+    mut stream: S,
+    opts: &PluginOpts,
+    request: Preamble,
+    connector: Connector,
+) -> io::Result<(BoxedSessionStream, Preamble)>
+where
+    S: SessionStream + 'static,
+{
+    if !opts.mux.unwrap_or_default().has_preamble() {
+        // Peer is configured with the same parameters
+        return wrap_session(stream, &request).map(|s| (s, request));
+    }
+    // A reply shows that the transport works, so that client can fall back to the next one
+    let probe = opts.transport.as_ref().is_some_and(|t| t.0.len() > 1);
+    if !request.is_required() && !probe {
+        return Ok((Box::new(stream), request));
+    }
+
+    request.write_to(&mut stream).await?;
+    let accepted = read_reply(&mut stream).await?;
+
+    if accepted.compress != request.compress {
+        debug!(
+            "server declined compress {}, using {}",
+            request.compress, accepted.compress
+        );
+    }
+
+    let stream: BoxedSessionStream = match accepted.resume {
+        Some(ResumeOption::Ticket(ticket)) => {
+            trace!("session is resumable with {:?}", ticket);
+            resume::client_stream(Box::new(stream), ticket, connector, resume_timeout(opts))
+        }
+        _ => {
+            if request.resume.is_some() {
+                debug!("server declined resume");
+            }
+            Box::new(stream)
+        }
+    };
+
+    wrap_session(stream, &accepted).map(|s| (s, accepted))
+}
+
+/// Present `ticket` on a new KCP session, `None` if the server no longer has the session
+pub(crate) async fn resume_session(
+    mut stream: BoxedSessionStream,
+    ticket: ResumeTicket,
+) -> io::Result<Option<BoxedSessionStream>> {
+    let request = Preamble {
+        resume: Some(ResumeOption::Ticket(ticket)),
+        ..Default::default()
+    };
+    request.write_to(&mut stream).await?;
+
+    let accepted = read_reply(&mut stream).await?;
+    if accepted.resume == Some(ResumeOption::Ticket(ticket)) {
+        Ok(Some(stream))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Listeners of the reverse tunnels accepted by a server, with their ports
+pub type ReverseListeners = Vec<(u16, TcpListener)>;
+
+/// Listen on `port` of the address of `SS_REMOTE_HOST`
+async fn bind_reverse_port(remote_addr: &ServerAddr, port: u16) -> io::Result<TcpListener> {
+    match *remote_addr {
+        ServerAddr::SocketAddr(sa) => TcpListener::bind(SocketAddr::new(sa.ip(), port)).await,
+        ServerAddr::DomainName(ref dname, _) => TcpListener::bind((dname.as_str(), port)).await,
+        ServerAddr::UnixSocket(ref path) => Err(network_required(path)),
+    }
+}
+
+/// Listen on the reverse tunnel ports of `request` for a new session, removing the ports that fail from it
+///
+/// Ports are confirmed only when they are listened on, others are declined.
+async fn bind_reverse_ports(config: &Config, request: &mut Preamble) -> ReverseListeners {
+    let mut reverse_listeners = Vec::new();
+    for port in mem::take(&mut request.reverse) {
+        match bind_reverse_port(&config.remote_addr, port).await {
+            Ok(listener) => {
+                request.reverse.push(port);
+                reverse_listeners.push((port, listener));
+            }
+            Err(err) => error!("reverse tunnel failed to listen on port {}, error: {}", port, err),
+        }
+    }
+    reverse_listeners
+}
+
+/// Accept a server session on `stream`, replying to the preamble if the client sent one
+///
+/// Returns the session with the parameters accepted and the listeners of its reverse tunnels, or `None` if `stream`
+/// resumed a session in `registry`, which carries on with it.
+pub async fn server_session<S>(
+    mut stream: S,
+    config: &Config,
+    registry: &Arc<ResumeRegistry>,
+) -> io::Result<Option<(BoxedSessionStream, Preamble, ReverseListeners)>>
+where
+    S: SessionStream + 'static,
+{
+    let opts = &config.plugin_opts;
+    if !opts.mux.unwrap_or_default().has_preamble() {
+        // Peer is configured with the same parameters
+        let accepted = Preamble::from_plugin_opts(opts);
+        return wrap_session(stream, &accepted).map(|s| Some((s, accepted, Vec::new())));
+    }
+
+    let first = match time::timeout(PREAMBLE_TIMEOUT, stream.read_u8()).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "session first byte timeout")),
+    };
+
+    if first != PREAMBLE_MAGIC[0] {
+        return Ok(Some((
+            Box::new(Rewind::new(vec![first], stream)),
+            Preamble::default(),
+            Vec::new(),
+        )));
+    }
+
+    let mut request = match time::timeout(PREAMBLE_TIMEOUT, async {
+        let mut magic = [0u8; 3];
+        stream.read_exact(&mut magic).await?;
+        if magic != PREAMBLE_MAGIC[1..] {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid preamble magic"));
+        }
+        Preamble::read_after_magic(&mut stream).await
+    })
+    .await
+    {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "preamble timeout")),
+    };
+
+    trace!("session preamble {:?}", request);
+
+    // Compression is declined by `compress=none`
+    if opts.compress == Some(CompressAlgorithm::None) {
+        request.compress = CompressAlgorithm::None;
+    }
+
+    // Streams of reverse tunnels are opened by the multiplexer
+    let multiplexed = opts.mux.unwrap_or_default().is_multiplexed();
+    request.reverse.retain(|port| match opts.reverse_port_range {
+        Some(ports) => multiplexed && ports.contains(*port),
+        None => false,
+    });
+
+    let resume_enabled = opts.resume.unwrap_or(false);
+    match request.resume {
+        Some(ResumeOption::Ticket(ticket)) => {
+            let session = if resume_enabled { registry.find(&ticket) } else { None };
+
+            let mut accepted = Preamble::default();
+            if session.is_some() {
+                accepted.resume = Some(ResumeOption::Ticket(ticket));
+            }
+            accepted.write_to(&mut stream).await?;
+
+            match session {
+                Some(tx) => match tx.send(Box::new(stream)).await {
+                    Ok(()) => Ok(None),
+                    Err(..) => Err(io::Error::new(ErrorKind::NotFound, "resumed session has finished")),
+                },
+                None => Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("no session for {:?}", ticket),
+                )),
+            }
+        }
+        Some(ResumeOption::New) if resume_enabled => {
+            let reverse_listeners = bind_reverse_ports(config, &mut request).await;
+            let (ticket, pending) = resume::server_register(registry);
+
+            let accepted = Preamble {
+                resume: Some(ResumeOption::Ticket(ticket)),
+                ..request
+            };
+            accepted.write_to(&mut stream).await?;
+
+            let stream = pending.start(Box::new(stream), resume_timeout(opts));
+            wrap_session(stream, &accepted).map(|s| Some((s, accepted, reverse_listeners)))
+        }
+        _ => {
+            let reverse_listeners = bind_reverse_ports(config, &mut request).await;
+            let accepted = Preamble {
+                resume: None,
+                ..request
+            };
+            accepted.write_to(&mut stream).await?;
+
+            wrap_session(stream, &accepted).map(|s| Some((s, accepted, reverse_listeners)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+
+    use crate::udp::DatagramLayers;
+
+    #[tokio::test]
+    async fn reverse_port_in_use_is_declined() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let free_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let plugin_opts = PluginOpts::from_str("reverse_port_range=1-65535").unwrap();
+        let config = Config {
+            local_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        };
+
+        let (client, server) = duplex(1024);
+        let registry = Arc::new(ResumeRegistry::default());
+        let request = Preamble {
+            reverse: vec![taken_port, free_port],
+            ..Default::default()
+        };
+        let connector = resume::connector(|| async { Err(io::Error::other("not resumable")) });
+        let (client, server) = tokio::join!(
+            client_session_with(client, &config.plugin_opts, request, connector),
+            server_session(server, &config, &registry),
+        );
+
+        let (_, accepted) = client.unwrap();
+        assert_eq!(accepted.reverse, vec![free_port]);
+        let (_, _, listeners) = server.unwrap().unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].1.local_addr().unwrap().port(), free_port);
+    }
+
+    #[tokio::test]
+    async fn reverse_ports_not_bound_when_resuming() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let plugin_opts = PluginOpts::from_str("reverse_port_range=1-65535&resume=true").unwrap();
+        let config = Config {
+            local_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        };
+
+        // The resumed session keeps the listeners it has
+        let registry = Arc::new(ResumeRegistry::default());
+        let (ticket, _pending) = resume::server_register(&registry);
+        let request = Preamble {
+            reverse: vec![port],
+            resume: Some(ResumeOption::Ticket(ticket)),
+            ..Default::default()
+        };
+
+        let (mut client, server) = duplex(1024);
+        request.write_to(&mut client).await.unwrap();
+        let resumed = server_session(server, &config, &registry).await.unwrap();
+        assert!(resumed.is_none());
+
+        let accepted = Preamble::read_from(&mut client).await.unwrap();
+        assert!(matches!(accepted.resume, Some(ResumeOption::Ticket(..))));
+        assert!(accepted.reverse.is_empty());
+    }
+}
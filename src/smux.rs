@@ -0,0 +1,992 @@
+//! Stream multiplexer compatible with [smux](https://github.com/xtaci/smux) v1 and v2, which kcptun uses
+//!
+//! ```plain
+//! +---------+-----+--------+-----------+
+//! | VERSION | CMD | LENGTH | STREAM ID |
+//! +---------+-----+--------+-----------+
+//! |    1    |  1  | 2 (LE) |   4 (LE)  |
+//! +---------+-----+--------+-----------+
+//! ```
+//!
+//! Version 2 adds per-stream flow control with `UPD` frames, carrying the bytes consumed and the window size of the
+//! receiver. Version 1 relies on the session-wide receive buffer, which stops reading the underlying connection
+//! while it is full.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use futures::Stream as FutureStream;
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, watch, Notify},
+    time,
+};
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const CMD_UPD: u8 = 4;
+
+const HEADER_SIZE: usize = 8;
+const UPD_SIZE: usize = 8;
+
+/// Window assumed before the peer sends its first `UPD`
+const INITIAL_PEER_WINDOW: u32 = 262144;
+
+/// Bytes of frames waiting for the underlying connection before writers are blocked
+const MAX_OUTBOUND_QUEUE: usize = 1024 * 1024;
+
+/// smux session configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Protocol version, 1 or 2
+    pub version: u8,
+    /// Interval of sending `NOP` to keep the session alive
+    pub keepalive_interval: Duration,
+    /// Session is closed if nothing was received in this duration
+    pub keepalive_timeout: Duration,
+    /// Maximum payload of one frame
+    pub max_frame_size: usize,
+    /// Maximum bytes buffered for all streams before reading the connection stops
+    pub max_receive_buffer: usize,
+    /// Window of each stream (version 2)
+    pub max_stream_buffer: u32,
+    /// Maximum streams waiting to be accepted
+    pub accept_backlog: usize,
+}
+
+impl Config {
+    pub fn new(version: u8) -> Config {
+        Config {
+            version,
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_timeout: Duration::from_secs(30),
+            max_frame_size: 32768,
+            max_receive_buffer: 4 * 1024 * 1024,
+            max_stream_buffer: 65536,
+            accept_backlog: 1024,
+        }
+    }
+}
+
+fn encode_frame(version: u8, cmd: u8, sid: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+    frame.push(version);
+    frame.push(cmd);
+    frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    frame.extend_from_slice(&sid.to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+struct StreamState {
+    recv_buf: VecDeque<u8>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    fin_received: bool,
+    fin_sent: bool,
+    // Version 2 flow control
+    num_read: u32,
+    incr: u32,
+    num_written: u32,
+    peer_consumed: u32,
+    peer_window: u32,
+}
+
+struct StreamShared {
+    id: u32,
+    state: Mutex<StreamState>,
+}
+
+impl StreamShared {
+    fn new(id: u32) -> StreamShared {
+        StreamShared {
+            id,
+            state: Mutex::new(StreamState {
+                recv_buf: VecDeque::new(),
+                read_waker: None,
+                write_waker: None,
+                fin_received: false,
+                fin_sent: false,
+                num_read: 0,
+                incr: 0,
+                num_written: 0,
+                peer_consumed: 0,
+                peer_window: INITIAL_PEER_WINDOW,
+            }),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Outbound {
+    frames: VecDeque<Vec<u8>>,
+    queued: usize,
+    blocked: Vec<Waker>,
+}
+
+struct Shared {
+    config: Config,
+    streams: Mutex<HashMap<u32, Arc<StreamShared>>>,
+    next_id: Mutex<u32>,
+    outbound: Mutex<Outbound>,
+    writer_notify: Notify,
+    recv_buffered: AtomicUsize,
+    recv_space: Notify,
+    closed: AtomicBool,
+    error: Mutex<Option<io::Error>>,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl Shared {
+    fn push_frame(&self, cmd: u8, sid: u32, data: &[u8]) {
+        let frame = encode_frame(self.config.version, cmd, sid, data);
+        {
+            let mut outbound = self.outbound.lock().unwrap();
+            outbound.queued += frame.len();
+            outbound.frames.push_back(frame);
+        }
+        self.writer_notify.notify_one();
+    }
+
+    fn get_stream(&self, sid: u32) -> Option<Arc<StreamShared>> {
+        self.streams.lock().unwrap().get(&sid).cloned()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let _ = self.shutdown_tx.send(true);
+
+        for waker in self.outbound.lock().unwrap().blocked.drain(..) {
+            waker.wake();
+        }
+
+        let streams: Vec<Arc<StreamShared>> = self.streams.lock().unwrap().values().cloned().collect();
+        for stream in streams {
+            let mut state = stream.state.lock().unwrap();
+            if let Some(waker) = state.read_waker.take() {
+                waker.wake();
+            }
+            if let Some(waker) = state.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A multiplexed stream
+pub struct Stream {
+    shared: Arc<Shared>,
+    stream: Arc<StreamShared>,
+}
+
+impl Stream {
+    fn new(shared: Arc<Shared>, stream: Arc<StreamShared>) -> Stream {
+        Stream { shared, stream }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.stream.id
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stream").field("id", &self.stream.id).finish()
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let mut state = this.stream.state.lock().unwrap();
+        if state.recv_buf.is_empty() {
+            if state.fin_received {
+                return Ok(()).into();
+            }
+            if this.shared.is_closed() {
+                return Err(io::Error::new(ErrorKind::ConnectionReset, "smux session closed")).into();
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.remaining().min(state.recv_buf.len());
+        {
+            let (first, second) = state.recv_buf.as_slices();
+            let n1 = n.min(first.len());
+            buf.put_slice(&first[..n1]);
+            buf.put_slice(&second[..n - n1]);
+        }
+        state.recv_buf.drain(..n);
+
+        this.shared.recv_buffered.fetch_sub(n, Ordering::AcqRel);
+        this.shared.recv_space.notify_one();
+
+        let mut consumed = None;
+        if this.shared.config.version >= 2 {
+            state.num_read = state.num_read.wrapping_add(n as u32);
+            state.incr = state.incr.wrapping_add(n as u32);
+            // Notify the window on the first read, and whenever half of it was consumed
+            if state.incr >= this.shared.config.max_stream_buffer / 2 || state.num_read == n as u32 {
+                consumed = Some(state.num_read);
+                state.incr = 0;
+            }
+        }
+        drop(state);
+
+        if let Some(consumed) = consumed {
+            let mut data = [0u8; UPD_SIZE];
+            data[..4].copy_from_slice(&consumed.to_le_bytes());
+            data[4..].copy_from_slice(&this.shared.config.max_stream_buffer.to_le_bytes());
+            this.shared.push_frame(CMD_UPD, this.stream.id, &data);
+        }
+
+        Ok(()).into()
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        if this.shared.is_closed() {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "smux session closed")).into();
+        }
+
+        let mut state = this.stream.state.lock().unwrap();
+        if state.fin_sent {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "smux stream closed")).into();
+        }
+
+        let mut n = buf.len().min(this.shared.config.max_frame_size).min(u16::MAX as usize);
+        if this.shared.config.version >= 2 {
+            let inflight = state.num_written.wrapping_sub(state.peer_consumed);
+            let window = state.peer_window.saturating_sub(inflight) as usize;
+            if window == 0 {
+                state.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            n = n.min(window);
+        }
+
+        {
+            let mut outbound = this.shared.outbound.lock().unwrap();
+            if outbound.queued >= MAX_OUTBOUND_QUEUE {
+                outbound.blocked.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let frame = encode_frame(this.shared.config.version, CMD_PSH, this.stream.id, &buf[..n]);
+            outbound.queued += frame.len();
+            outbound.frames.push_back(frame);
+        }
+
+        state.num_written = state.num_written.wrapping_add(n as u32);
+        drop(state);
+
+        this.shared.writer_notify.notify_one();
+        Ok(n).into()
+    }
+
+    /// Frames are queued for the session writer, which flushes the underlying connection whenever its queue drains
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Ok(()).into()
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let mut state = this.stream.state.lock().unwrap();
+        if !state.fin_sent {
+            state.fin_sent = true;
+            drop(state);
+            this.shared.push_frame(CMD_FIN, this.stream.id, &[]);
+        }
+
+        Ok(()).into()
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let (fin_sent, buffered) = {
+            let mut state = self.stream.state.lock().unwrap();
+            let fin_sent = state.fin_sent;
+            state.fin_sent = true;
+            let buffered = state.recv_buf.len();
+            state.recv_buf.clear();
+            (fin_sent, buffered)
+        };
+
+        self.shared.streams.lock().unwrap().remove(&self.stream.id);
+
+        if buffered > 0 {
+            self.shared.recv_buffered.fetch_sub(buffered, Ordering::AcqRel);
+            self.shared.recv_space.notify_one();
+        }
+
+        if !fin_sent && !self.shared.is_closed() {
+            self.shared.push_frame(CMD_FIN, self.stream.id, &[]);
+        }
+    }
+}
+
+/// Handle for opening streams in a session
+#[derive(Clone)]
+pub struct Control {
+    shared: Arc<Shared>,
+}
+
+impl Control {
+    /// Open a new stream
+    pub async fn open_stream(&mut self) -> io::Result<Stream> {
+        if self.shared.is_closed() {
+            return Err(io::Error::new(ErrorKind::NotConnected, "smux session closed"));
+        }
+
+        let sid = {
+            let mut next_id = self.shared.next_id.lock().unwrap();
+            match next_id.checked_add(2) {
+                Some(id) => {
+                    *next_id = id;
+                    id
+                }
+                None => return Err(io::Error::other("smux stream id exhausted")),
+            }
+        };
+
+        let stream = Arc::new(StreamShared::new(sid));
+        self.shared.streams.lock().unwrap().insert(sid, stream.clone());
+        self.shared.push_frame(CMD_SYN, sid, &[]);
+
+        trace!("smux opened stream {}", sid);
+
+        Ok(Stream::new(self.shared.clone(), stream))
+    }
+
+    /// Close the session
+    pub async fn close(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// smux session, yields streams opened by the remote
+///
+/// The session is driven by background tasks, and it is closed when this is dropped.
+pub struct Session {
+    shared: Arc<Shared>,
+    accept_rx: mpsc::Receiver<Stream>,
+}
+
+impl Session {
+    pub fn new_client<T>(io: T, config: Config) -> Session
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Session::new(io, config, true)
+    }
+
+    pub fn new_server<T>(io: T, config: Config) -> Session
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Session::new(io, config, false)
+    }
+
+    fn new<T>(io: T, config: Config, client: bool) -> Session
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (accept_tx, accept_rx) = mpsc::channel(config.accept_backlog);
+
+        let shared = Arc::new(Shared {
+            config,
+            streams: Mutex::new(HashMap::new()),
+            // Client opens odd stream IDs, server opens even
+            next_id: Mutex::new(if client { 1 } else { 0 }),
+            outbound: Mutex::new(Outbound::default()),
+            writer_notify: Notify::new(),
+            recv_buffered: AtomicUsize::new(0),
+            recv_space: Notify::new(),
+            closed: AtomicBool::new(false),
+            error: Mutex::new(None),
+            shutdown_tx,
+        });
+
+        let (reader, writer) = tokio::io::split(io);
+
+        {
+            let shared = shared.clone();
+            let shutdown_rx = shutdown_rx.clone();
+            tokio::spawn(async move {
+                if let Err(err) = read_loop(&shared, reader, &accept_tx, shutdown_rx).await {
+                    debug!("smux session read error: {}", err);
+                    *shared.error.lock().unwrap() = Some(err);
+                }
+                // Session yields the error once streams can no longer be accepted
+                drop(accept_tx);
+                shared.close();
+            });
+        }
+
+        {
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                if let Err(err) = write_loop(&shared, writer, shutdown_rx).await {
+                    debug!("smux session write error: {}", err);
+                }
+                shared.close();
+            });
+        }
+
+        Session { shared, accept_rx }
+    }
+
+    pub fn control(&self) -> Control {
+        Control {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl FutureStream for Session {
+    type Item = io::Result<Stream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.accept_rx.poll_recv(cx) {
+            Poll::Ready(Some(stream)) => Poll::Ready(Some(Ok(stream))),
+            Poll::Ready(None) => Poll::Ready(this.shared.error.lock().unwrap().take().map(Err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+async fn read_loop<R>(
+    shared: &Arc<Shared>,
+    mut reader: R,
+    accept_tx: &mpsc::Sender<Stream>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_SIZE];
+    let mut payload = vec![0u8; u16::MAX as usize];
+
+    loop {
+        // Stop reading the connection while receive buffers are full
+        while shared.recv_buffered.load(Ordering::Acquire) >= shared.config.max_receive_buffer {
+            tokio::select! {
+                _ = shared.recv_space.notified() => {}
+                _ = shutdown_rx.changed() => return Ok(()),
+            }
+        }
+
+        let read_frame = async {
+            reader.read_exact(&mut header).await?;
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            reader.read_exact(&mut payload[..len]).await?;
+            Ok::<_, io::Error>(len)
+        };
+
+        let len = tokio::select! {
+            r = time::timeout(shared.config.keepalive_timeout, read_frame) => {
+                match r {
+                    Ok(Ok(len)) => len,
+                    Ok(Err(ref err)) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    Ok(Err(err)) => return Err(err),
+                    Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "smux keepalive timeout")),
+                }
+            }
+            _ = shutdown_rx.changed() => return Ok(()),
+        };
+
+        let version = header[0];
+        let cmd = header[1];
+        let sid = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let data = &payload[..len];
+
+        if version != shared.config.version {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "smux version mismatch, expecting {} but received {}",
+                    shared.config.version, version
+                ),
+            ));
+        }
+
+        match cmd {
+            CMD_NOP => {}
+            CMD_SYN => {
+                let stream = {
+                    let mut streams = shared.streams.lock().unwrap();
+                    if streams.contains_key(&sid) {
+                        continue;
+                    }
+                    let stream = Arc::new(StreamShared::new(sid));
+                    streams.insert(sid, stream.clone());
+                    stream
+                };
+
+                trace!("smux accepted stream {}", sid);
+
+                // A stream that cannot be accepted is dropped, which sends FIN back
+                let _ = accept_tx.try_send(Stream::new(shared.clone(), stream));
+            }
+            CMD_FIN => {
+                if let Some(stream) = shared.get_stream(sid) {
+                    let mut state = stream.state.lock().unwrap();
+                    state.fin_received = true;
+                    if let Some(waker) = state.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            CMD_PSH => {
+                if len == 0 {
+                    continue;
+                }
+                if let Some(stream) = shared.get_stream(sid) {
+                    let mut state = stream.state.lock().unwrap();
+                    state.recv_buf.extend(data);
+                    shared.recv_buffered.fetch_add(len, Ordering::AcqRel);
+                    if let Some(waker) = state.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            CMD_UPD if version >= 2 => {
+                if len != UPD_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "smux invalid UPD frame"));
+                }
+                if let Some(stream) = shared.get_stream(sid) {
+                    let mut state = stream.state.lock().unwrap();
+                    state.peer_consumed = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    state.peer_window = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+                    if let Some(waker) = state.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("smux invalid command {}", cmd),
+                ));
+            }
+        }
+    }
+}
+
+async fn write_loop<W>(shared: &Arc<Shared>, mut writer: W, mut shutdown_rx: watch::Receiver<bool>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut keepalive = time::interval(shared.config.keepalive_interval);
+    keepalive.tick().await;
+
+    loop {
+        let frame = shared.outbound.lock().unwrap().frames.pop_front();
+        match frame {
+            Some(frame) => {
+                writer.write_all(&frame).await?;
+
+                let mut outbound = shared.outbound.lock().unwrap();
+                outbound.queued -= frame.len();
+                if outbound.queued < MAX_OUTBOUND_QUEUE {
+                    for waker in outbound.blocked.drain(..) {
+                        waker.wake();
+                    }
+                }
+            }
+            None => {
+                writer.flush().await?;
+
+                tokio::select! {
+                    _ = shared.writer_notify.notified() => {}
+                    _ = keepalive.tick() => {
+                        shared.push_frame(CMD_NOP, 0, &[]);
+                    }
+                    _ = shutdown_rx.changed() => break,
+                }
+            }
+        }
+    }
+
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    // Frames as kcptun writes them, header fields in little endian
+    const V1_SYN_3: &[u8] = &[0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00];
+    const V1_PSH_3_HELLO: &[u8] = &[
+        0x01, 0x02, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o',
+    ];
+    const V1_NOP: &[u8] = &[0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    const V1_FIN_3: &[u8] = &[0x01, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00];
+    const V2_SYN_3: &[u8] = &[0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00];
+    const V2_PSH_3_HELLO: &[u8] = &[
+        0x02, 0x02, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o',
+    ];
+    /// Consumed 0, window 0
+    const V2_UPD_3_CLOSED: &[u8] = &[
+        0x02, 0x04, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    /// Consumed 0, window 262144
+    const V2_UPD_3_OPEN: &[u8] = &[
+        0x02, 0x04, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00,
+    ];
+
+    fn server(version: u8) -> (Session, DuplexStream) {
+        let (a, b) = duplex(1024 * 1024);
+        (Session::new_server(a, Config::new(version)), b)
+    }
+
+    /// Next frame written by the session, skipping `NOP`
+    async fn read_frame(peer: &mut DuplexStream) -> Vec<u8> {
+        loop {
+            let mut frame = vec![0u8; HEADER_SIZE];
+            peer.read_exact(&mut frame).await.unwrap();
+            let len = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+            frame.resize(HEADER_SIZE + len, 0);
+            peer.read_exact(&mut frame[HEADER_SIZE..]).await.unwrap();
+            if frame[1] != CMD_NOP {
+                return frame;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_frames() {
+        let (mut session, mut peer) = server(1);
+
+        peer.write_all(V1_SYN_3).await.unwrap();
+        peer.write_all(V1_NOP).await.unwrap();
+        peer.write_all(V1_PSH_3_HELLO).await.unwrap();
+        let mut stream = session.next().await.unwrap().unwrap();
+        assert_eq!(stream.id(), 3);
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        stream.write_all(b"hello").await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, V1_PSH_3_HELLO);
+
+        peer.write_all(V1_FIN_3).await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        stream.shutdown().await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, V1_FIN_3);
+    }
+
+    #[tokio::test]
+    async fn v1_open() {
+        let (a, mut peer) = duplex(1024 * 1024);
+        let session = Session::new_client(a, Config::new(1));
+
+        let mut stream = session.control().open_stream().await.unwrap();
+        assert_eq!(stream.id(), 3);
+        assert_eq!(read_frame(&mut peer).await, V1_SYN_3);
+
+        stream.write_all(b"hello").await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, V1_PSH_3_HELLO);
+    }
+
+    #[tokio::test]
+    async fn v2_frames() {
+        let (mut session, mut peer) = server(2);
+
+        peer.write_all(V2_SYN_3).await.unwrap();
+        peer.write_all(V2_PSH_3_HELLO).await.unwrap();
+        let mut stream = session.next().await.unwrap().unwrap();
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Window is announced on the first read: consumed 5, window 65536
+        assert_eq!(
+            read_frame(&mut peer).await,
+            [0x02, 0x04, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]
+        );
+
+        stream.write_all(b"hello").await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, V2_PSH_3_HELLO);
+    }
+
+    #[tokio::test]
+    async fn v2_window() {
+        let (mut session, mut peer) = server(2);
+
+        peer.write_all(V2_SYN_3).await.unwrap();
+        peer.write_all(V2_UPD_3_CLOSED).await.unwrap();
+        let mut stream = session.next().await.unwrap().unwrap();
+        // Let the UPD be read
+        time::sleep(Duration::from_millis(50)).await;
+
+        // Nothing is sent while the peer's window is closed
+        let write = time::timeout(Duration::from_millis(100), stream.write_all(b"hello")).await;
+        assert!(write.is_err());
+
+        peer.write_all(V2_UPD_3_OPEN).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, V2_PSH_3_HELLO);
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        let (mut session, mut peer) = server(1);
+
+        peer.write_all(V2_SYN_3).await.unwrap();
+        let err = session.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn invalid_upd() {
+        let (mut session, mut peer) = server(2);
+
+        // UPD with a 4 byte payload
+        peer.write_all(V2_SYN_3).await.unwrap();
+        peer.write_all(&[0x02, 0x04, 0x04, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        let _stream = session.next().await.unwrap().unwrap();
+        let err = session.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn oversized_write() {
+        let (mut session, mut peer) = server(1);
+
+        peer.write_all(V1_SYN_3).await.unwrap();
+        let mut stream = session.next().await.unwrap().unwrap();
+
+        // Writes beyond the frame size are split, the length field never wraps
+        let data = vec![7u8; 100_000];
+        stream.write_all(&data).await.unwrap();
+
+        let mut received = 0;
+        while received < data.len() {
+            let frame = read_frame(&mut peer).await;
+            assert_eq!(frame[1], CMD_PSH);
+            let len = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+            assert!(len <= Config::new(1).max_frame_size);
+            assert_eq!(frame.len(), HEADER_SIZE + len);
+            received += len;
+        }
+        assert_eq!(received, data.len());
+    }
+
+    #[tokio::test]
+    async fn oversized_frame() {
+        let (mut session, mut peer) = server(1);
+
+        // A frame claiming more payload than the peer ever sends resets its streams
+        peer.write_all(V1_SYN_3).await.unwrap();
+        let mut stream = session.next().await.unwrap().unwrap();
+        peer.write_all(&[0x01, 0x02, 0xff, 0xff, 0x03, 0x00, 0x00, 0x00, b'h', b'i'])
+            .await
+            .unwrap();
+        drop(peer);
+
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    // Byte streams of kcptun sessions, each direction in the order xtaci/smux writes it: `rawHeader` of frame.go with
+    // the payload, client stream IDs 3, 5, ..., `NOP` on stream 0, and `UPD` of version 2 sent on the first read of a
+    // stream with a stream buffer of 65536
+    const KCPTUN_V1_CLIENT: &[u8] = &[
+        0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // SYN 3
+        0x01, 0x02, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', // PSH 3 hello
+        0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // SYN 5
+        0x01, 0x02, 0x03, 0x00, 0x05, 0x00, 0x00, 0x00, b'f', b'o', b'o', // PSH 5 foo
+        0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // NOP
+        0x01, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // FIN 3
+        0x01, 0x01, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // FIN 5
+    ];
+    const KCPTUN_V1_SERVER: &[u8] = &[
+        0x01, 0x02, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, b'w', b'o', b'r', b'l', b'd', // PSH 3 world
+        0x01, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // FIN 3
+        0x01, 0x01, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // FIN 5
+    ];
+    const KCPTUN_V2_CLIENT: &[u8] = &[
+        0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // SYN 3
+        0x02, 0x02, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', // PSH 3 hello
+        0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // NOP
+        0x02, 0x04, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, // UPD 3
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, // consumed 5, window 65536
+        0x02, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // FIN 3
+    ];
+    const KCPTUN_V2_SERVER: &[u8] = &[
+        0x02, 0x04, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, // UPD 3
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, // consumed 5, window 65536
+        0x02, 0x02, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, b'w', b'o', b'r', b'l', b'd', // PSH 3 world
+        0x02, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // FIN 3
+    ];
+
+    /// Frames of a byte stream, without `NOP`
+    fn split_frames(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            let len = HEADER_SIZE + u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+            if bytes[1] != CMD_NOP {
+                frames.push(bytes[..len].to_vec());
+            }
+            bytes = &bytes[len..];
+        }
+        frames
+    }
+
+    /// Next `n` frames written by the session, skipping `NOP`
+    async fn read_frames(peer: &mut DuplexStream, n: usize) -> Vec<Vec<u8>> {
+        let mut frames = Vec::with_capacity(n);
+        for _ in 0..n {
+            frames.push(read_frame(peer).await);
+        }
+        frames
+    }
+
+    #[test]
+    fn kcptun_nop() {
+        assert_eq!(encode_frame(1, CMD_NOP, 0, &[]), &KCPTUN_V1_CLIENT[40..48]);
+        assert_eq!(encode_frame(2, CMD_NOP, 0, &[]), &KCPTUN_V2_CLIENT[21..29]);
+    }
+
+    #[tokio::test]
+    async fn kcptun_v1_server() {
+        let (mut session, mut peer) = server(1);
+        peer.write_all(KCPTUN_V1_CLIENT).await.unwrap();
+
+        let mut first = session.next().await.unwrap().unwrap();
+        let mut second = session.next().await.unwrap().unwrap();
+        assert_eq!((first.id(), second.id()), (3, 5));
+
+        let mut buf = Vec::new();
+        first.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        buf.clear();
+        second.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"foo");
+
+        first.write_all(b"world").await.unwrap();
+        first.shutdown().await.unwrap();
+        second.shutdown().await.unwrap();
+
+        let expected = split_frames(KCPTUN_V1_SERVER);
+        assert_eq!(read_frames(&mut peer, expected.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn kcptun_v1_client() {
+        let (a, mut peer) = duplex(1024 * 1024);
+        let session = Session::new_client(a, Config::new(1));
+        let mut control = session.control();
+
+        let mut first = control.open_stream().await.unwrap();
+        first.write_all(b"hello").await.unwrap();
+        let mut second = control.open_stream().await.unwrap();
+        second.write_all(b"foo").await.unwrap();
+        first.shutdown().await.unwrap();
+        second.shutdown().await.unwrap();
+
+        let expected = split_frames(KCPTUN_V1_CLIENT);
+        assert_eq!(read_frames(&mut peer, expected.len()).await, expected);
+
+        peer.write_all(KCPTUN_V1_SERVER).await.unwrap();
+        let mut buf = Vec::new();
+        first.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"world");
+        assert_eq!(second.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn kcptun_v2_server() {
+        let (mut session, mut peer) = server(2);
+        let expected = split_frames(KCPTUN_V2_SERVER);
+
+        // SYN and PSH, the rest comes after the server has answered
+        peer.write_all(&KCPTUN_V2_CLIENT[..21]).await.unwrap();
+        let mut stream = session.next().await.unwrap().unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(read_frame(&mut peer).await, expected[0]);
+
+        stream.write_all(b"world").await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, expected[1]);
+
+        peer.write_all(&KCPTUN_V2_CLIENT[21..]).await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        stream.shutdown().await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, expected[2]);
+    }
+
+    #[tokio::test]
+    async fn kcptun_v2_client() {
+        let (a, mut peer) = duplex(1024 * 1024);
+        let session = Session::new_client(a, Config::new(2));
+        let expected = split_frames(KCPTUN_V2_CLIENT);
+
+        let mut stream = session.control().open_stream().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        assert_eq!(read_frames(&mut peer, 2).await, expected[..2]);
+
+        // UPD and PSH of the server, reading the PSH announces the window
+        peer.write_all(&KCPTUN_V2_SERVER[..29]).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(read_frame(&mut peer).await, expected[2]);
+
+        stream.shutdown().await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, expected[3]);
+        peer.write_all(&KCPTUN_V2_SERVER[29..]).await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -1,4 +1,9 @@
-use std::{io, mem, os::unix::io::AsRawFd};
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::unix::io::AsRawFd,
+};
 
 use log::error;
 
@@ -47,3 +52,77 @@ pub fn set_bindtodevice<S: AsRawFd>(socket: &S, iface: &str) -> io::Result<()> {
 
     Ok(())
 }
+
+/// Set `IP_TRANSPARENT` (or `IPV6_TRANSPARENT`) for accepting TPROXY connections
+///
+/// NOTE: This will require CAP_NET_ADMIN capability (root in most cases)
+pub fn set_ip_transparent<S: AsRawFd>(socket: &S, addr: SocketAddr) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let (level, name) = match addr {
+        SocketAddr::V4(..) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(..) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const _ as *const _,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        error!("setsockopt IP_TRANSPARENT failed, error: {}", err);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Get the destination of a connection before it was changed by iptables `REDIRECT`, with `SO_ORIGINAL_DST`
+pub fn get_original_destination<S: AsRawFd>(socket: &S, local_addr: SocketAddr) -> io::Result<SocketAddr> {
+    let (level, name) = match local_addr {
+        SocketAddr::V4(..) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(..) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+
+        let ret = libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut storage as *mut _ as *mut _,
+            &mut len,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = &*(&storage as *const _ as *const libc::sockaddr_in);
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+            }
+            libc::AF_INET6 => {
+                let addr = &*(&storage as *const _ as *const libc::sockaddr_in6);
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("SO_ORIGINAL_DST returned address family {}", family),
+            )),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! KCP transport, the default
+//!
+//! Sessions are KCP sessions over UDP, sent through UDP relays if port hopping, multipath, roaming, datagram layers,
+//! statistics, the firewall or session limits of server are enabled.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use futures::future::BoxFuture;
+use log::info;
+use tokio::net::lookup_host;
+use tokio_kcp::KcpListener;
+
+use super::{AcceptedSession, Transport, TransportListener};
+use crate::{
+    config::{network_required, Config, ServerAddr},
+    opt::create_outbound_kcp,
+    session::BoxedSessionStream,
+    udp::{
+        client::{connect_relayed_kcp, RelayConfig},
+        server::UdpServerRelay,
+        stats::KcpStats,
+        HeaderConfig,
+    },
+};
+
+/// KCP sessions over UDP
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KcpTransport;
+
+impl Transport for KcpTransport {
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<BoxedSessionStream>> {
+        Box::pin(connect_kcp(config))
+    }
+
+    fn listen<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+        Box::pin(async move {
+            let (listener, relay) = bind_kcp_listener(config).await?;
+            Ok(Box::new(KcpTransportListener { listener, relay }) as Box<dyn TransportListener>)
+        })
+    }
+
+    fn connect_with_stats<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> BoxFuture<'a, io::Result<(BoxedSessionStream, Option<Arc<KcpStats>>)>> {
+        Box::pin(connect_kcp_with_stats(config))
+    }
+}
+
+struct KcpTransportListener {
+    listener: KcpListener,
+    relay: Option<Arc<UdpServerRelay>>,
+}
+
+impl TransportListener for KcpTransportListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedSessionStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer_addr, ..) = self.accept_with_stats().await?;
+            Ok((stream, peer_addr))
+        })
+    }
+
+    fn accept_with_stats(&mut self) -> BoxFuture<'_, io::Result<AcceptedSession>> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.listener.accept().await?;
+
+            // Sessions from the relay are accepted from its loopback sockets
+            let (peer_addr, stats, permit) = match self.relay {
+                Some(ref relay) => (
+                    relay.peer_addr(&peer_addr).unwrap_or(peer_addr),
+                    relay.kcp_stats(&peer_addr),
+                    relay.take_session_permit(&peer_addr),
+                ),
+                None => (peer_addr, None, None),
+            };
+
+            Ok((Box::new(stream) as BoxedSessionStream, peer_addr, stats, permit))
+        })
+    }
+}
+
+async fn connect_server_addr(
+    config: &Config,
+    addr: SocketAddr,
+) -> io::Result<(BoxedSessionStream, Option<Arc<KcpStats>>)> {
+    let kcp_config = &config.kcp_config;
+    let opts = &config.plugin_opts;
+
+    match RelayConfig::from_plugin_opts(opts, &config.datagram_layers) {
+        Some(relay_config) => {
+            let stream = connect_relayed_kcp(kcp_config, addr, relay_config, opts).await?;
+            let stats = stream.kcp_stats().cloned();
+            Ok((Box::new(stream), stats))
+        }
+        None => {
+            let stream = create_outbound_kcp(kcp_config, addr, opts).await?;
+            Ok((Box::new(stream), None))
+        }
+    }
+}
+
+/// Connect a KCP session to `SS_REMOTE_*`
+pub async fn connect_kcp(config: &Config) -> io::Result<BoxedSessionStream> {
+    connect_kcp_with_stats(config).await.map(|(stream, _)| stream)
+}
+
+/// Connect a KCP session to `SS_REMOTE_*`, with its statistics if `kcp_stats` is set
+pub async fn connect_kcp_with_stats(config: &Config) -> io::Result<(BoxedSessionStream, Option<Arc<KcpStats>>)> {
+    match config.remote_addr {
+        ServerAddr::SocketAddr(sa) => connect_server_addr(config, sa).await,
+        ServerAddr::DomainName(ref dname, port) => {
+            let mut result = None;
+
+            for addr in lookup_host((dname.as_str(), port)).await? {
+                match connect_server_addr(config, addr).await {
+                    Ok(s) => {
+                        result = Some(Ok(s));
+                        break;
+                    }
+                    Err(err) => {
+                        result = Some(Err(err));
+                    }
+                }
+            }
+
+            match result {
+                None => Err(io::Error::new(ErrorKind::Other, "lookup_host resolved to empty")),
+                Some(Ok(s)) => Ok(s),
+                Some(Err(err)) => Err(err.into()),
+            }
+        }
+        ServerAddr::UnixSocket(ref path) => Err(network_required(path)),
+    }
+}
+
+/// Addresses that `config` listens on, `SS_REMOTE_*` and the ports in `server_port_range`
+pub async fn listen_addrs(config: &Config) -> io::Result<Vec<SocketAddr>> {
+    let ip = match config.remote_addr {
+        ServerAddr::SocketAddr(sa) => sa.ip(),
+        ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await?.next() {
+            Some(sa) => sa.ip(),
+            None => return Err(io::Error::other("lookup_host resolved to empty")),
+        },
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    };
+
+    let mut addrs = vec![SocketAddr::new(ip, config.remote_addr.port())];
+    if let Some(ports) = config.plugin_opts.server_port_range {
+        for port in ports.iter() {
+            if port != config.remote_addr.port() {
+                addrs.push(SocketAddr::new(ip, port));
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+/// Listen for KCP sessions on `SS_REMOTE_*`, through a UDP relay if port hopping, multipath, roaming, datagram layers,
+/// statistics, the firewall or session limits are enabled
+pub async fn bind_kcp_listener(config: &Config) -> io::Result<(KcpListener, Option<Arc<UdpServerRelay>>)> {
+    let opts = &config.plugin_opts;
+
+    let header = HeaderConfig::for_server(opts);
+    let kcp_stats = opts.kcp_stats.unwrap_or(false);
+    if opts.server_port_range.is_none()
+        && header.is_none()
+        && config.datagram_layers.is_empty()
+        && !kcp_stats
+        && config.firewall.is_none()
+        && !config.limits.as_ref().is_some_and(|l| l.limits_sessions())
+    {
+        let listener = match config.remote_addr {
+            ServerAddr::SocketAddr(sa) => KcpListener::bind(config.kcp_config, sa).await?,
+            ServerAddr::DomainName(ref dname, port) => {
+                KcpListener::bind(config.kcp_config, (dname.as_str(), port)).await?
+            }
+            ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+        };
+
+        info!("KCP server listening on {}", listener.local_addr().unwrap());
+
+        return Ok((listener, None));
+    }
+
+    // KCP sessions are relayed from all ports in the range, to the listener on loopback
+    let listener = KcpListener::bind(config.kcp_config, "127.0.0.1:0").await?;
+    let target = listener.local_addr()?;
+
+    let addrs = listen_addrs(config).await?;
+
+    let accepts_roaming = header.is_some();
+    let relay = UdpServerRelay::bind(&addrs, target, config).await?;
+
+    match opts.server_port_range {
+        Some(ports) => info!(
+            "KCP server listening on {} and ports {}, relaying to {}",
+            addrs[0], ports, target
+        ),
+        None => info!("KCP server listening on {}, relaying to {}", addrs[0], target),
+    }
+    if accepts_roaming {
+        info!("KCP server accepts multipath and roaming clients");
+    }
+
+    Ok((listener, Some(relay)))
+}
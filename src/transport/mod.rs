@@ -0,0 +1,577 @@
+//! Transports that carry sessions between local and server
+//!
+//! KCP is the default. Where UDP is blocked, sessions can be carried in plain TCP, or in a WebSocket over TCP, which
+//! may be wrapped in TLS to pass through HTTPS proxies and CDNs. Server accepts both on `tcp_port`, and tells them
+//! apart by the WebSocket handshake.
+//!
+//! QUIC connections replace both KCP sessions and their multiplexer, see [`quic`].
+//!
+//! Library users may carry sessions in a [`Transport`] of their own, set in [`Config::transport`].
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use log::{debug, error, info};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::{
+    config::{network_required, Config, ServerAddr},
+    limit::SessionPermit,
+    opt::{create_outbound_tcp, PluginOpts},
+    session::BoxedSessionStream,
+    udp::stats::KcpStats,
+};
+
+pub mod kcp;
+pub mod quic;
+pub mod ws;
+
+use self::kcp::KcpTransport;
+
+/// Timeout for receiving the first bytes of a TCP connection
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of peeking again at the first bytes of a TCP session, until all of them arrive
+const TCP_PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A session accepted by a [`TransportListener`], with the address of its client, statistics of its KCP session, and
+/// its admission if the transport has admitted it already
+pub type AcceptedSession = (
+    BoxedSessionStream,
+    SocketAddr,
+    Option<Arc<KcpStats>>,
+    Option<SessionPermit>,
+);
+
+/// Carries sessions between local and server
+///
+/// Sessions are byte streams, which the preamble, the multiplexer and everything above run on.
+pub trait Transport: Send + Sync + fmt::Debug {
+    /// Connect a session to the server of `config`
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<BoxedSessionStream>>;
+
+    /// Listen for sessions of clients, as the server of `config`
+    fn listen<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>>;
+
+    /// Like `connect`, with statistics of the KCP session if the transport tracks them
+    fn connect_with_stats<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> BoxFuture<'a, io::Result<(BoxedSessionStream, Option<Arc<KcpStats>>)>> {
+        Box::pin(async move { self.connect(config).await.map(|stream| (stream, None)) })
+    }
+}
+
+/// Sessions accepted by a [`Transport`]
+pub trait TransportListener: Send {
+    /// Accept a session, with the address of its client
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedSessionStream, SocketAddr)>>;
+
+    /// Like `accept`, with statistics of the KCP session if the transport tracks them
+    fn accept_with_stats(&mut self) -> BoxFuture<'_, io::Result<AcceptedSession>> {
+        Box::pin(async move { self.accept().await.map(|(stream, addr)| (stream, addr, None, None)) })
+    }
+}
+
+/// Transport of `config`, KCP unless the library user has set one
+pub fn transport_of(config: &Config) -> &dyn Transport {
+    match config.transport {
+        Some(ref transport) => &**transport,
+        None => &KcpTransport,
+    }
+}
+
+/// Transport of sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportKind {
+    /// KCP over UDP
+    #[default]
+    Kcp,
+    /// Plain TCP
+    Tcp,
+    /// WebSocket over TCP, or over TLS with `tls`
+    Ws,
+    /// QUIC, with native streams instead of a multiplexer
+    Quic,
+}
+
+impl TransportKind {
+    /// Built-in transport of this kind, `None` for QUIC, which has no session streams
+    pub fn transport(self) -> Option<&'static dyn Transport> {
+        match self {
+            TransportKind::Kcp => Some(&KcpTransport),
+            TransportKind::Tcp => Some(&TcpTransport),
+            TransportKind::Ws => Some(&WsTransport),
+            TransportKind::Quic => None,
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TransportKind, String> {
+        match s.trim() {
+            "kcp" => Ok(TransportKind::Kcp),
+            "tcp" => Ok(TransportKind::Tcp),
+            "ws" => Ok(TransportKind::Ws),
+            "quic" => Ok(TransportKind::Quic),
+            s => Err(format!("unknown transport {:?}", s)),
+        }
+    }
+}
+
+impl Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransportKind::Kcp => f.write_str("kcp"),
+            TransportKind::Tcp => f.write_str("tcp"),
+            TransportKind::Ws => f.write_str("ws"),
+            TransportKind::Quic => f.write_str("quic"),
+        }
+    }
+}
+
+/// Transports of `transport` option, a `,` separated list that client tries in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportList(pub Vec<TransportKind>);
+
+impl Default for TransportList {
+    fn default() -> TransportList {
+        TransportList(vec![TransportKind::Kcp])
+    }
+}
+
+impl FromStr for TransportList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TransportList, String> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(TransportList)
+    }
+}
+
+impl Display for TransportList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, transport) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            transport.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TransportList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TransportList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TransportList, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+fn tcp_port(opts: &PluginOpts) -> io::Result<u16> {
+    opts.tcp_port
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "tcp and ws transports require tcp_port"))
+}
+
+/// Connect a TCP connection to the server's `tcp_port`
+async fn connect_tcp(config: &Config) -> io::Result<TcpStream> {
+    let port = tcp_port(&config.plugin_opts)?;
+    let stream = match config.remote_addr {
+        ServerAddr::SocketAddr(sa) => create_outbound_tcp((sa.ip(), port), &config.plugin_opts).await?,
+        ServerAddr::DomainName(ref dname, _) => {
+            create_outbound_tcp((dname.as_str(), port), &config.plugin_opts).await?
+        }
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    };
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Connect a session to the server over TCP
+pub async fn connect_tcp_session(config: &Config) -> io::Result<BoxedSessionStream> {
+    let stream = connect_tcp(config).await?;
+    Ok(Box::new(stream))
+}
+
+/// Connect a session to the server in a WebSocket, wrapped in TLS if `tls` is set
+pub async fn connect_ws_session(config: &Config) -> io::Result<BoxedSessionStream> {
+    let opts = &config.plugin_opts;
+    let stream = connect_tcp(config).await?;
+
+    let remote_host = config.remote_addr.host();
+    let host = opts.ws_host.as_deref().unwrap_or(&remote_host);
+    let path = opts.ws_path.as_deref().unwrap_or("/");
+
+    if !opts.tls.unwrap_or(false) {
+        let stream = ws::client_handshake(stream, host, path).await?;
+        return Ok(Box::new(stream));
+    }
+
+    let sni = opts.tls_sni.as_deref().unwrap_or(host);
+    let server_name = ServerName::try_from(sni.to_owned())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("invalid tls_sni {:?}", sni)))?;
+    let stream = tls_connector().connect(server_name, stream).await?;
+    let stream = ws::client_handshake(stream, host, path).await?;
+    Ok(Box::new(stream))
+}
+
+/// Listen for TCP and WebSocket sessions on `tcp_port`, on the address of `SS_REMOTE_HOST`
+async fn bind_tcp_listener(config: &Config) -> io::Result<TcpListener> {
+    let port = tcp_port(&config.plugin_opts)?;
+    match config.remote_addr {
+        ServerAddr::SocketAddr(sa) => TcpListener::bind((sa.ip(), port)).await,
+        ServerAddr::DomainName(ref dname, _) => TcpListener::bind((dname.as_str(), port)).await,
+        ServerAddr::UnixSocket(ref path) => Err(network_required(path)),
+    }
+}
+
+/// Accept a session on a TCP connection, which may start with a WebSocket handshake
+async fn accept_tcp_session(stream: TcpStream, opts: &PluginOpts) -> io::Result<BoxedSessionStream> {
+    stream.set_nodelay(true)?;
+
+    let mut first = [0u8; 4];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut first).await?;
+            if n == 0 || n == first.len() {
+                return Ok::<_, io::Error>(n);
+            }
+            time::sleep(TCP_PEEK_INTERVAL).await;
+        }
+    };
+    let n = match time::timeout(TCP_HANDSHAKE_TIMEOUT, peek).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "tcp session first bytes timeout")),
+    };
+
+    if &first[..n] != b"GET " {
+        return Ok(Box::new(stream));
+    }
+
+    match time::timeout(
+        TCP_HANDSHAKE_TIMEOUT,
+        ws::server_handshake(stream, opts.ws_path.as_deref()),
+    )
+    .await
+    {
+        Ok(r) => Ok(Box::new(r?)),
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "websocket handshake timeout")),
+    }
+}
+
+/// Sessions in plain TCP connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<BoxedSessionStream>> {
+        Box::pin(connect_tcp_session(config))
+    }
+
+    /// Accepts both TCP and WebSocket sessions
+    fn listen<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+        Box::pin(listen_tcp(config))
+    }
+}
+
+/// Sessions in WebSockets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WsTransport;
+
+impl Transport for WsTransport {
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<BoxedSessionStream>> {
+        Box::pin(connect_ws_session(config))
+    }
+
+    /// Accepts both TCP and WebSocket sessions
+    fn listen<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+        Box::pin(listen_tcp(config))
+    }
+}
+
+async fn listen_tcp(config: &Config) -> io::Result<Box<dyn TransportListener>> {
+    let listener = bind_tcp_listener(config).await?;
+    info!("TCP server listening on {}", listener.local_addr()?);
+
+    // Handshakes are completed in tasks of their own, so slow clients do not hold up others
+    let (tx, rx) = mpsc::channel(64);
+    let opts = config.plugin_opts.clone();
+    let firewall = config.firewall.clone();
+    let task = tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            // Closed before its WebSocket handshake gets any work
+            if firewall.as_ref().is_some_and(|f| !f.permits(peer_addr.ip())) {
+                debug!("refused tcp {}, not permitted by firewall", peer_addr);
+                continue;
+            }
+
+            debug!("accepted tcp {}", peer_addr);
+
+            let tx = tx.clone();
+            let opts = opts.clone();
+            tokio::spawn(async move {
+                match accept_tcp_session(stream, &opts).await {
+                    Ok(stream) => {
+                        let _ = tx.send((stream, peer_addr)).await;
+                    }
+                    Err(err) => error!("tcp session {} handshake error: {}", peer_addr, err),
+                }
+            });
+        }
+    });
+
+    Ok(Box::new(TcpTransportListener { rx, task }))
+}
+
+struct TcpTransportListener {
+    rx: mpsc::Receiver<(BoxedSessionStream, SocketAddr)>,
+    task: JoinHandle<()>,
+}
+
+impl TransportListener for TcpTransportListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedSessionStream, SocketAddr)>> {
+        Box::pin(async move {
+            self.rx
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::other("tcp listener stopped"))
+        })
+    }
+}
+
+impl Drop for TcpTransportListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{firewall::Firewall, local, server, udp::DatagramLayers};
+
+    /// Sessions in in-memory pipes, from local to the server of the same transport
+    #[derive(Debug)]
+    struct MemoryTransport {
+        tx: mpsc::UnboundedSender<DuplexStream>,
+        rx: Mutex<Option<mpsc::UnboundedReceiver<DuplexStream>>>,
+        connects: AtomicUsize,
+    }
+
+    impl MemoryTransport {
+        fn new() -> MemoryTransport {
+            let (tx, rx) = mpsc::unbounded_channel();
+            MemoryTransport {
+                tx,
+                rx: Mutex::new(Some(rx)),
+                connects: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn connect<'a>(&'a self, _config: &'a Config) -> BoxFuture<'a, io::Result<BoxedSessionStream>> {
+            Box::pin(async move {
+                let (client, server) = duplex(64 * 1024);
+                self.tx.send(server).map_err(|_| io::Error::other("server stopped"))?;
+                self.connects.fetch_add(1, Ordering::Relaxed);
+                Ok(Box::new(client) as BoxedSessionStream)
+            })
+        }
+
+        fn listen<'a>(&'a self, _config: &'a Config) -> BoxFuture<'a, io::Result<Box<dyn TransportListener>>> {
+            Box::pin(async move {
+                let rx = self
+                    .rx
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| io::Error::other("listening already"))?;
+                Ok(Box::new(MemoryListener(rx)) as Box<dyn TransportListener>)
+            })
+        }
+    }
+
+    struct MemoryListener(mpsc::UnboundedReceiver<DuplexStream>);
+
+    impl TransportListener for MemoryListener {
+        fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedSessionStream, SocketAddr)>> {
+            Box::pin(async move {
+                let stream = self
+                    .0
+                    .recv()
+                    .await
+                    .ok_or_else(|| io::Error::other("transport closed"))?;
+                Ok((
+                    Box::new(stream) as BoxedSessionStream,
+                    "192.0.2.1:4000".parse().unwrap(),
+                ))
+            })
+        }
+    }
+
+    fn memory_config(local_addr: SocketAddr, transport: &Arc<MemoryTransport>) -> Config {
+        let plugin_opts = PluginOpts::from_str("mux=false").unwrap();
+        Config {
+            local_addr: ServerAddr::SocketAddr(local_addr),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: Some(transport.clone()),
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let listen_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let transport = Arc::new(MemoryTransport::new());
+        tokio::spawn(server::start_proxy(memory_config(upstream_addr, &transport)));
+        tokio::spawn(local::start_proxy(memory_config(listen_addr, &transport)));
+
+        let mut client = loop {
+            match TcpStream::connect(listen_addr).await {
+                Ok(s) => break s,
+                Err(..) => time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        client.write_all(b"hello").await.unwrap();
+
+        let (mut upstream_stream, _) = upstream.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        upstream_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        upstream_stream.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        assert_eq!(transport.connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn websocket_request_in_segments() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GE").await.unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            stream
+                .write_all(
+                    b"T / HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut status = [0u8; 12];
+            stream.read_exact(&mut status).await.unwrap();
+            status
+        };
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept_tcp_session(stream, &PluginOpts::default()).await
+        };
+
+        let (status, session) = tokio::join!(client, server);
+        assert!(session.is_ok());
+        assert_eq!(&status, b"HTTP/1.1 101");
+    }
+
+    #[tokio::test]
+    async fn refused_by_firewall() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let plugin_opts = PluginOpts::from_str(&format!("tcp_port={}&denied_ips=127.0.0.1/32", port)).unwrap();
+        let config = Config {
+            local_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            firewall: Firewall::from_plugin_opts(&plugin_opts),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            limits: None,
+        };
+        let _listener = listen_tcp(&config).await.unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        // Closed without a handshake reply
+        let mut buf = Vec::new();
+        let n = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+    }
+}
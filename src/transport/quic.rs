@@ -0,0 +1,486 @@
+//! QUIC transport, in place of KCP sessions and their multiplexer
+//!
+//! Each stream is a native QUIC stream, so streams do not block each other when packets are lost. Connections are
+//! secured by TLS 1.3 with a self-signed certificate, which server generates on its first start, and which clients pin
+//! instead of verifying it with certificate authorities.
+
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    future::Future,
+    io::{self, ErrorKind, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{ready, Stream};
+use log::info;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, ConnectionError, Endpoint, EndpointConfig, Incoming, RecvStream, SendStream,
+    ServerConfig, TokioRuntime, TransportConfig, VarInt,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::lookup_host,
+    time,
+};
+use tokio_rustls::rustls::{
+    self,
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        verify_server_name,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::ParsedCertificate,
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+
+use crate::{
+    config::{network_required, Config, ServerAddr},
+    opt::{create_outbound_udp, PluginOpts},
+};
+
+/// Name in the self-signed certificate, clients send it in TLS handshakes
+const QUIC_SERVER_NAME: &str = "sskcp";
+
+const QUIC_ALPN: &[u8] = b"sskcp";
+
+/// Timeout for establishing a QUIC connection
+const QUIC_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of keep-alive packets sent by clients, so that NAT mappings do not expire
+const QUIC_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum streams that a client can open at the same time in a connection
+const QUIC_MAX_STREAMS: u32 = 1024;
+
+fn quic_port(opts: &PluginOpts) -> io::Result<u16> {
+    opts.quic_port
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "quic transport requires quic_port"))
+}
+
+fn load_cert(path: &str) -> io::Result<CertificateDer<'static>> {
+    CertificateDer::from_pem_file(path)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, format!("invalid certificate {}, {}", path, err)))
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, format!("invalid private key {}, {}", path, err)))
+}
+
+/// Write a file that only its owner can read
+fn write_private(path: &str, data: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(data)
+}
+
+/// Load the certificate of server, generating a self-signed one if neither file exists
+fn load_or_generate_cert(
+    cert_path: &str,
+    key_path: &str,
+) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    if !Path::new(cert_path).exists() && !Path::new(key_path).exists() {
+        let certified =
+            rcgen::generate_simple_self_signed(vec![QUIC_SERVER_NAME.to_owned()]).map_err(io::Error::other)?;
+        write_private(key_path, certified.key_pair.serialize_pem().as_bytes())?;
+        fs::write(cert_path, certified.cert.pem())?;
+        info!(
+            "generated QUIC certificate {}, copy it to clients as their quic_cert",
+            cert_path
+        );
+    }
+
+    Ok((load_cert(cert_path)?, load_key(key_path)?))
+}
+
+/// Accepts exactly the certificate in `quic_cert`, whoever signed it, if it names the server
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.cert.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer));
+        }
+        verify_server_name(&ParsedCertificate::try_from(end_entity)?, server_name)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn client_config(opts: &PluginOpts) -> io::Result<ClientConfig> {
+    let cert_path = opts
+        .quic_cert
+        .as_deref()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "quic transport requires quic_cert"))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PinnedCertVerifier {
+        cert: load_cert(cert_path)?,
+        provider: provider.clone(),
+    };
+
+    let mut tls_config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(QUIC_KEEP_ALIVE_INTERVAL));
+
+    let mut config = ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls_config).map_err(io::Error::other)?,
+    ));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+fn server_config(opts: &PluginOpts) -> io::Result<ServerConfig> {
+    let (cert_path, key_path) = match (opts.quic_cert.as_deref(), opts.quic_key.as_deref()) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "quic_port requires quic_cert and quic_key",
+            ))
+        }
+    };
+    let (cert, key) = load_or_generate_cert(cert_path, key_path)?;
+
+    let mut tls_config = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    tls_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let mut transport = TransportConfig::default();
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(QUIC_MAX_STREAMS));
+    transport.max_concurrent_uni_streams(VarInt::from_u32(0));
+
+    let mut config = ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(tls_config).map_err(io::Error::other)?,
+    ));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+async fn connect_quic_addr(addr: SocketAddr, config: ClientConfig, opts: &PluginOpts) -> io::Result<Connection> {
+    let socket = create_outbound_udp(addr, opts).await?.into_std()?;
+    let endpoint = Endpoint::new(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?;
+
+    let connecting = endpoint
+        .connect_with(config, addr, QUIC_SERVER_NAME)
+        .map_err(io::Error::other)?;
+    match time::timeout(QUIC_HANDSHAKE_TIMEOUT, connecting).await {
+        Ok(r) => r.map_err(io::Error::other),
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "quic handshake timeout")),
+    }
+}
+
+/// Connect a QUIC connection to the server's `quic_port`
+pub async fn connect_quic_session(config: &Config) -> io::Result<QuicSession> {
+    let opts = &config.plugin_opts;
+    let port = quic_port(opts)?;
+    let client_config = client_config(opts)?;
+
+    let addrs = match config.remote_addr {
+        ServerAddr::SocketAddr(sa) => vec![SocketAddr::new(sa.ip(), port)],
+        ServerAddr::DomainName(ref dname, _) => lookup_host((dname.as_str(), port)).await?.collect(),
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    };
+
+    let mut last_err = None;
+    for addr in addrs {
+        match connect_quic_addr(addr, client_config.clone(), opts).await {
+            Ok(conn) => return Ok(QuicSession::new(conn)),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::other("lookup_host resolved to empty")))
+}
+
+/// Listen for QUIC connections on `quic_port`, on the address of `SS_REMOTE_HOST`
+pub async fn bind_quic_endpoint(config: &Config) -> io::Result<Endpoint> {
+    let port = quic_port(&config.plugin_opts)?;
+    let server_config = server_config(&config.plugin_opts)?;
+
+    let addr = match config.remote_addr {
+        ServerAddr::SocketAddr(sa) => SocketAddr::new(sa.ip(), port),
+        ServerAddr::DomainName(ref dname, _) => match lookup_host((dname.as_str(), port)).await?.next() {
+            Some(sa) => sa,
+            None => return Err(io::Error::other("lookup_host resolved to empty")),
+        },
+        ServerAddr::UnixSocket(ref path) => return Err(network_required(path)),
+    };
+
+    Endpoint::server(server_config, addr)
+}
+
+/// Complete the handshake of a QUIC connection accepted by server
+pub async fn accept_quic_session(incoming: Incoming) -> io::Result<QuicSession> {
+    match time::timeout(QUIC_HANDSHAKE_TIMEOUT, incoming).await {
+        Ok(r) => r.map(QuicSession::new).map_err(io::Error::other),
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "quic handshake timeout")),
+    }
+}
+
+type AcceptBi = Pin<Box<dyn Future<Output = Result<(SendStream, RecvStream), ConnectionError>> + Send>>;
+
+/// QUIC connection, yields streams opened by the remote
+pub struct QuicSession {
+    conn: Connection,
+    accept: Option<AcceptBi>,
+}
+
+impl QuicSession {
+    fn new(conn: Connection) -> QuicSession {
+        QuicSession { conn, accept: None }
+    }
+
+    pub fn connection(&self) -> Connection {
+        self.conn.clone()
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+}
+
+impl Stream for QuicSession {
+    type Item = io::Result<QuicStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let accept = this.accept.get_or_insert_with(|| {
+            let conn = this.conn.clone();
+            Box::pin(async move { conn.accept_bi().await })
+        });
+        let result = ready!(accept.as_mut().poll(cx));
+        this.accept = None;
+
+        match result {
+            Ok((send, recv)) => Some(Ok(QuicStream { send, recv })).into(),
+            Err(ConnectionError::ApplicationClosed(..)) | Err(ConnectionError::LocallyClosed) => None.into(),
+            Err(err) => Some(Err(io::Error::other(err))).into(),
+        }
+    }
+}
+
+/// Open a stream in a QUIC connection
+///
+/// The remote does not see the stream until something is written to it.
+pub async fn open_quic_stream(conn: &Connection) -> io::Result<QuicStream> {
+    let (send, recv) = conn.open_bi().await.map_err(io::Error::other)?;
+    Ok(QuicStream { send, recv })
+}
+
+/// Bidirectional QUIC stream
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn id(&self) -> u32 {
+        self.send.id().index() as u32
+    }
+}
+
+impl fmt::Debug for QuicStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QuicStream").field("id", &self.send.id()).finish()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send)
+            .poll_write(cx, buf)
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Paths of a certificate and its key for a test, removed when dropped
+    struct TempCert {
+        cert: String,
+        key: String,
+    }
+
+    impl TempCert {
+        fn new(test: &str) -> TempCert {
+            let dir = std::env::temp_dir();
+            let prefix = format!("sskcp-quic-{}-{}", std::process::id(), test);
+            TempCert {
+                cert: dir.join(format!("{}.pem", prefix)).to_string_lossy().into_owned(),
+                key: dir.join(format!("{}.key", prefix)).to_string_lossy().into_owned(),
+            }
+        }
+
+        fn opts(&self) -> PluginOpts {
+            PluginOpts {
+                quic_cert: Some(self.cert.clone()),
+                quic_key: Some(self.key.clone()),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for TempCert {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.cert);
+            let _ = fs::remove_file(&self.key);
+        }
+    }
+
+    fn bind_server(opts: &PluginOpts) -> Endpoint {
+        Endpoint::server(server_config(opts).unwrap(), "127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let cert = TempCert::new("echo");
+        let opts = cert.opts();
+        let endpoint = bind_server(&opts);
+        let addr = endpoint.local_addr().unwrap();
+
+        let server = async {
+            let mut session = accept_quic_session(endpoint.accept().await.unwrap()).await.unwrap();
+            let mut stream = session.next().await.unwrap().unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+            // Keep the connection until the client has read the echo
+            session.next().await;
+        };
+        let client = async {
+            let conn = connect_quic_addr(addr, client_config(&opts).unwrap(), &opts)
+                .await
+                .unwrap();
+            let mut stream = open_quic_stream(&conn).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            conn.close(VarInt::from_u32(0), b"");
+            buf
+        };
+
+        let ((), echoed) = tokio::join!(server, client);
+        assert_eq!(echoed, b"hello");
+    }
+
+    #[tokio::test]
+    async fn other_certificate_rejected() {
+        let server_cert = TempCert::new("other-cert-server");
+        let client_cert = TempCert::new("other-cert-client");
+        load_or_generate_cert(&client_cert.cert, &client_cert.key).unwrap();
+
+        let endpoint = bind_server(&server_cert.opts());
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let _ = accept_quic_session(incoming).await;
+            }
+        });
+
+        let opts = client_cert.opts();
+        assert!(connect_quic_addr(addr, client_config(&opts).unwrap(), &opts)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn mismatched_server_name_rejected() {
+        let cert = TempCert::new("mismatched-name");
+        let certified = rcgen::generate_simple_self_signed(vec!["other.example".to_owned()]).unwrap();
+        fs::write(&cert.key, certified.key_pair.serialize_pem()).unwrap();
+        fs::write(&cert.cert, certified.cert.pem()).unwrap();
+
+        let opts = cert.opts();
+        let endpoint = bind_server(&opts);
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let _ = accept_quic_session(incoming).await;
+            }
+        });
+
+        assert!(connect_quic_addr(addr, client_config(&opts).unwrap(), &opts)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,449 @@
+//! WebSocket framing of a session, for networks that only let HTTP through
+//!
+//! Session bytes are carried in binary messages, as RFC 6455 describes. Clients mask their frames, servers do not.
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::ready;
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::frontend::read_http_head;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Status code of the Close frame sent on shutdown, normal closure
+const CLOSE_NORMAL: u16 = 1000;
+
+/// Maximum payload bytes in one frame that is written
+const MAX_WRITE_PAYLOAD_SIZE: usize = 16384;
+/// Maximum payload bytes in one frame that is read
+const MAX_READ_PAYLOAD_SIZE: usize = 1024 * 1024;
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+fn header<'a>(headers: &'a [httparse::Header<'a>], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value)
+}
+
+/// Whether a comma separated header value has `token`
+fn header_has_token(value: Option<&[u8]>, token: &str) -> bool {
+    match value.and_then(|v| std::str::from_utf8(v).ok()) {
+        Some(v) => v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+        None => false,
+    }
+}
+
+/// Upgrade `stream` to a WebSocket as a client, requesting `path` on `host`
+pub async fn client_handshake<S>(mut stream: S, host: &str, path: &str) -> io::Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = BASE64.encode(key);
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = read_http_head(&mut stream).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(httparse::Status::Complete(..)) => {}
+        Ok(httparse::Status::Partial) | Err(..) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid websocket handshake response",
+            ));
+        }
+    }
+
+    if response.code != Some(101) {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("websocket handshake rejected with status {:?}", response.code),
+        ));
+    }
+    if header(response.headers, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_bytes()) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid Sec-WebSocket-Accept in websocket handshake",
+        ));
+    }
+
+    Ok(WebSocketStream::new(stream, true))
+}
+
+/// Accept a WebSocket upgrade on `stream` as a server. Requests for other paths than `path` are rejected if it is set.
+pub async fn server_handshake<S>(mut stream: S, path: Option<&str>) -> io::Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = read_http_head(&mut stream).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(&head) {
+        Ok(httparse::Status::Complete(..)) => {}
+        Ok(httparse::Status::Partial) | Err(..) => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid websocket handshake request",
+            ));
+        }
+    }
+
+    let key = header(request.headers, "Sec-WebSocket-Key").and_then(|k| std::str::from_utf8(k).ok());
+    let upgrade = header_has_token(header(request.headers, "Upgrade"), "websocket")
+        && header_has_token(header(request.headers, "Connection"), "upgrade");
+
+    let key = match key {
+        Some(key) if upgrade && request.method == Some("GET") => key.trim(),
+        _ => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a websocket handshake request",
+            ));
+        }
+    };
+
+    if let Some(path) = path {
+        if request.path != Some(path) {
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("websocket handshake for path {:?}", request.path),
+            ));
+        }
+    }
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(WebSocketStream::new(stream, false))
+}
+
+/// Session carried in a WebSocket
+pub struct WebSocketStream<S> {
+    stream: S,
+    is_client: bool,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    read_buf: Vec<u8>,
+    payload: Vec<u8>,
+    payload_pos: usize,
+    read_eof: bool,
+    close_sent: bool,
+}
+
+impl<S> WebSocketStream<S> {
+    fn new(stream: S, is_client: bool) -> WebSocketStream<S> {
+        WebSocketStream {
+            stream,
+            is_client,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            read_buf: Vec::new(),
+            payload: Vec::new(),
+            payload_pos: 0,
+            read_eof: false,
+            close_sent: false,
+        }
+    }
+
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let buf = &mut self.write_buf;
+        buf.push(0x80 | opcode);
+
+        let mask_bit = if self.is_client { 0x80 } else { 0x00 };
+        match payload.len() {
+            n if n < 126 => buf.push(mask_bit | n as u8),
+            n if n <= u16::MAX as usize => {
+                buf.push(mask_bit | 126);
+                buf.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                buf.push(mask_bit | 127);
+                buf.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+
+        if self.is_client {
+            let mut mask = [0u8; 4];
+            rand::thread_rng().fill_bytes(&mut mask);
+            buf.extend_from_slice(&mask);
+            buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            buf.extend_from_slice(payload);
+        }
+    }
+
+    /// Decode one frame from `read_buf`. Returns `false` if more data is required.
+    fn decode_frame(&mut self) -> io::Result<bool> {
+        let buf = &self.read_buf;
+        if buf.len() < 2 {
+            return Ok(false);
+        }
+
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        let (len, mut pos) = match buf[1] & 0x7f {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(false);
+                }
+                (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(false);
+                }
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            n => (n as u64, 2),
+        };
+
+        if len > MAX_READ_PAYLOAD_SIZE as u64 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "websocket frame too large"));
+        }
+        let len = len as usize;
+
+        let mut mask = None;
+        if masked {
+            if buf.len() < pos + 4 {
+                return Ok(false);
+            }
+            mask = Some([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            pos += 4;
+        }
+
+        if buf.len() < pos + len {
+            return Ok(false);
+        }
+
+        let mut payload = buf[pos..pos + len].to_vec();
+        if let Some(mask) = mask {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+        self.read_buf.drain(..pos + len);
+
+        match opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                self.payload = payload;
+                self.payload_pos = 0;
+            }
+            OPCODE_CLOSE => {
+                self.read_eof = true;
+            }
+            OPCODE_PING => {
+                // Sent by the reader, or with the next write or flush
+                self.encode_frame(OPCODE_PONG, &payload);
+            }
+            OPCODE_PONG => {}
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown websocket opcode {:#x}", opcode),
+                ));
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.payload_pos < this.payload.len() {
+                let n = buf.remaining().min(this.payload.len() - this.payload_pos);
+                buf.put_slice(&this.payload[this.payload_pos..this.payload_pos + n]);
+                this.payload_pos += n;
+                return Ok(()).into();
+            }
+
+            if this.read_eof {
+                return Ok(()).into();
+            }
+
+            let write_idle = this.write_buf.is_empty();
+            if this.decode_frame()? {
+                // Only if nothing else was buffered, so that the waker of a writer waiting on the buffer is kept
+                if write_idle && !this.write_buf.is_empty() {
+                    let _ = this.poll_write_buffered(cx);
+                }
+                continue;
+            }
+
+            let mut chunk = [0u8; 16384];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+
+            let n = read_buf.filled().len();
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+            this.read_buf.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_buffered(cx))?;
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        let n = buf.len().min(MAX_WRITE_PAYLOAD_SIZE);
+        this.encode_frame(OPCODE_BINARY, &buf[..n]);
+
+        if let Poll::Ready(Err(err)) = this.poll_write_buffered(cx) {
+            return Err(err).into();
+        }
+
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        if !this.close_sent {
+            this.encode_frame(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+            this.close_sent = true;
+            ready!(this.poll_write_buffered(cx))?;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncReadExt};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (client, server) = duplex(65536);
+        let (client, server) = tokio::join!(
+            client_handshake(client, "example.com", "/ws"),
+            server_handshake(server, Some("/ws")),
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let data = (0..50000u32).map(|i| i as u8).collect::<Vec<_>>();
+        client.write_all(&data).await.unwrap();
+        client.flush().await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"reply").await.unwrap();
+        server.flush().await.unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"reply");
+    }
+
+    #[tokio::test]
+    async fn pong_sent_by_reader() {
+        let (mut peer, stream) = duplex(1024);
+        let mut stream = WebSocketStream::new(stream, false);
+
+        peer.write_all(&[0x89, 2, b'h', b'i', 0x82, 1, b'x']).await.unwrap();
+        let mut data = [0u8; 1];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"x");
+
+        let mut pong = [0u8; 4];
+        peer.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x8a, 2, b'h', b'i']);
+    }
+
+    #[tokio::test]
+    async fn close_sent_on_shutdown() {
+        let (mut peer, stream) = duplex(1024);
+        let mut stream = WebSocketStream::new(stream, false);
+
+        stream.shutdown().await.unwrap();
+        let mut close = Vec::new();
+        peer.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+    }
+}
@@ -0,0 +1,761 @@
+//! Client side UDP relay
+//!
+//! ```plain
+//!             Loopback                          UDP
+//! [KcpStream] <------> [UdpClientRelay] ----------------> SERVER:PORT
+//! ```
+//!
+//! The KCP session only sees the relay's loopback address, so the relay is free to hop to another server port, and to
+//! another local port, without breaking the session.
+//!
+//! In multipath mode, the relay sends over one socket per interface or address, either on all of them, in turn, or on
+//! the one with the lowest round-trip time measured by probes.
+//!
+//! In roaming mode, the relay opens new sockets when asked to, when the system would send from another address, or
+//! when sending fails or nothing has been received for a while. The server moves the session to the new address.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::{debug, error, info, trace};
+use rand::Rng;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_kcp::{KcpConfig, KcpStream};
+
+use super::{
+    stats::KcpStats, DatagramLayers, HeaderConfig, MultipathMode, PathHeader, PathSpec, ReplayWindow, PACKET_DATA,
+    PACKET_DATA_REDUNDANT, PACKET_PROBE, PACKET_PROBE_REPLY, RECV_ERROR_BACKOFF,
+};
+use crate::opt::{create_outbound_udp, PluginOpts, PortRange};
+
+/// Default interval of hopping, if `server_port_range` is set
+const DEFAULT_HOP_INTERVAL: Duration = Duration::from_secs(60);
+/// Default timeout of receiving nothing before hopping, if `server_port_range` is set
+const DEFAULT_HOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval of probing paths in multipath mode
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Paths that received nothing in this duration are avoided
+const PATH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Rebind if nothing was received in this duration while sending, in roaming mode
+const ROAMING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Port hopping configuration
+#[derive(Debug, Clone)]
+pub struct HopConfig {
+    /// Server ports to hop between
+    pub ports: PortRange,
+    /// Hop on schedule
+    pub interval: Option<Duration>,
+    /// Hop if nothing was received in this duration while sending
+    pub timeout: Option<Duration>,
+}
+
+impl HopConfig {
+    pub fn from_plugin_opts(ports: PortRange, opts: &PluginOpts) -> HopConfig {
+        let duration = |secs: Option<u64>, default: Duration| match secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(default),
+        };
+
+        HopConfig {
+            ports,
+            interval: duration(opts.hop_interval, DEFAULT_HOP_INTERVAL),
+            timeout: duration(opts.hop_timeout, DEFAULT_HOP_TIMEOUT),
+        }
+    }
+}
+
+/// Multipath configuration
+#[derive(Debug, Clone)]
+pub struct MultipathConfig {
+    /// Interfaces or addresses to send from
+    pub paths: Vec<PathSpec>,
+    pub mode: MultipathMode,
+}
+
+/// UDP relay configuration
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    pub hop: Option<HopConfig>,
+    pub multipath: Option<MultipathConfig>,
+    /// Rebind sockets when the network changes
+    pub roaming: bool,
+    pub header: Option<HeaderConfig>,
+    pub layers: DatagramLayers,
+    /// Track statistics of the KCP session
+    pub kcp_stats: bool,
+}
+
+impl RelayConfig {
+    /// `None` if KCP sessions could send to the server directly
+    pub fn from_plugin_opts(opts: &PluginOpts, layers: &DatagramLayers) -> Option<RelayConfig> {
+        let hop = opts
+            .server_port_range
+            .map(|ports| HopConfig::from_plugin_opts(ports, opts));
+        let multipath = opts.multipath.as_ref().map(|paths| MultipathConfig {
+            paths: paths.0.clone(),
+            mode: opts.multipath_mode.unwrap_or_default(),
+        });
+
+        let roaming = opts.roaming.unwrap_or(false);
+        let kcp_stats = opts.kcp_stats.unwrap_or(false);
+
+        if hop.is_none() && multipath.is_none() && !roaming && layers.is_empty() && !kcp_stats {
+            return None;
+        }
+        Some(RelayConfig {
+            hop,
+            multipath,
+            roaming,
+            header: HeaderConfig::for_client(opts),
+            layers: layers.clone(),
+            kcp_stats,
+        })
+    }
+}
+
+/// UDP relay of one KCP session, stopped when dropped
+pub struct UdpClientRelay {
+    local_addr: SocketAddr,
+    server_addr: SocketAddr,
+    stats: Option<Arc<KcpStats>>,
+    task: JoinHandle<()>,
+}
+
+impl UdpClientRelay {
+    /// Start relaying to `server_addr`, recording datagrams of the KCP session in `stats`
+    pub async fn start(
+        server_addr: SocketAddr,
+        config: RelayConfig,
+        opts: &PluginOpts,
+        stats: Option<Arc<KcpStats>>,
+    ) -> io::Result<UdpClientRelay> {
+        let kcp_socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let local_addr = kcp_socket.local_addr()?;
+
+        let path_opts = match config.multipath {
+            Some(ref multipath) => multipath
+                .paths
+                .iter()
+                .map(|path| (path.to_string(), path.apply(opts)))
+                .collect(),
+            None => vec![("default".to_owned(), opts.clone())],
+        };
+
+        let (inbound_tx, inbound_rx) = mpsc::channel(1024);
+        let paths = open_paths(&path_opts, server_addr, &inbound_tx).await?;
+
+        let now = Instant::now();
+        let relay = ClientRelay {
+            kcp_addr: None,
+            server_addr,
+            base_port: server_addr.port(),
+            hop_at: config.hop.as_ref().and_then(|hop| hop.interval).map(|i| now + i),
+            config,
+            path_opts,
+            paths,
+            inbound_tx,
+            stats: stats.clone(),
+            session_id: rand::random(),
+            send_seq: 0,
+            replay: ReplayWindow::default(),
+            next_path: 0,
+            rebind_generation: REBIND_GENERATION.load(Ordering::Relaxed),
+            started: now,
+            last_sent: now,
+            last_received: now,
+        };
+
+        let task = tokio::spawn(async move {
+            if let Err(err) = relay.run(kcp_socket, inbound_rx).await {
+                error!("udp relay to {} exited with error: {}", server_addr, err);
+            }
+        });
+
+        Ok(UdpClientRelay {
+            local_addr,
+            server_addr,
+            stats,
+            task,
+        })
+    }
+
+    /// Loopback address that KCP should send to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn kcp_stats(&self) -> Option<&Arc<KcpStats>> {
+        self.stats.as_ref()
+    }
+}
+
+impl Drop for UdpClientRelay {
+    fn drop(&mut self) {
+        self.task.abort();
+
+        if let Some(ref stats) = self.stats {
+            info!("kcp session to {} closed, {}", self.server_addr, stats.snapshot());
+        }
+    }
+}
+
+fn hop_port(ports: &PortRange, current: u16) -> u16 {
+    if ports.start == ports.end {
+        return ports.start;
+    }
+
+    let mut rng = rand::thread_rng();
+    loop {
+        let port = rng.gen_range(ports.start..=ports.end);
+        if port != current {
+            return port;
+        }
+    }
+}
+
+static REBIND_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Ask relays in roaming mode to rebind their sockets, like after the network has changed
+pub fn request_rebind() {
+    REBIND_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Ask relays to rebind on every `SIGUSR1`
+#[cfg(unix)]
+pub async fn rebind_on_signal() {
+    let mut signal = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("failed to listen for SIGUSR1, error: {}", err);
+            return;
+        }
+    };
+
+    while signal.recv().await.is_some() {
+        info!("received SIGUSR1, rebinding udp relays");
+        request_rebind();
+    }
+}
+
+/// Source IP that the system chooses for sending to `addr`
+fn route_source_ip(addr: SocketAddr) -> Option<IpAddr> {
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(..) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(..) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = std::net::UdpSocket::bind(bind_addr).ok()?;
+    socket.connect(addr).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+struct Inbound {
+    path: usize,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// Outbound socket bound to one interface or address
+struct ClientPath {
+    name: String,
+    socket: Arc<UdpSocket>,
+    recv_task: JoinHandle<()>,
+    srtt: Option<Duration>,
+    last_received: Instant,
+    /// Source IP chosen by the system when opened, if the path isn't bound to an interface or address
+    route_ip: Option<IpAddr>,
+    send_failed: bool,
+}
+
+impl ClientPath {
+    async fn open(
+        index: usize,
+        name: &str,
+        opts: &PluginOpts,
+        server_addr: SocketAddr,
+        inbound_tx: &mpsc::Sender<Inbound>,
+    ) -> io::Result<ClientPath> {
+        let socket = Arc::new(create_outbound_udp(server_addr, opts).await?);
+
+        let recv_task = {
+            let socket = socket.clone();
+            let inbound_tx = inbound_tx.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let (n, from) = match socket.recv_from(&mut buf).await {
+                        Ok(r) => r,
+                        Err(err) => {
+                            debug!("udp relay recv error: {}", err);
+                            time::sleep(RECV_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+
+                    let inbound = Inbound {
+                        path: index,
+                        from,
+                        data: buf[..n].to_vec(),
+                    };
+                    if inbound_tx.send(inbound).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+
+        let route_ip = if opts.outbound_bind_addr.is_none() && opts.outbound_bind_interface.is_none() {
+            route_source_ip(server_addr)
+        } else {
+            None
+        };
+
+        Ok(ClientPath {
+            name: name.to_owned(),
+            socket,
+            recv_task,
+            srtt: None,
+            last_received: Instant::now(),
+            route_ip,
+            send_failed: false,
+        })
+    }
+
+    fn is_alive(&self, now: Instant) -> bool {
+        now - self.last_received < PATH_TIMEOUT
+    }
+}
+
+impl Drop for ClientPath {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
+
+/// Open sockets of all paths, skipping the ones that fail
+async fn open_paths(
+    path_opts: &[(String, PluginOpts)],
+    server_addr: SocketAddr,
+    inbound_tx: &mpsc::Sender<Inbound>,
+) -> io::Result<Vec<ClientPath>> {
+    let mut paths = Vec::with_capacity(path_opts.len());
+    let mut last_err = None;
+
+    for (name, opts) in path_opts {
+        match ClientPath::open(paths.len(), name, opts, server_addr, inbound_tx).await {
+            Ok(path) => paths.push(path),
+            Err(err) => {
+                error!(
+                    "udp relay failed to open path {} to {}, error: {}",
+                    name, server_addr, err
+                );
+                last_err = Some(err);
+            }
+        }
+    }
+
+    match last_err {
+        Some(err) if paths.is_empty() => Err(err),
+        _ => Ok(paths),
+    }
+}
+
+struct ClientRelay {
+    kcp_addr: Option<SocketAddr>,
+    server_addr: SocketAddr,
+    base_port: u16,
+    config: RelayConfig,
+    path_opts: Vec<(String, PluginOpts)>,
+    paths: Vec<ClientPath>,
+    inbound_tx: mpsc::Sender<Inbound>,
+    stats: Option<Arc<KcpStats>>,
+    session_id: u64,
+    send_seq: u64,
+    replay: ReplayWindow,
+    next_path: usize,
+    rebind_generation: u64,
+    started: Instant,
+    last_sent: Instant,
+    last_received: Instant,
+    hop_at: Option<Instant>,
+}
+
+impl ClientRelay {
+    async fn run(mut self, kcp_socket: UdpSocket, mut inbound_rx: mpsc::Receiver<Inbound>) -> io::Result<()> {
+        let mut kcp_buf = vec![0u8; 65536];
+        let mut send_buf = Vec::with_capacity(65536);
+        let mut check_interval = time::interval(PROBE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                r = kcp_socket.recv_from(&mut kcp_buf) => {
+                    let (n, addr) = r?;
+                    self.kcp_addr = Some(addr);
+                    if let Some(ref stats) = self.stats {
+                        stats.on_send(&kcp_buf[..n]);
+                    }
+                    self.send_data(&kcp_buf[..n], &mut send_buf).await;
+                    self.last_sent = Instant::now();
+                }
+                Some(inbound) = inbound_rx.recv() => {
+                    self.handle_inbound(&kcp_socket, inbound).await;
+                }
+                _ = check_interval.tick() => {
+                    if self.config.header.is_some() {
+                        self.send_probes(&mut send_buf).await;
+                    }
+                    self.check_hop().await;
+                    self.check_roaming().await;
+                }
+            }
+        }
+    }
+
+    async fn send_data(&mut self, payload: &[u8], buf: &mut Vec<u8>) {
+        let mut layer_buf = Vec::new();
+
+        let header_config = match self.config.header {
+            Some(ref header) => header,
+            None => {
+                let packet = self.config.layers.wrap(payload, &mut layer_buf);
+                let path = &mut self.paths[0];
+                if let Err(err) = path.socket.send_to(packet, self.server_addr).await {
+                    debug!("udp relay send to {} error: {}", self.server_addr, err);
+                    path.send_failed = true;
+                }
+                return;
+            }
+        };
+
+        let mode = self.config.multipath.as_ref().map(|multipath| multipath.mode);
+        let redundant = mode == Some(MultipathMode::Redundant);
+
+        self.send_seq += 1;
+        let header = PathHeader {
+            packet_type: if redundant { PACKET_DATA_REDUNDANT } else { PACKET_DATA },
+            session_id: self.session_id,
+            seq: self.send_seq,
+        };
+        header_config.encode(&header, payload, buf);
+        let packet = self.config.layers.wrap(buf, &mut layer_buf);
+
+        if redundant {
+            for path in self.paths.iter_mut() {
+                if let Err(err) = path.socket.send_to(packet, self.server_addr).await {
+                    debug!(
+                        "udp relay send to {} via {} error: {}",
+                        self.server_addr, path.name, err
+                    );
+                    path.send_failed = true;
+                }
+            }
+        } else {
+            let index = match mode {
+                Some(mode) => self.pick_path(mode),
+                None => 0,
+            };
+            let path = &mut self.paths[index];
+            if let Err(err) = path.socket.send_to(packet, self.server_addr).await {
+                debug!(
+                    "udp relay send to {} via {} error: {}",
+                    self.server_addr, path.name, err
+                );
+                path.send_failed = true;
+            }
+        }
+    }
+
+    /// Choose a path for sending, among the ones that received recently if there are any
+    fn pick_path(&mut self, mode: MultipathMode) -> usize {
+        let now = Instant::now();
+        let count = self.paths.len();
+        let any_alive = self.paths.iter().any(|p| p.is_alive(now));
+        let usable = |p: &ClientPath| !any_alive || p.is_alive(now);
+
+        match mode {
+            MultipathMode::LowRtt => self
+                .paths
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| usable(p))
+                .min_by_key(|(_, p)| p.srtt.unwrap_or(Duration::MAX))
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+            MultipathMode::RoundRobin | MultipathMode::Redundant => {
+                for _ in 0..count {
+                    let index = self.next_path % count;
+                    self.next_path = index + 1;
+                    if usable(&self.paths[index]) {
+                        return index;
+                    }
+                }
+                0
+            }
+        }
+    }
+
+    /// Probe every path, which also tells the server about paths that only carry duplicates
+    async fn send_probes(&mut self, buf: &mut Vec<u8>) {
+        let header_config = match self.config.header {
+            Some(ref header) => header,
+            None => return,
+        };
+        let timestamp = self.started.elapsed().as_micros() as u64;
+        let mut layer_buf = Vec::new();
+
+        for path in &self.paths {
+            self.send_seq += 1;
+            let header = PathHeader {
+                packet_type: PACKET_PROBE,
+                session_id: self.session_id,
+                seq: self.send_seq,
+            };
+            header_config.encode(&header, &timestamp.to_be_bytes(), buf);
+            let packet = self.config.layers.wrap(buf, &mut layer_buf);
+
+            if let Err(err) = path.socket.send_to(packet, self.server_addr).await {
+                trace!(
+                    "udp relay probe to {} via {} error: {}",
+                    self.server_addr,
+                    path.name,
+                    err
+                );
+            }
+        }
+    }
+
+    async fn handle_inbound(&mut self, kcp_socket: &UdpSocket, inbound: Inbound) {
+        let Inbound { path, from, data } = inbound;
+
+        let port_valid = match self.config.hop {
+            Some(ref hop) => hop.ports.contains(from.port()) || from.port() == self.base_port,
+            None => from.port() == self.base_port,
+        };
+        if from.ip() != self.server_addr.ip() || !port_valid {
+            trace!("udp relay ignored {} bytes from {}", data.len(), from);
+            return;
+        }
+
+        let mut layer_buf = Vec::new();
+        let data = match self.config.layers.unwrap(&data, &mut layer_buf) {
+            Some(d) => d,
+            None => {
+                trace!("udp relay dropped {} bytes from {}", data.len(), from);
+                return;
+            }
+        };
+
+        let payload = if let Some(ref header_config) = self.config.header {
+            let (header, payload) = match header_config.decode(data) {
+                Some(h) if h.0.session_id == self.session_id => h,
+                _ => {
+                    trace!("udp relay ignored {} bytes from {}", data.len(), from);
+                    return;
+                }
+            };
+            self.mark_received(path);
+
+            match header.packet_type {
+                PACKET_DATA | PACKET_DATA_REDUNDANT => {
+                    if !self.replay.accept(header.seq) {
+                        return;
+                    }
+                    payload
+                }
+                PACKET_PROBE_REPLY if payload.len() == 8 => {
+                    let mut timestamp = [0u8; 8];
+                    timestamp.copy_from_slice(payload);
+                    let sent = Duration::from_micros(u64::from_be_bytes(timestamp));
+                    let rtt = self.started.elapsed().saturating_sub(sent);
+
+                    if let Some(path) = self.paths.get_mut(path) {
+                        path.srtt = Some(match path.srtt {
+                            Some(srtt) => (srtt * 7 + rtt) / 8,
+                            None => rtt,
+                        });
+                        trace!("udp relay path {} rtt {:?}, srtt {:?}", path.name, rtt, path.srtt);
+                    }
+                    return;
+                }
+                _ => return,
+            }
+        } else {
+            self.mark_received(path);
+            data
+        };
+
+        if let Some(ref stats) = self.stats {
+            stats.on_recv(payload);
+        }
+
+        if let Some(addr) = self.kcp_addr {
+            if let Err(err) = kcp_socket.send_to(payload, addr).await {
+                debug!("udp relay send to kcp {} error: {}", addr, err);
+            }
+        }
+    }
+
+    fn mark_received(&mut self, path: usize) {
+        let now = Instant::now();
+        self.last_received = now;
+        if let Some(path) = self.paths.get_mut(path) {
+            path.last_received = now;
+        }
+    }
+
+    async fn check_hop(&mut self) {
+        let hop = match self.config.hop {
+            Some(ref hop) => hop.clone(),
+            None => return,
+        };
+
+        let now = Instant::now();
+        let throttled = match hop.timeout {
+            Some(timeout) => self.last_sent > self.last_received && now - self.last_received >= timeout,
+            None => false,
+        };
+        let scheduled = matches!(self.hop_at, Some(at) if now >= at);
+
+        if !throttled && !scheduled {
+            return;
+        }
+
+        let mut new_addr = self.server_addr;
+        new_addr.set_port(hop_port(&hop.ports, self.server_addr.port()));
+
+        // Send from new local ports, so nothing of the old 5-tuples remains
+        match open_paths(&self.path_opts, new_addr, &self.inbound_tx).await {
+            Ok(paths) => {
+                debug!(
+                    "udp relay hopped from {} to {}, {}",
+                    self.server_addr,
+                    new_addr,
+                    if throttled { "nothing received" } else { "scheduled" }
+                );
+                self.paths = paths;
+                self.server_addr = new_addr;
+            }
+            Err(err) => {
+                error!("udp relay failed to hop to {}, error: {}", new_addr, err);
+            }
+        }
+
+        self.last_received = now;
+        self.hop_at = hop.interval.map(|i| now + i);
+    }
+
+    async fn check_roaming(&mut self) {
+        if !self.config.roaming {
+            return;
+        }
+
+        let now = Instant::now();
+        let generation = REBIND_GENERATION.load(Ordering::Relaxed);
+
+        let reason = if generation != self.rebind_generation {
+            "requested"
+        } else if self.paths.iter().any(|p| p.send_failed) {
+            "send failed"
+        } else if self.last_sent > self.last_received && now - self.last_received >= ROAMING_TIMEOUT {
+            "nothing received"
+        } else {
+            let route_ip = route_source_ip(self.server_addr);
+            if route_ip.is_some()
+                && self
+                    .paths
+                    .iter()
+                    .any(|p| p.route_ip.is_some() && p.route_ip != route_ip)
+            {
+                "source address changed"
+            } else {
+                return;
+            }
+        };
+
+        self.rebind_generation = generation;
+        self.last_received = now;
+
+        match open_paths(&self.path_opts, self.server_addr, &self.inbound_tx).await {
+            Ok(paths) => {
+                info!("udp relay to {} rebound, {}", self.server_addr, reason);
+                self.paths = paths;
+            }
+            Err(err) => {
+                error!("udp relay to {} failed to rebind, error: {}", self.server_addr, err);
+            }
+        }
+    }
+}
+
+/// Stream that keeps a UDP relay running while it is alive
+pub struct RelayedStream<S> {
+    stream: S,
+    relay: UdpClientRelay,
+}
+
+impl<S> RelayedStream<S> {
+    /// Statistics of the KCP session, if `kcp_stats` is set
+    pub fn kcp_stats(&self) -> Option<&Arc<KcpStats>> {
+        self.relay.kcp_stats()
+    }
+}
+
+impl<S> AsyncRead for RelayedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for RelayedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Create a KcpStream to `server_addr`, sending through a UDP relay
+pub async fn connect_relayed_kcp(
+    config: &KcpConfig,
+    server_addr: SocketAddr,
+    relay_config: RelayConfig,
+    opts: &PluginOpts,
+) -> io::Result<RelayedStream<KcpStream>> {
+    let stats = relay_config.kcp_stats.then(|| Arc::new(KcpStats::new(config)));
+    let relay = UdpClientRelay::start(server_addr, relay_config, opts, stats).await?;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let stream = KcpStream::connect_with_socket(config, socket, relay.local_addr()).await?;
+
+    Ok(RelayedStream { stream, relay })
+}
@@ -0,0 +1,739 @@
+//! UDP relays between KCP sessions and the network
+//!
+//! KCP sessions are bound to the addresses they talk to. Relaying their datagrams through loopback sockets lets the
+//! path on the network change without breaking sessions.
+//!
+//! In multipath and roaming mode, datagrams between relays carry a header, so that the server can recognize a session
+//! arriving from several addresses, or from a new address, and both sides can drop duplicates.
+//!
+//! ```plain
+//! +------+------------+--------+---------+-----------+
+//! | TYPE | SESSION ID |  SEQ   | PAYLOAD |    TAG    |
+//! +------+------------+--------+---------+-----------+
+//! |  1   |   8 (BE)   | 8 (BE) |         | 16 or 0   |
+//! +------+------------+--------+---------+-----------+
+//! ```
+//!
+//! TAG is HMAC-SHA256 of everything before it, truncated to 16 bytes, if `session_key` is set. Only packets with a
+//! valid TAG and a fresh SEQ can move a session to another address.
+//!
+//! Library users may add [`DatagramLayer`]s, which transform every datagram between the relays, outside of the
+//! header. Both sides must have the same layers.
+
+use std::{
+    fmt::{self, Display},
+    io,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
+
+use crate::opt::PluginOpts;
+
+pub mod client;
+pub mod server;
+pub mod stats;
+
+/// Size of KCP segment header
+const KCP_OVERHEAD: usize = 24;
+
+/// Pause of receive loops after an error, so that a failing socket does not spin
+pub(crate) const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_CMD_WINS: u8 = 84;
+
+/// Conversation ID of a KCP packet, `None` if it is not a KCP packet
+fn kcp_conv(packet: &[u8]) -> Option<u32> {
+    if packet.len() < KCP_OVERHEAD {
+        return None;
+    }
+    Some(u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]))
+}
+
+/// Whether `packet` may be the first packet of a KCP session: segments of one conversation with valid commands and
+/// lengths, starting with a data segment
+fn kcp_opens_session(packet: &[u8]) -> bool {
+    let conv = match kcp_conv(packet) {
+        Some(c) => c,
+        None => return false,
+    };
+    if packet[4] != KCP_CMD_PUSH {
+        return false;
+    }
+
+    let mut rest = packet;
+    while !rest.is_empty() {
+        if kcp_conv(rest) != Some(conv) || !(KCP_CMD_PUSH..=KCP_CMD_WINS).contains(&rest[4]) {
+            return false;
+        }
+        let len = u32::from_le_bytes([rest[20], rest[21], rest[22], rest[23]]) as usize;
+        rest = match KCP_OVERHEAD.checked_add(len).and_then(|end| rest.get(end..)) {
+            Some(r) => r,
+            None => return false,
+        };
+    }
+    true
+}
+
+const PATH_HEADER_SIZE: usize = 17;
+const PATH_TAG_SIZE: usize = 16;
+
+/// KCP packet
+const PACKET_DATA: u8 = 0x01;
+/// KCP packet, sent on every path. Replies should be sent on every path, too.
+const PACKET_DATA_REDUNDANT: u8 = 0x02;
+/// Path probe, echoed by the server
+const PACKET_PROBE: u8 = 0x03;
+const PACKET_PROBE_REPLY: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy)]
+struct PathHeader {
+    packet_type: u8,
+    session_id: u64,
+    seq: u64,
+}
+
+impl PathHeader {
+    fn encode(&self, payload: &[u8], buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.push(self.packet_type);
+        buf.extend_from_slice(&self.session_id.to_be_bytes());
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(payload);
+    }
+
+    fn decode(packet: &[u8]) -> Option<(PathHeader, &[u8])> {
+        if packet.len() < PATH_HEADER_SIZE {
+            return None;
+        }
+
+        let mut session_id = [0u8; 8];
+        session_id.copy_from_slice(&packet[1..9]);
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&packet[9..17]);
+
+        let header = PathHeader {
+            packet_type: packet[0],
+            session_id: u64::from_be_bytes(session_id),
+            seq: u64::from_be_bytes(seq),
+        };
+        Some((header, &packet[PATH_HEADER_SIZE..]))
+    }
+}
+
+/// Signs and verifies datagrams with `session_key`
+#[derive(Clone)]
+struct PacketAuth {
+    mac: Hmac<Sha256>,
+}
+
+impl PacketAuth {
+    fn new(key: &str) -> PacketAuth {
+        PacketAuth {
+            mac: Hmac::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    fn sign(&self, buf: &mut Vec<u8>) {
+        let mut mac = self.mac.clone();
+        mac.update(buf);
+        let tag = mac.finalize().into_bytes();
+        buf.extend_from_slice(&tag[..PATH_TAG_SIZE]);
+    }
+
+    /// Packet without its tag, `None` if the tag is invalid
+    fn verify<'a>(&self, packet: &'a [u8]) -> Option<&'a [u8]> {
+        if packet.len() < PATH_TAG_SIZE {
+            return None;
+        }
+
+        let (data, tag) = packet.split_at(packet.len() - PATH_TAG_SIZE);
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        mac.verify_truncated_left(tag).ok().map(|_| data)
+    }
+}
+
+/// Header of datagrams between relays, used in multipath and roaming mode
+#[derive(Clone)]
+pub struct HeaderConfig {
+    auth: Option<PacketAuth>,
+}
+
+impl HeaderConfig {
+    /// Header of a client with `multipath` or `roaming`, `None` if datagrams are relayed as they are
+    pub fn for_client(opts: &PluginOpts) -> Option<HeaderConfig> {
+        if opts.multipath.is_none() && !opts.roaming.unwrap_or(false) {
+            return None;
+        }
+        Some(HeaderConfig::new(opts))
+    }
+
+    /// Header of a server with `accept_multipath` or `roaming`, `None` if datagrams are relayed as they are
+    pub fn for_server(opts: &PluginOpts) -> Option<HeaderConfig> {
+        if !opts.accept_multipath.unwrap_or(false) && !opts.roaming.unwrap_or(false) {
+            return None;
+        }
+        Some(HeaderConfig::new(opts))
+    }
+
+    fn new(opts: &PluginOpts) -> HeaderConfig {
+        HeaderConfig {
+            auth: opts.session_key.as_deref().map(PacketAuth::new),
+        }
+    }
+
+    fn encode(&self, header: &PathHeader, payload: &[u8], buf: &mut Vec<u8>) {
+        header.encode(payload, buf);
+        if let Some(ref auth) = self.auth {
+            auth.sign(buf);
+        }
+    }
+
+    fn decode<'a>(&self, packet: &'a [u8]) -> Option<(PathHeader, &'a [u8])> {
+        let packet = match self.auth {
+            Some(ref auth) => auth.verify(packet)?,
+            None => packet,
+        };
+        PathHeader::decode(packet)
+    }
+}
+
+impl fmt::Debug for HeaderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HeaderConfig")
+            .field("authenticated", &self.auth.is_some())
+            .finish()
+    }
+}
+
+/// Transforms UDP datagrams of KCP sessions on their way to and from the network, like obfuscation
+pub trait DatagramLayer: Send + Sync + fmt::Debug {
+    /// Transform a datagram before it is sent
+    fn wrap(&self, datagram: &mut Vec<u8>);
+
+    /// Undo `wrap` on a received datagram, returns `false` if it should be dropped
+    fn unwrap(&self, datagram: &mut Vec<u8>) -> bool;
+}
+
+/// Stack of datagram layers, the first one is the innermost
+#[derive(Debug, Clone, Default)]
+pub struct DatagramLayers(pub Vec<Arc<dyn DatagramLayer>>);
+
+impl DatagramLayers {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `datagram` wrapped by all layers in `buf`, or `datagram` itself if there are none
+    pub fn wrap<'a>(&self, datagram: &'a [u8], buf: &'a mut Vec<u8>) -> &'a [u8] {
+        if self.0.is_empty() {
+            return datagram;
+        }
+
+        buf.clear();
+        buf.extend_from_slice(datagram);
+        for layer in &self.0 {
+            layer.wrap(buf);
+        }
+        buf
+    }
+
+    /// `datagram` unwrapped by all layers in `buf`, or `datagram` itself if there are none. `None` if any layer drops
+    /// it.
+    pub fn unwrap<'a>(&self, datagram: &'a [u8], buf: &'a mut Vec<u8>) -> Option<&'a [u8]> {
+        if self.0.is_empty() {
+            return Some(datagram);
+        }
+
+        buf.clear();
+        buf.extend_from_slice(datagram);
+        for layer in self.0.iter().rev() {
+            if !layer.unwrap(buf) {
+                return None;
+            }
+        }
+        Some(buf)
+    }
+}
+
+/// Check options of UDP relays
+pub fn check_plugin_opts(opts: &PluginOpts) -> io::Result<()> {
+    let header = opts.multipath.is_some() || opts.accept_multipath.unwrap_or(false) || opts.roaming.unwrap_or(false);
+    if header && opts.session_key.is_none() {
+        return Err(io::Error::other("multipath and roaming require session_key"));
+    }
+    Ok(())
+}
+
+/// Check options of UDP relays of a server, which accepts multipath clients with `accept_multipath`
+pub fn check_server_plugin_opts(opts: &PluginOpts) -> io::Result<()> {
+    if opts.multipath.is_some() {
+        return Err(io::Error::other(
+            "multipath is an option of local, server accepts multipath clients with accept_multipath=true",
+        ));
+    }
+    check_plugin_opts(opts)
+}
+
+/// Sequence numbers that `ReplayWindow` tells apart behind the newest one
+///
+/// Sequence numbers are shared by all paths, so packets of a slow path arrive far behind those of a fast one.
+const REPLAY_WINDOW: u64 = 4096;
+
+/// Sliding window of received sequence numbers, for dropping duplicates
+///
+/// Bit `seq % REPLAY_WINDOW` of the bitmap is set if `seq` was received.
+#[derive(Debug)]
+struct ReplayWindow {
+    max_seq: u64,
+    bitmap: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> ReplayWindow {
+        ReplayWindow {
+            max_seq: 0,
+            bitmap: [0; (REPLAY_WINDOW / 64) as usize],
+        }
+    }
+}
+
+impl ReplayWindow {
+    /// Returns `false` if `seq` was seen, or it is too old to tell
+    fn accept(&mut self, seq: u64) -> bool {
+        if seq > self.max_seq {
+            // Forget the sequence numbers that slide out of the window
+            if seq - self.max_seq >= REPLAY_WINDOW {
+                self.bitmap = [0; (REPLAY_WINDOW / 64) as usize];
+            } else {
+                for s in self.max_seq + 1..seq {
+                    self.set(s, false);
+                }
+            }
+            self.set(seq, true);
+            self.max_seq = seq;
+            return true;
+        }
+
+        if self.max_seq - seq >= REPLAY_WINDOW || self.get(seq) {
+            return false;
+        }
+        self.set(seq, true);
+        true
+    }
+
+    fn get(&self, seq: u64) -> bool {
+        let bit = seq % REPLAY_WINDOW;
+        self.bitmap[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, seq: u64, received: bool) {
+        let bit = seq % REPLAY_WINDOW;
+        let word = &mut self.bitmap[(bit / 64) as usize];
+        if received {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+}
+
+/// How a multipath client chooses paths for sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultipathMode {
+    /// Send every packet on all paths
+    #[default]
+    Redundant,
+    /// Send on each path in turn
+    RoundRobin,
+    /// Send on the path with the lowest round-trip time
+    LowRtt,
+}
+
+/// One path of a multipath client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSpec {
+    /// Bind to this address
+    Addr(IpAddr),
+    /// Bind to this interface
+    Interface(String),
+}
+
+impl PathSpec {
+    /// Outbound options for sockets of this path
+    pub fn apply(&self, opts: &PluginOpts) -> PluginOpts {
+        let mut opts = opts.clone();
+        match *self {
+            PathSpec::Addr(addr) => {
+                opts.outbound_bind_addr = Some(addr);
+                opts.outbound_bind_interface = None;
+            }
+            PathSpec::Interface(ref iface) => {
+                opts.outbound_bind_addr = None;
+                opts.outbound_bind_interface = Some(iface.clone());
+            }
+        }
+        opts
+    }
+}
+
+impl FromStr for PathSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PathSpec, String> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("empty path".to_owned());
+        }
+        match s.parse::<IpAddr>() {
+            Ok(addr) => Ok(PathSpec::Addr(addr)),
+            Err(..) => Ok(PathSpec::Interface(s.to_owned())),
+        }
+    }
+}
+
+impl Display for PathSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathSpec::Addr(ref addr) => addr.fmt(f),
+            PathSpec::Interface(ref iface) => f.write_str(iface),
+        }
+    }
+}
+
+/// Paths of `multipath` option of local, a `,` separated list of interfaces or addresses
+///
+/// Servers accept multipath clients with `accept_multipath` instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathList(pub Vec<PathSpec>);
+
+impl FromStr for PathList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PathList, String> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(PathList)
+    }
+}
+
+impl Display for PathList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, path) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            path.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PathList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PathList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PathList, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{net::UdpSocket, time};
+
+    use super::*;
+    use crate::config::{Config, ServerAddr};
+
+    /// XORs every byte with a key
+    #[derive(Debug)]
+    struct XorLayer(u8);
+
+    impl DatagramLayer for XorLayer {
+        fn wrap(&self, datagram: &mut Vec<u8>) {
+            datagram.iter_mut().for_each(|b| *b ^= self.0);
+        }
+
+        fn unwrap(&self, datagram: &mut Vec<u8>) -> bool {
+            self.wrap(datagram);
+            true
+        }
+    }
+
+    /// Prepends a magic, drops datagrams without it
+    #[derive(Debug)]
+    struct MagicLayer;
+
+    impl DatagramLayer for MagicLayer {
+        fn wrap(&self, datagram: &mut Vec<u8>) {
+            datagram.splice(0..0, *b"MG");
+        }
+
+        fn unwrap(&self, datagram: &mut Vec<u8>) -> bool {
+            if !datagram.starts_with(b"MG") {
+                return false;
+            }
+            datagram.drain(..2);
+            true
+        }
+    }
+
+    #[test]
+    fn datagram_layers_round_trip() {
+        let layers = DatagramLayers(vec![Arc::new(XorLayer(0x5a)), Arc::new(MagicLayer)]);
+        let datagram = b"kcp segment";
+
+        let mut buf = Vec::new();
+        let wrapped = layers.wrap(datagram, &mut buf).to_vec();
+        // The first layer is the innermost
+        assert_eq!(&wrapped[..2], b"MG");
+        assert_eq!(wrapped.len(), datagram.len() + 2);
+        assert!(wrapped[2..].iter().zip(datagram).all(|(w, d)| w ^ 0x5a == *d));
+
+        assert_eq!(layers.unwrap(&wrapped, &mut buf), Some(&datagram[..]));
+        assert_eq!(layers.unwrap(&wrapped[1..], &mut buf), None);
+
+        // Without layers, datagrams are not copied
+        let layers = DatagramLayers::default();
+        assert!(std::ptr::eq(layers.wrap(datagram, &mut buf), &datagram[..]));
+        assert_eq!(layers.unwrap(datagram, &mut buf), Some(&datagram[..]));
+    }
+
+    fn segment(conv: u32, cmd: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&conv.to_le_bytes());
+        buf.push(cmd);
+        buf.push(0);
+        buf.extend_from_slice(&128u16.to_le_bytes());
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn opens_session() {
+        assert!(kcp_opens_session(&segment(7, KCP_CMD_PUSH, b"hello")));
+
+        let mut packet = segment(7, KCP_CMD_PUSH, b"hello");
+        packet.extend(segment(7, KCP_CMD_ACK, &[]));
+        assert!(kcp_opens_session(&packet));
+    }
+
+    #[test]
+    fn does_not_open_session() {
+        // Too short, or not starting with data
+        assert!(!kcp_opens_session(&[0; KCP_OVERHEAD - 1]));
+        assert!(!kcp_opens_session(&segment(7, KCP_CMD_ACK, &[])));
+
+        // Length beyond the packet
+        let mut packet = segment(7, KCP_CMD_PUSH, b"hello");
+        packet.pop();
+        assert!(!kcp_opens_session(&packet));
+
+        // Length that overflows
+        let mut packet = segment(7, KCP_CMD_PUSH, &[]);
+        packet[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(!kcp_opens_session(&packet));
+
+        // Invalid command, or another conversation, in a later segment
+        let mut packet = segment(7, KCP_CMD_PUSH, b"hello");
+        packet.extend(segment(7, 0, &[]));
+        assert!(!kcp_opens_session(&packet));
+        let mut packet = segment(7, KCP_CMD_PUSH, b"hello");
+        packet.extend(segment(8, KCP_CMD_ACK, &[]));
+        assert!(!kcp_opens_session(&packet));
+    }
+
+    fn header_config(opts: &str) -> HeaderConfig {
+        HeaderConfig::for_client(&PluginOpts::from_str(opts).unwrap()).unwrap()
+    }
+
+    fn data_packet(config: &HeaderConfig, session_id: u64, seq: u64, payload: &[u8]) -> Vec<u8> {
+        let header = PathHeader {
+            packet_type: PACKET_DATA,
+            session_id,
+            seq,
+        };
+        let mut buf = Vec::new();
+        config.encode(&header, payload, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn header_authenticated() {
+        let config = header_config("roaming=true&session_key=k");
+        let packet = data_packet(&config, 42, 7, b"kcp");
+        assert_eq!(packet.len(), PATH_HEADER_SIZE + 3 + PATH_TAG_SIZE);
+
+        let (header, payload) = config.decode(&packet).unwrap();
+        assert_eq!(
+            (header.packet_type, header.session_id, header.seq),
+            (PACKET_DATA, 42, 7)
+        );
+        assert_eq!(payload, b"kcp");
+    }
+
+    #[test]
+    fn header_not_authenticated() {
+        let config = header_config("roaming=true&session_key=k");
+        let packet = data_packet(&config, 42, 7, b"kcp");
+
+        // Wrong tag, or any bit of the header or payload changed
+        let mut tampered = packet.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(config.decode(&tampered).is_none());
+        for i in [0, 1, 9, PATH_HEADER_SIZE] {
+            let mut tampered = packet.clone();
+            tampered[i] ^= 1;
+            assert!(config.decode(&tampered).is_none(), "byte {}", i);
+        }
+
+        // Wrong session key, or none
+        let other = header_config("roaming=true&session_key=other");
+        assert!(other.decode(&packet).is_none());
+        assert!(config.decode(&data_packet(&other, 42, 7, b"kcp")).is_none());
+        assert!(config.decode(&packet[PATH_TAG_SIZE..]).is_none());
+
+        // Truncated, down to a tag without a header
+        for len in [
+            0,
+            PATH_TAG_SIZE - 1,
+            PATH_TAG_SIZE,
+            PATH_HEADER_SIZE + PATH_TAG_SIZE - 1,
+        ] {
+            assert!(config.decode(&packet[..len]).is_none(), "length {}", len);
+        }
+    }
+
+    /// Address that the next datagram to `socket` comes from, if one comes
+    async fn recv_from(socket: &UdpSocket) -> Option<SocketAddr> {
+        let mut buf = [0u8; 2048];
+        match time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await {
+            Ok(r) => Some(r.unwrap().1),
+            Err(..) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn roaming_after_authentication() {
+        let plugin_opts = PluginOpts::from_str("roaming=true&session_key=k").unwrap();
+        let header = HeaderConfig::for_client(&plugin_opts).unwrap();
+        let config = Config {
+            local_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: None,
+        };
+
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = "127.0.0.1:0".parse().unwrap();
+        let relay = server::UdpServerRelay::bind(&[listen_addr], target.local_addr().unwrap(), &config)
+            .await
+            .unwrap();
+        let relay_addr = relay.local_addrs()[0];
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let roamed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let segment = segment(7, KCP_CMD_PUSH, b"hello");
+
+        let first = data_packet(&header, 42, 1, &segment);
+        client.send_to(&first, relay_addr).await.unwrap();
+        let session_addr = recv_from(&target).await.unwrap();
+        target.send_to(&segment, session_addr).await.unwrap();
+        assert_eq!(recv_from(&client).await, Some(relay_addr));
+
+        // Forged and replayed packets from other addresses do not move the session
+        let forged = data_packet(&header_config("roaming=true&session_key=other"), 42, 2, &segment);
+        attacker.send_to(&forged, relay_addr).await.unwrap();
+        attacker.send_to(&first, relay_addr).await.unwrap();
+        assert_eq!(recv_from(&target).await, None);
+        target.send_to(&segment, session_addr).await.unwrap();
+        assert!(recv_from(&client).await.is_some());
+        assert_eq!(recv_from(&attacker).await, None);
+
+        // An authenticated packet with a fresh sequence number does
+        roamed
+            .send_to(&data_packet(&header, 42, 2, &segment), relay_addr)
+            .await
+            .unwrap();
+        assert_eq!(recv_from(&target).await, Some(session_addr));
+        target.send_to(&segment, session_addr).await.unwrap();
+        assert!(recv_from(&roamed).await.is_some());
+        assert_eq!(recv_from(&client).await, None);
+    }
+
+    #[test]
+    fn replay_duplicates_and_reordering() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(1));
+        assert!(!window.accept(1));
+
+        // Late packets are accepted once, in any order
+        assert!(window.accept(5));
+        assert!(window.accept(3));
+        assert!(window.accept(2));
+        assert!(!window.accept(3));
+        assert!(window.accept(4));
+        assert!(!window.accept(5));
+    }
+
+    #[test]
+    fn replay_window_edges() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(10_000));
+
+        // A slow path thousands of packets behind
+        let oldest = 10_000 - (REPLAY_WINDOW - 1);
+        assert!(window.accept(oldest));
+        assert!(!window.accept(oldest));
+        assert!(!window.accept(oldest - 1));
+
+        // Sliding forward forgets what slid out, and nothing of the new sequence numbers is seen yet
+        assert!(window.accept(10_000 + REPLAY_WINDOW - 1));
+        assert!(!window.accept(10_000 - 1));
+        assert!(window.accept(10_000 + 1));
+        assert!(window.accept(10_000 + REPLAY_WINDOW - 2));
+        assert!(!window.accept(10_000));
+
+        // A jump beyond the window clears it
+        assert!(window.accept(100_000));
+        assert!(window.accept(100_000 - 1));
+        assert!(!window.accept(100_000 - REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn multipath_options() {
+        let opts = |s: &str| PluginOpts::from_str(s).unwrap();
+
+        assert!(check_plugin_opts(&opts("multipath=wlan0,rmnet0")).is_err());
+        assert!(check_plugin_opts(&opts("multipath=wlan0,rmnet0&session_key=k")).is_ok());
+        assert!(check_server_plugin_opts(&opts("accept_multipath=true")).is_err());
+        assert!(check_server_plugin_opts(&opts("accept_multipath=true&session_key=k")).is_ok());
+        assert!(check_server_plugin_opts(&opts("multipath=true&session_key=k")).is_err());
+
+        assert!(HeaderConfig::for_server(&opts("accept_multipath=true&session_key=k")).is_some());
+        assert!(HeaderConfig::for_server(&opts("accept_multipath=false")).is_none());
+    }
+}
@@ -0,0 +1,605 @@
+//! Server side UDP relay
+//!
+//! ```plain
+//!           UDP                                 Loopback
+//! CLIENT ----------> [UdpServerRelay] <-------------------> [KcpListener]
+//!        PORT RANGE
+//! ```
+//!
+//! Each KCP session, identified by the client's IP and its conversation ID, is relayed through a loopback socket of
+//! its own. Replies are sent from the port that the client sent to most recently, to the address it sent from, so the
+//! session survives when the client hops between ports.
+//!
+//! Datagrams from clients that the firewall does not permit are dropped before anything else. A new session is only
+//! relayed if its first packet is a well-formed KCP data packet, while fewer than `max_peers` sessions are, and if the
+//! session limits of server admit it. Its admission is handed over when the KcpListener accepts it.
+//!
+//! Sessions of multipath and roaming clients are identified by the session ID in the header instead, so they may
+//! arrive from any address. Duplicates are dropped, and replies are sent on all paths that the client sent from
+//! recently if it sends redundantly, otherwise on the latest one.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, info, trace};
+use tokio::{
+    net::UdpSocket,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_kcp::KcpConfig;
+
+use super::{
+    kcp_conv, kcp_opens_session, stats::KcpStats, DatagramLayers, HeaderConfig, PathHeader, ReplayWindow, PACKET_DATA,
+    PACKET_DATA_REDUNDANT, PACKET_PROBE, PACKET_PROBE_REPLY, RECV_ERROR_BACKOFF,
+};
+use crate::{
+    config::Config,
+    firewall::Firewall,
+    limit::{Limits, SessionPermit},
+};
+
+/// Paths that the client sent nothing from in this duration are not replied on
+const PATH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default of `max_peers`
+pub(crate) const DEFAULT_MAX_PEERS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum PeerKey {
+    /// Client's IP and KCP conversation ID
+    Conv(IpAddr, u32),
+    /// Session ID of a multipath or roaming client
+    Session(u64),
+}
+
+struct PeerPath {
+    addr: SocketAddr,
+    socket_index: usize,
+    last_seen: Instant,
+}
+
+struct PeerRoute {
+    paths: Vec<PeerPath>,
+    /// Index of the path that the client sent from most recently
+    latest: usize,
+    /// Client sends on all paths, so replies are sent on all of them too
+    redundant: bool,
+    replay: ReplayWindow,
+    send_seq: u64,
+    last_seen: Instant,
+}
+
+impl PeerRoute {
+    /// Record a packet from `addr` to socket `socket_index`, returns `true` if the address changed
+    fn replace(&mut self, addr: SocketAddr, socket_index: usize) -> bool {
+        let now = Instant::now();
+        let changed = self.paths[0].addr != addr;
+        self.last_seen = now;
+        self.paths[0] = PeerPath {
+            addr,
+            socket_index,
+            last_seen: now,
+        };
+        changed
+    }
+
+    /// Record an authenticated packet with a fresh sequence number from `addr` to socket `socket_index`, making it the
+    /// latest path if `latest` is set. Returns `true` if the latest address changed.
+    fn update(&mut self, addr: SocketAddr, socket_index: usize, latest: bool) -> bool {
+        let now = Instant::now();
+        let latest_addr = self.paths[self.latest].addr;
+        self.last_seen = now;
+
+        match self.paths.iter().position(|p| p.addr == addr) {
+            Some(index) => {
+                let path = &mut self.paths[index];
+                path.socket_index = socket_index;
+                path.last_seen = now;
+            }
+            None => self.paths.push(PeerPath {
+                addr,
+                socket_index,
+                last_seen: now,
+            }),
+        }
+
+        self.paths.retain(|p| now - p.last_seen < PATH_TIMEOUT);
+
+        let new_latest = if latest { addr } else { latest_addr };
+        self.latest = self.paths.iter().position(|p| p.addr == new_latest).unwrap_or(0);
+
+        self.paths[self.latest].addr != latest_addr
+    }
+
+    /// Record a packet from `addr` that may be replayed, only refreshing a path that is known already
+    fn refresh(&mut self, addr: SocketAddr, socket_index: usize) {
+        if let Some(path) = self.paths.iter_mut().find(|p| p.addr == addr) {
+            path.socket_index = socket_index;
+            path.last_seen = Instant::now();
+        }
+    }
+}
+
+struct Peer {
+    socket: Arc<UdpSocket>,
+    relay_addr: SocketAddr,
+    session_id: u64,
+    route: Mutex<PeerRoute>,
+    stats: Option<Arc<KcpStats>>,
+    /// Admission of the session, until the KcpListener accepts it
+    permit: Mutex<Option<SessionPermit>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// UDP relay in front of a KcpListener
+pub struct UdpServerRelay {
+    sockets: Vec<Arc<UdpSocket>>,
+    target: SocketAddr,
+    expire: Duration,
+    header: Option<HeaderConfig>,
+    layers: DatagramLayers,
+    kcp_stats: Option<KcpConfig>,
+    firewall: Option<Arc<Firewall>>,
+    limits: Option<Arc<Limits>>,
+    max_peers: usize,
+    peers: Mutex<HashMap<PeerKey, Arc<Peer>>>,
+    peer_addrs: Mutex<HashMap<SocketAddr, SocketAddr>>,
+}
+
+impl UdpServerRelay {
+    /// Listen on `addrs`, relaying to the KcpListener on `target`, which has the parameters of `config`. Idle sessions
+    /// are forgotten after `session_expire`.
+    ///
+    /// Datagrams carry a header for multipath and roaming clients, and go through the datagram layers. Statistics of
+    /// sessions are tracked with `kcp_stats`. Datagrams of clients that the firewall does not permit are dropped, at
+    /// most `max_peers` sessions are relayed, and new sessions are admitted by the limits of `config`.
+    pub async fn bind(addrs: &[SocketAddr], target: SocketAddr, config: &Config) -> io::Result<Arc<UdpServerRelay>> {
+        let opts = &config.plugin_opts;
+
+        let mut sockets = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            sockets.push(Arc::new(UdpSocket::bind(addr).await?));
+        }
+
+        let relay = Arc::new(UdpServerRelay {
+            sockets,
+            target,
+            expire: config.kcp_config.session_expire,
+            header: HeaderConfig::for_server(opts),
+            layers: config.datagram_layers.clone(),
+            kcp_stats: opts.kcp_stats.unwrap_or(false).then_some(config.kcp_config),
+            firewall: config.firewall.clone(),
+            limits: config.limits.clone().filter(|l| l.limits_sessions()),
+            max_peers: opts.max_peers.unwrap_or(DEFAULT_MAX_PEERS),
+            peers: Mutex::new(HashMap::new()),
+            peer_addrs: Mutex::new(HashMap::new()),
+        });
+
+        for index in 0..relay.sockets.len() {
+            let relay = relay.clone();
+            tokio::spawn(async move { relay.recv_loop(index).await });
+        }
+
+        {
+            let relay = relay.clone();
+            tokio::spawn(async move { relay.cleanup_loop().await });
+        }
+
+        Ok(relay)
+    }
+
+    /// Addresses that the relay listens on
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().filter_map(|s| s.local_addr().ok()).collect()
+    }
+
+    /// Client's address of the session that the KcpListener sees as `relay_addr`
+    pub fn peer_addr(&self, relay_addr: &SocketAddr) -> Option<SocketAddr> {
+        self.peer_addrs.lock().unwrap().get(relay_addr).cloned()
+    }
+
+    /// Statistics of the session that the KcpListener sees as `relay_addr`, if `kcp_stats` is set
+    pub fn kcp_stats(&self, relay_addr: &SocketAddr) -> Option<Arc<KcpStats>> {
+        let peers = self.peers.lock().unwrap();
+        let peer = peers.values().find(|p| p.relay_addr == *relay_addr)?;
+        peer.stats.clone()
+    }
+
+    /// Admission of the session that the KcpListener sees as `relay_addr`, if the relay has admitted it
+    pub fn take_session_permit(&self, relay_addr: &SocketAddr) -> Option<SessionPermit> {
+        let peers = self.peers.lock().unwrap();
+        let peer = peers.values().find(|p| p.relay_addr == *relay_addr)?;
+        let permit = peer.permit.lock().unwrap().take();
+        permit
+    }
+
+    /// Peer of `key`, created for `packet` if it opens a session and there is room for it
+    fn get_or_create_peer(
+        self: &Arc<Self>,
+        key: PeerKey,
+        addr: SocketAddr,
+        socket_index: usize,
+        packet: &[u8],
+    ) -> io::Result<Option<Arc<Peer>>> {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get(&key) {
+            return Ok(Some(peer.clone()));
+        }
+
+        if !kcp_opens_session(packet) {
+            trace!(
+                "udp relay ignored {} bytes from {}, not a new session",
+                packet.len(),
+                addr
+            );
+            return Ok(None);
+        }
+        if peers.len() >= self.max_peers {
+            debug!(
+                "udp relay refused session of {}, {} sessions relayed",
+                addr,
+                peers.len()
+            );
+            return Ok(None);
+        }
+        let permit = match self.limits {
+            Some(ref limits) => match limits.admit_session(addr.ip()) {
+                Some(p) => Some(p),
+                None => {
+                    debug!("udp relay refused session of {}, too many sessions", addr);
+                    return Ok(None);
+                }
+            },
+            None => None,
+        };
+
+        let bind_addr = match self.target {
+            SocketAddr::V4(..) => "127.0.0.1:0",
+            SocketAddr::V6(..) => "[::1]:0",
+        };
+        let socket = std::net::UdpSocket::bind(bind_addr)?;
+        socket.connect(self.target)?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let relay_addr = socket.local_addr()?;
+
+        let now = Instant::now();
+        let peer = Arc::new(Peer {
+            socket,
+            relay_addr,
+            session_id: match key {
+                PeerKey::Session(id) => id,
+                PeerKey::Conv(..) => 0,
+            },
+            route: Mutex::new(PeerRoute {
+                paths: vec![PeerPath {
+                    addr,
+                    socket_index,
+                    last_seen: now,
+                }],
+                latest: 0,
+                redundant: false,
+                replay: ReplayWindow::default(),
+                send_seq: 0,
+                last_seen: now,
+            }),
+            stats: self.kcp_stats.as_ref().map(|config| Arc::new(KcpStats::new(config))),
+            permit: Mutex::new(permit),
+            task: Mutex::new(None),
+        });
+
+        let task = {
+            let relay = self.clone();
+            let peer = peer.clone();
+            tokio::spawn(async move { relay.reply_loop(peer).await })
+        };
+        *peer.task.lock().unwrap() = Some(task);
+
+        peers.insert(key, peer.clone());
+        self.peer_addrs.lock().unwrap().insert(relay_addr, addr);
+
+        debug!("udp relay new session {} {:?} via {}", addr, key, relay_addr);
+
+        Ok(Some(peer))
+    }
+
+    async fn recv_loop(self: Arc<Self>, index: usize) {
+        let socket = self.sockets[index].clone();
+        let mut buf = vec![0u8; 65536];
+        let mut send_buf = Vec::new();
+        let mut layer_buf = Vec::new();
+        let mut reply_buf = Vec::new();
+
+        loop {
+            let (n, addr) = match socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(err) => {
+                    debug!("udp relay recv error: {}", err);
+                    time::sleep(RECV_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            if self.firewall.as_ref().is_some_and(|f| !f.permits(addr.ip())) {
+                trace!("udp relay dropped {} bytes from {}, not permitted", n, addr);
+                continue;
+            }
+
+            let packet = match self.layers.unwrap(&buf[..n], &mut layer_buf) {
+                Some(p) => p,
+                None => {
+                    trace!("udp relay dropped {} bytes from {}", n, addr);
+                    continue;
+                }
+            };
+            let (peer, addr_changed, payload) = if let Some(ref header_config) = self.header {
+                let (header, payload) = match header_config.decode(packet) {
+                    Some(h) => h,
+                    None => {
+                        trace!("udp relay ignored {} bytes from {}", n, addr);
+                        continue;
+                    }
+                };
+                let key = PeerKey::Session(header.session_id);
+
+                match header.packet_type {
+                    PACKET_DATA | PACKET_DATA_REDUNDANT if kcp_conv(payload).is_some() => {
+                        let peer = match self.get_or_create_peer(key, addr, index, payload) {
+                            Ok(Some(p)) => p,
+                            Ok(None) => continue,
+                            Err(err) => {
+                                debug!("udp relay failed to create session for {}, error: {}", addr, err);
+                                continue;
+                            }
+                        };
+
+                        let addr_changed = {
+                            let mut route = peer.route.lock().unwrap();
+                            if !route.replay.accept(header.seq) {
+                                route.refresh(addr, index);
+                                continue;
+                            }
+                            route.redundant = header.packet_type == PACKET_DATA_REDUNDANT;
+                            route.update(addr, index, true)
+                        };
+
+                        (peer, addr_changed, payload)
+                    }
+                    PACKET_PROBE => {
+                        let peer = self.peers.lock().unwrap().get(&key).cloned();
+                        if let Some(peer) = peer {
+                            let mut route = peer.route.lock().unwrap();
+                            if route.replay.accept(header.seq) {
+                                route.update(addr, index, false);
+                            } else {
+                                route.refresh(addr, index);
+                            }
+                        }
+
+                        let reply = PathHeader {
+                            packet_type: PACKET_PROBE_REPLY,
+                            ..header
+                        };
+                        header_config.encode(&reply, payload, &mut send_buf);
+                        let reply = self.layers.wrap(&send_buf, &mut reply_buf);
+                        if let Err(err) = socket.send_to(reply, addr).await {
+                            trace!("udp relay probe reply to {} error: {}", addr, err);
+                        }
+                        continue;
+                    }
+                    _ => {
+                        trace!("udp relay ignored {} bytes from {}", n, addr);
+                        continue;
+                    }
+                }
+            } else {
+                let conv = match kcp_conv(packet) {
+                    Some(c) => c,
+                    None => {
+                        trace!("udp relay ignored {} bytes from {}", n, addr);
+                        continue;
+                    }
+                };
+
+                let peer = match self.get_or_create_peer(PeerKey::Conv(addr.ip(), conv), addr, index, packet) {
+                    Ok(Some(p)) => p,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        debug!("udp relay failed to create session for {}, error: {}", addr, err);
+                        continue;
+                    }
+                };
+
+                let addr_changed = peer.route.lock().unwrap().replace(addr, index);
+                (peer, addr_changed, packet)
+            };
+
+            if addr_changed {
+                trace!("udp relay session via {} moved to {}", peer.relay_addr, addr);
+                self.peer_addrs.lock().unwrap().insert(peer.relay_addr, addr);
+            }
+
+            if let Some(ref stats) = peer.stats {
+                stats.on_recv(payload);
+            }
+            if let Err(err) = peer.socket.send(payload).await {
+                debug!("udp relay send to kcp error: {}", err);
+            }
+        }
+    }
+
+    async fn reply_loop(self: Arc<Self>, peer: Arc<Peer>) {
+        let mut buf = vec![0u8; 65536];
+        let mut send_buf = Vec::new();
+        let mut layer_buf = Vec::new();
+        let mut targets = Vec::new();
+
+        loop {
+            let n = match peer.socket.recv(&mut buf).await {
+                Ok(n) => n,
+                Err(err) => {
+                    debug!("udp relay recv from kcp error: {}", err);
+                    break;
+                }
+            };
+
+            if let Some(ref stats) = peer.stats {
+                stats.on_send(&buf[..n]);
+            }
+
+            targets.clear();
+            let packet = if let Some(ref header_config) = self.header {
+                let seq = {
+                    let mut route = peer.route.lock().unwrap();
+                    route.send_seq += 1;
+
+                    let now = Instant::now();
+                    if route.redundant {
+                        for path in route.paths.iter().filter(|p| now - p.last_seen < PATH_TIMEOUT) {
+                            targets.push((path.addr, path.socket_index));
+                        }
+                    }
+                    if targets.is_empty() {
+                        let path = &route.paths[route.latest];
+                        targets.push((path.addr, path.socket_index));
+                    }
+
+                    route.send_seq
+                };
+
+                let header = PathHeader {
+                    packet_type: PACKET_DATA,
+                    session_id: peer.session_id,
+                    seq,
+                };
+                header_config.encode(&header, &buf[..n], &mut send_buf);
+                &send_buf[..]
+            } else {
+                let route = peer.route.lock().unwrap();
+                let path = &route.paths[route.latest];
+                targets.push((path.addr, path.socket_index));
+                &buf[..n]
+            };
+            let packet = self.layers.wrap(packet, &mut layer_buf);
+
+            for &(addr, index) in &targets {
+                if let Err(err) = self.sockets[index].send_to(packet, addr).await {
+                    debug!("udp relay send to {} error: {}", addr, err);
+                }
+            }
+        }
+    }
+
+    async fn cleanup_loop(self: Arc<Self>) {
+        let mut interval = time::interval(Duration::from_secs(10));
+
+        loop {
+            interval.tick().await;
+
+            let now = Instant::now();
+            let expired: Vec<Arc<Peer>> = {
+                let mut peers = self.peers.lock().unwrap();
+                let keys: Vec<PeerKey> = peers
+                    .iter()
+                    .filter(|(_, p)| now - p.route.lock().unwrap().last_seen >= self.expire)
+                    .map(|(k, _)| *k)
+                    .collect();
+                keys.iter().filter_map(|k| peers.remove(k)).collect()
+            };
+
+            for peer in expired {
+                if let Some(task) = peer.task.lock().unwrap().take() {
+                    task.abort();
+                }
+                self.peer_addrs.lock().unwrap().remove(&peer.relay_addr);
+                trace!("udp relay session via {} expired", peer.relay_addr);
+
+                if let Some(ref stats) = peer.stats {
+                    let route = peer.route.lock().unwrap();
+                    info!(
+                        "kcp session of {} closed, {}",
+                        route.paths[route.latest].addr,
+                        stats.snapshot()
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::KCP_CMD_PUSH, *};
+    use crate::{config::ServerAddr, opt::PluginOpts, udp::DatagramLayers};
+
+    fn segment(conv: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&conv.to_le_bytes());
+        buf.push(KCP_CMD_PUSH);
+        buf.push(0);
+        buf.extend_from_slice(&128u16.to_le_bytes());
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(b"hello");
+        buf
+    }
+
+    #[tokio::test]
+    async fn sessions_over_limit_are_dropped() {
+        let plugin_opts = PluginOpts::from_str("max_sessions=1").unwrap();
+        let limits = Arc::new(Limits::from_plugin_opts(&plugin_opts));
+        let config = Config {
+            local_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            remote_addr: ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            kcp_config: plugin_opts.build_kcp_config(),
+            plugin_opts,
+            transport: None,
+            datagram_layers: DatagramLayers::default(),
+            firewall: None,
+            limits: Some(limits.clone()),
+        };
+
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = "127.0.0.1:0".parse().unwrap();
+        let relay = UdpServerRelay::bind(&[listen_addr], target.local_addr().unwrap(), &config)
+            .await
+            .unwrap();
+        let relay_addr = relay.local_addrs()[0];
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1024];
+
+        client.send_to(&segment(1), relay_addr).await.unwrap();
+        let (n, from) = time::timeout(Duration::from_secs(5), target.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], &segment(1)[..]);
+
+        // Another session of the client is not relayed while the first one holds its admission
+        client.send_to(&segment(2), relay_addr).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(200), target.recv_from(&mut buf))
+            .await
+            .is_err());
+        assert_eq!(limits.refused().sessions, 1);
+
+        // Until the KcpListener accepts the first one and its admission drops
+        let permit = relay.take_session_permit(&from);
+        assert!(permit.is_some());
+        assert!(relay.take_session_permit(&from).is_none());
+        drop(permit);
+
+        client.send_to(&segment(2), relay_addr).await.unwrap();
+        let (n, _) = time::timeout(Duration::from_secs(5), target.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], &segment(2)[..]);
+    }
+}
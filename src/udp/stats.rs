@@ -0,0 +1,472 @@
+//! Statistics of KCP sessions
+//!
+//! UDP relays see every datagram of the KCP sessions they carry, and read the KCP segments in them:
+//!
+//! ```plain
+//! +------+-----+-----+-----+----+----+-----+-----+------+
+//! | CONV | CMD | FRG | WND | TS | SN | UNA | LEN | DATA |
+//! +------+-----+-----+-----+----+----+-----+-----+------+
+//! |  4   |  1  |  1  |  2  | 4  | 4  |  4  |  4  | LEN  |
+//! +------+-----+-----+-----+----+----+-----+-----+------+
+//! ```
+//!
+//! Round-trip times are measured from a data segment to its ACK, leaving out retransmitted segments, and smoothed the
+//! way KCP does. A retransmission sooner than RTO after the last transmission is counted as a fast retransmission.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tokio_kcp::KcpConfig;
+
+use super::{KCP_CMD_ACK, KCP_CMD_PUSH, KCP_OVERHEAD};
+
+/// Initial RTO of KCP, in milliseconds
+const KCP_RTO_DEF: u32 = 200;
+/// Minimum RTO of KCP, in milliseconds
+const KCP_RTO_MIN: u32 = 100;
+/// Minimum RTO of KCP in nodelay mode, in milliseconds
+const KCP_RTO_NDL: u32 = 30;
+/// Maximum RTO of KCP, in milliseconds
+const KCP_RTO_MAX: u32 = 60000;
+
+/// Segments received this far ahead of the next expected one are not remembered
+const MAX_RECV_AHEAD: u32 = 65536;
+
+struct SentSegment {
+    first_sent: Instant,
+    last_sent: Instant,
+    transmissions: u32,
+}
+
+struct StatsState {
+    started: Instant,
+    packets_sent: u64,
+    packets_received: u64,
+    segments_sent: u64,
+    segments_received: u64,
+    retransmits: u64,
+    fast_retransmits: u64,
+    duplicates: u64,
+    out_of_order: u64,
+    srtt: u32,
+    rttvar: u32,
+    rto: u32,
+    /// Data segments sent and not acknowledged yet
+    unacked: BTreeMap<u32, SentSegment>,
+    snd_nxt: u32,
+    remote_una: u32,
+    rcv_nxt: u32,
+    received_ahead: BTreeSet<u32>,
+    remote_window: u16,
+    recv_free: u16,
+    min_recv_free: u16,
+    max_in_flight: u32,
+}
+
+/// Statistics of one KCP session, fed with its datagrams
+pub struct KcpStats {
+    interval: u32,
+    min_rto: u32,
+    send_window: u16,
+    recv_window: u16,
+    state: Mutex<StatsState>,
+}
+
+impl fmt::Debug for KcpStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KcpStats").field("snapshot", &self.snapshot()).finish()
+    }
+}
+
+/// Segment header fields that statistics need
+struct Segment {
+    cmd: u8,
+    wnd: u16,
+    sn: u32,
+    una: u32,
+}
+
+/// Headers of the KCP segments in `datagram`
+fn segments(mut datagram: &[u8]) -> impl Iterator<Item = Segment> + '_ {
+    std::iter::from_fn(move || {
+        if datagram.len() < KCP_OVERHEAD {
+            return None;
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes([datagram[i], datagram[i + 1], datagram[i + 2], datagram[i + 3]]);
+        let segment = Segment {
+            cmd: datagram[4],
+            wnd: u16::from_le_bytes([datagram[6], datagram[7]]),
+            sn: u32_at(12),
+            una: u32_at(16),
+        };
+        let len = u32_at(20) as usize;
+
+        datagram = KCP_OVERHEAD
+            .checked_add(len)
+            .and_then(|end| datagram.get(end..))
+            .unwrap_or(&[]);
+        Some(segment)
+    })
+}
+
+/// `a - b` of sequence numbers, which wrap around
+fn seq_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+impl KcpStats {
+    /// Statistics of a session with parameters `config`
+    pub fn new(config: &KcpConfig) -> KcpStats {
+        let (send_window, recv_window) = config.wnd_size;
+        KcpStats {
+            interval: config.nodelay.interval.max(0) as u32,
+            min_rto: if config.nodelay.nodelay {
+                KCP_RTO_NDL
+            } else {
+                KCP_RTO_MIN
+            },
+            send_window,
+            recv_window,
+            state: Mutex::new(StatsState {
+                started: Instant::now(),
+                packets_sent: 0,
+                packets_received: 0,
+                segments_sent: 0,
+                segments_received: 0,
+                retransmits: 0,
+                fast_retransmits: 0,
+                duplicates: 0,
+                out_of_order: 0,
+                srtt: 0,
+                rttvar: 0,
+                rto: KCP_RTO_DEF,
+                unacked: BTreeMap::new(),
+                snd_nxt: 0,
+                remote_una: 0,
+                rcv_nxt: 0,
+                received_ahead: BTreeSet::new(),
+                remote_window: 0,
+                recv_free: recv_window,
+                min_recv_free: recv_window,
+                max_in_flight: 0,
+            }),
+        }
+    }
+
+    /// Record a datagram sent by the session
+    pub fn on_send(&self, datagram: &[u8]) {
+        let now = Instant::now();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.packets_sent += 1;
+
+        for segment in segments(datagram) {
+            state.recv_free = segment.wnd;
+            state.min_recv_free = state.min_recv_free.min(segment.wnd);
+
+            if segment.cmd != KCP_CMD_PUSH {
+                continue;
+            }
+            state.segments_sent += 1;
+
+            let rto = Duration::from_millis(state.rto as u64);
+            match state.unacked.get_mut(&segment.sn) {
+                Some(sent) => {
+                    let fast = now - sent.last_sent < rto;
+                    sent.last_sent = now;
+                    sent.transmissions += 1;
+
+                    state.retransmits += 1;
+                    if fast {
+                        state.fast_retransmits += 1;
+                    }
+                }
+                // Acknowledged already, the ACK was lost
+                None if seq_diff(segment.sn, state.snd_nxt) < 0 => state.retransmits += 1,
+                None => {
+                    state.unacked.insert(
+                        segment.sn,
+                        SentSegment {
+                            first_sent: now,
+                            last_sent: now,
+                            transmissions: 1,
+                        },
+                    );
+                    state.snd_nxt = segment.sn.wrapping_add(1);
+                }
+            }
+
+            let in_flight = seq_diff(state.snd_nxt, state.remote_una).max(0) as u32;
+            state.max_in_flight = state.max_in_flight.max(in_flight);
+        }
+    }
+
+    /// Record a datagram received by the session
+    pub fn on_recv(&self, datagram: &[u8]) {
+        let now = Instant::now();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.packets_received += 1;
+
+        for segment in segments(datagram) {
+            state.remote_window = segment.wnd;
+
+            match segment.cmd {
+                KCP_CMD_ACK => {
+                    if let Some(sent) = state.unacked.remove(&segment.sn) {
+                        if sent.transmissions == 1 {
+                            let rtt = (now - sent.first_sent).as_millis() as u32;
+                            self.update_rtt(state, rtt);
+                        }
+                    }
+                }
+                KCP_CMD_PUSH => {
+                    state.segments_received += 1;
+
+                    let sn = segment.sn;
+                    let ahead = seq_diff(sn, state.rcv_nxt);
+                    if ahead < 0 || state.received_ahead.contains(&sn) {
+                        state.duplicates += 1;
+                    } else if ahead > 0 {
+                        state.out_of_order += 1;
+                        if (ahead as u32) < MAX_RECV_AHEAD {
+                            state.received_ahead.insert(sn);
+                        }
+                    } else {
+                        state.rcv_nxt = state.rcv_nxt.wrapping_add(1);
+                        while state.received_ahead.remove(&state.rcv_nxt) {
+                            state.rcv_nxt = state.rcv_nxt.wrapping_add(1);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            // After the ACK, which usually carries an UNA past the segment it acknowledges
+            if seq_diff(segment.una, state.remote_una) > 0 {
+                state.remote_una = segment.una;
+                state.unacked.retain(|&sn, _| seq_diff(sn, segment.una) >= 0);
+            }
+        }
+    }
+
+    /// Same as `ikcp_update_ack`
+    fn update_rtt(&self, state: &mut StatsState, rtt: u32) {
+        if state.srtt == 0 {
+            state.srtt = rtt.max(1);
+            state.rttvar = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(state.srtt);
+            state.rttvar = (3 * state.rttvar + delta) / 4;
+            state.srtt = ((7 * state.srtt + rtt) / 8).max(1);
+        }
+        let rto = state.srtt + self.interval.max(4 * state.rttvar);
+        state.rto = rto.clamp(self.min_rto, KCP_RTO_MAX);
+    }
+
+    pub fn snapshot(&self) -> KcpStatsSnapshot {
+        let state = self.state.lock().unwrap();
+        KcpStatsSnapshot {
+            duration: state.started.elapsed().as_secs(),
+            srtt: state.srtt,
+            rttvar: state.rttvar,
+            rto: state.rto,
+            packets_sent: state.packets_sent,
+            packets_received: state.packets_received,
+            segments_sent: state.segments_sent,
+            segments_received: state.segments_received,
+            retransmits: state.retransmits,
+            fast_retransmits: state.fast_retransmits,
+            duplicates: state.duplicates,
+            out_of_order: state.out_of_order,
+            send_window: self.send_window,
+            remote_window: state.remote_window,
+            in_flight: seq_diff(state.snd_nxt, state.remote_una).max(0) as u32,
+            max_in_flight: state.max_in_flight,
+            recv_window: self.recv_window,
+            recv_free: state.recv_free,
+            min_recv_free: state.min_recv_free,
+        }
+    }
+}
+
+/// Statistics of a KCP session at some moment. Times are in milliseconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KcpStatsSnapshot {
+    /// Seconds since the session started
+    pub duration: u64,
+    /// Smoothed round-trip time
+    pub srtt: u32,
+    /// Round-trip time variation
+    pub rttvar: u32,
+    /// Retransmission timeout
+    pub rto: u32,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Data segments sent, including retransmissions
+    pub segments_sent: u64,
+    /// Data segments received, including duplicates
+    pub segments_received: u64,
+    pub retransmits: u64,
+    /// Retransmissions sooner than RTO, for duplicate ACKs
+    pub fast_retransmits: u64,
+    /// Data segments that were received already
+    pub duplicates: u64,
+    /// Data segments received before some earlier ones
+    pub out_of_order: u64,
+    /// Send window of the session, in segments
+    pub send_window: u16,
+    /// Receive window advertised by the peer
+    pub remote_window: u16,
+    /// Data segments sent and not acknowledged
+    pub in_flight: u32,
+    pub max_in_flight: u32,
+    /// Receive window of the session, in segments
+    pub recv_window: u16,
+    /// Free receive window advertised to the peer
+    pub recv_free: u16,
+    pub min_recv_free: u16,
+}
+
+impl Display for KcpStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}s, rtt {}ms (var {}ms), rto {}ms, sent {} packets {} segments ({} retransmitted, {} fast), \
+             received {} packets {} segments ({} duplicate, {} out of order), \
+             in flight at most {} of window {}, receive window used at most {} of {}",
+            self.duration,
+            self.srtt,
+            self.rttvar,
+            self.rto,
+            self.packets_sent,
+            self.segments_sent,
+            self.retransmits,
+            self.fast_retransmits,
+            self.packets_received,
+            self.segments_received,
+            self.duplicates,
+            self.out_of_order,
+            self.max_in_flight,
+            self.send_window,
+            self.recv_window.saturating_sub(self.min_recv_free),
+            self.recv_window
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(cmd: u8, sn: u32, una: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(KCP_OVERHEAD + data.len());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.push(cmd);
+        buf.push(0);
+        buf.extend_from_slice(&128u16.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&sn.to_le_bytes());
+        buf.extend_from_slice(&una.to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn parse_segments() {
+        let mut datagram = segment(KCP_CMD_PUSH, 7, 3, b"hello");
+        datagram.extend(segment(KCP_CMD_ACK, 5, 4, b""));
+        let parsed = segments(&datagram).map(|s| (s.cmd, s.sn, s.una)).collect::<Vec<_>>();
+        assert_eq!(parsed, [(KCP_CMD_PUSH, 7, 3), (KCP_CMD_ACK, 5, 4)]);
+
+        // A length past the end of the datagram ends it
+        let mut datagram = segment(KCP_CMD_PUSH, 1, 0, b"hello");
+        datagram.truncate(datagram.len() - 1);
+        datagram.extend(segment(KCP_CMD_PUSH, 2, 0, b""));
+        assert_eq!(segments(&datagram).count(), 1);
+
+        assert_eq!(segments(&[0u8; KCP_OVERHEAD - 1]).count(), 0);
+
+        let mut datagram = segment(KCP_CMD_PUSH, 1, 0, b"");
+        datagram[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        datagram.extend(segment(KCP_CMD_PUSH, 2, 0, b""));
+        assert_eq!(segments(&datagram).count(), 1);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let stats = KcpStats::new(&KcpConfig::default());
+        stats.state.lock().unwrap().rcv_nxt = u32::MAX;
+        stats.on_recv(&segment(KCP_CMD_PUSH, 0, 0, b"b"));
+        stats.on_recv(&segment(KCP_CMD_PUSH, u32::MAX, 0, b"a"));
+        stats.on_recv(&segment(KCP_CMD_PUSH, u32::MAX - 1, 0, b"z"));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.out_of_order, 1);
+        assert_eq!(snapshot.duplicates, 1);
+        assert_eq!(stats.state.lock().unwrap().rcv_nxt, 1);
+
+        let stats = KcpStats::new(&KcpConfig::default());
+        {
+            let mut state = stats.state.lock().unwrap();
+            state.snd_nxt = u32::MAX;
+            state.remote_una = u32::MAX;
+        }
+        stats.on_send(&segment(KCP_CMD_PUSH, u32::MAX, 0, b"a"));
+        stats.on_send(&segment(KCP_CMD_PUSH, 0, 0, b"b"));
+        assert_eq!(stats.snapshot().in_flight, 2);
+        assert_eq!(stats.snapshot().retransmits, 0);
+        stats.on_recv(&segment(KCP_CMD_ACK, u32::MAX, 0, b""));
+        assert_eq!(stats.snapshot().in_flight, 1);
+        assert_eq!(stats.state.lock().unwrap().unacked.len(), 1);
+        stats.on_recv(&segment(KCP_CMD_ACK, 0, 1, b""));
+        assert_eq!(stats.snapshot().in_flight, 0);
+        assert!(stats.state.lock().unwrap().unacked.is_empty());
+    }
+
+    #[test]
+    fn retransmits_and_acks() {
+        let stats = KcpStats::new(&KcpConfig::default());
+        stats.on_send(&segment(KCP_CMD_PUSH, 0, 0, b"a"));
+        stats.on_send(&segment(KCP_CMD_PUSH, 1, 0, b"b"));
+        stats.on_send(&segment(KCP_CMD_PUSH, 1, 0, b"b"));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.segments_sent, 3);
+        assert_eq!(snapshot.retransmits, 1);
+        assert_eq!(snapshot.in_flight, 2);
+
+        // UNA acknowledges every segment before it
+        stats.on_recv(&segment(KCP_CMD_ACK, 0, 2, b""));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.in_flight, 0);
+        assert_eq!(snapshot.max_in_flight, 2);
+        assert!(stats.state.lock().unwrap().unacked.is_empty());
+
+        // Sent again after its ACK was lost
+        stats.on_send(&segment(KCP_CMD_PUSH, 0, 0, b"a"));
+        assert_eq!(stats.snapshot().retransmits, 2);
+    }
+
+    #[test]
+    fn duplicates_and_out_of_order() {
+        let stats = KcpStats::new(&KcpConfig::default());
+        stats.on_recv(&segment(KCP_CMD_PUSH, 0, 0, b"a"));
+        stats.on_recv(&segment(KCP_CMD_PUSH, 2, 0, b"c"));
+        stats.on_recv(&segment(KCP_CMD_PUSH, 2, 0, b"c"));
+        stats.on_recv(&segment(KCP_CMD_PUSH, 1, 0, b"b"));
+        stats.on_recv(&segment(KCP_CMD_PUSH, 0, 0, b"a"));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.segments_received, 5);
+        assert_eq!(snapshot.out_of_order, 1);
+        assert_eq!(snapshot.duplicates, 2);
+        assert_eq!(stats.state.lock().unwrap().rcv_nxt, 3);
+    }
+}
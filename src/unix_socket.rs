@@ -0,0 +1,253 @@
+//! Unix domain sockets
+//!
+//! `SS_LOCAL_HOST` may be the absolute path of a unix domain socket instead of a host, and `SS_LOCAL_PORT` is ignored.
+//! Server then connects streams to the socket, and local listens on it, replacing a socket left by an earlier process.
+//! `unix_socket_mode` and `unix_socket_owner` set the file mode and owner of the socket that local creates. Other hosts
+//! are network addresses only, see [`ServerAddr::from_network_str`].
+//!
+//! [`ServerAddr::from_network_str`]: crate::config::ServerAddr::from_network_str
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// File mode of `unix_socket_mode` option, in octal like `660`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixSocketMode(pub u32);
+
+impl FromStr for UnixSocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UnixSocketMode, String> {
+        match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(UnixSocketMode(mode)),
+            _ => Err(format!("invalid file mode {:?}, should be octal like 660", s)),
+        }
+    }
+}
+
+impl Display for UnixSocketMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:o}", self.0)
+    }
+}
+
+impl Serialize for UnixSocketMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnixSocketMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<UnixSocketMode, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        ffi::CString,
+        fs::{self, Permissions},
+        io::{self, ErrorKind},
+        mem,
+        os::unix::fs::{chown, FileTypeExt, PermissionsExt},
+        path::Path,
+        ptr,
+    };
+
+    use tokio::net::UnixListener;
+
+    use crate::opt::PluginOpts;
+
+    /// Initial size of the buffer of `getpwnam_r` and `getgrnam_r`, grown while it is too small
+    const NAME_BUF_SIZE: usize = 1024;
+
+    /// Largest buffer of `getpwnam_r` and `getgrnam_r`
+    const NAME_BUF_MAX: usize = 1024 * 1024;
+
+    /// Remove the socket at `path` if there is one, refusing to remove anything else
+    pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+            Ok(..) => Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            )),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Listen on `path`, creating the socket with the file mode creation mask `umask`
+    ///
+    /// The mask is of the process, so files created by other threads meanwhile are only more restricted.
+    pub fn bind_with_umask(path: &Path, umask: libc::mode_t) -> io::Result<UnixListener> {
+        // SAFETY: umask only replaces the mask of the process and returns the previous one, it cannot fail
+        let old = unsafe { libc::umask(umask) };
+        let result = UnixListener::bind(path);
+        // SAFETY: Same as above, restoring the previous mask
+        unsafe { libc::umask(old) };
+        result
+    }
+
+    /// Listen on `path`, with the mode and owner of `unix_socket_mode` and `unix_socket_owner`
+    ///
+    /// With `unix_socket_mode`, the socket is only accessible to its owner until it has its owner and mode.
+    pub fn bind_unix_listener(path: &Path, opts: &PluginOpts) -> io::Result<UnixListener> {
+        remove_stale_socket(path)?;
+        let listener = match opts.unix_socket_mode {
+            Some(..) => bind_with_umask(path, 0o177)?,
+            None => UnixListener::bind(path)?,
+        };
+
+        if let Some(ref owner) = opts.unix_socket_owner {
+            let (user, group) = match owner.split_once(':') {
+                Some((user, group)) => (user, group),
+                None => (owner.as_str(), ""),
+            };
+            let uid = match user {
+                "" => None,
+                user => Some(user_id(user)?),
+            };
+            let gid = match group {
+                "" => None,
+                group => Some(group_id(group)?),
+            };
+            chown(path, uid, gid)?;
+        }
+        if let Some(mode) = opts.unix_socket_mode {
+            fs::set_permissions(path, Permissions::from_mode(mode.0))?;
+        }
+
+        Ok(listener)
+    }
+
+    fn c_name(name: &str) -> io::Result<CString> {
+        CString::new(name).map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("invalid name {:?}", name)))
+    }
+
+    /// ID of user `name`, which may be an ID already
+    pub(super) fn user_id(name: &str) -> io::Result<u32> {
+        if let Ok(id) = name.parse() {
+            return Ok(id);
+        }
+
+        let name_c = c_name(name)?;
+        let uid = lookup(|buf| {
+            // SAFETY: passwd is plain old data, which getpwnam_r fills in
+            let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            // SAFETY: name is NUL terminated, the pointers are valid for the call, and buf is as long as told
+            let ret =
+                unsafe { libc::getpwnam_r(name_c.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+            (ret, (!result.is_null()).then_some(passwd.pw_uid))
+        })?;
+        uid.ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no user {:?}", name)))
+    }
+
+    /// ID of group `name`, which may be an ID already
+    pub(super) fn group_id(name: &str) -> io::Result<u32> {
+        if let Ok(id) = name.parse() {
+            return Ok(id);
+        }
+
+        let name_c = c_name(name)?;
+        let gid = lookup(|buf| {
+            // SAFETY: group is plain old data, which getgrnam_r fills in
+            let mut group: libc::group = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            // SAFETY: name is NUL terminated, the pointers are valid for the call, and buf is as long as told
+            let ret =
+                unsafe { libc::getgrnam_r(name_c.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+            (ret, (!result.is_null()).then_some(group.gr_gid))
+        })?;
+        gid.ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no group {:?}", name)))
+    }
+
+    /// Call a `get*nam_r` function with buffers that grow while they are too small, returning its entry if found
+    fn lookup<T>(mut call: impl FnMut(&mut [libc::c_char]) -> (libc::c_int, Option<T>)) -> io::Result<Option<T>> {
+        let mut buf = vec![0; NAME_BUF_SIZE];
+        loop {
+            match call(&mut buf) {
+                (0, entry) => return Ok(entry),
+                (libc::ERANGE, _) if buf.len() < NAME_BUF_MAX => buf.resize(buf.len() * 2, 0),
+                (errno, _) => return Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::{bind_unix_listener, bind_with_umask, remove_stale_socket};
+#[cfg(all(test, unix))]
+use self::unix::{group_id, user_id};
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, io::ErrorKind, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use super::*;
+    use crate::opt::PluginOpts;
+
+    /// Path of a socket of a test, removed when dropped
+    struct TempSocket(PathBuf);
+
+    impl TempSocket {
+        fn new(test: &str) -> TempSocket {
+            TempSocket(std::env::temp_dir().join(format!("sskcp-{}-{}.sock", test, std::process::id())))
+        }
+    }
+
+    impl Drop for TempSocket {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn bound_with_umask() {
+        let path = std::env::temp_dir().join(format!("sskcp-umask-{}.sock", std::process::id()));
+        let _listener = bind_with_umask(&path, 0o177).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn listener_mode() {
+        let socket = TempSocket::new("mode");
+        let opts = PluginOpts::from_str("unix_socket_mode=640").unwrap();
+
+        // A socket left by an earlier process is replaced
+        let stale = bind_unix_listener(&socket.0, &opts).unwrap();
+        drop(stale);
+        let _listener = bind_unix_listener(&socket.0, &opts).unwrap();
+
+        let mode = fs::metadata(&socket.0).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[tokio::test]
+    async fn listener_does_not_replace_files() {
+        let socket = TempSocket::new("file");
+        fs::write(&socket.0, b"data").unwrap();
+
+        let err = bind_unix_listener(&socket.0, &PluginOpts::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&socket.0).unwrap(), b"data");
+    }
+
+    #[test]
+    fn owner_names() {
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(user_id("1234").unwrap(), 1234);
+        assert_eq!(group_id("1234").unwrap(), 1234);
+        assert_eq!(user_id("sskcp-no-such-user").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(group_id("sskcp-no-such-group").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,699 @@
+//! Users of a multi-user server
+//!
+//! With `users`, server authenticates each session to a user of a JSON file, reloaded when it changes:
+//!
+//! ```plain
+//! [{"id": "alice", "key": "secret", "quota": 102400, "rate_limit": 2048},
+//!  {"id": "bob", "key": "another secret", "enabled": false}]
+//! ```
+//!
+//! `quota` is MiB per calendar month in UTC, and `rate_limit` KiB/s in each direction, shared by all sessions of the
+//! user. Both are unlimited if missing or `0`. Bytes in both directions of each user's sessions are saved to
+//! `users_usage` every minute. New sessions of users who have used up their quota are refused, and sessions that use it
+//! up are closed.
+//!
+//! Local authenticates with `user` and `user_key`, after the e2e handshake and before the session preamble:
+//!
+//! ```plain
+//! LOCAL  -> SERVER  ID_LEN(1) ID
+//! SERVER -> LOCAL   CHALLENGE(16)
+//! LOCAL  -> SERVER  PROOF(32)
+//! SERVER -> LOCAL   STATUS(1)
+//! ```
+//!
+//! `PROOF` is HMAC-SHA256 of `CHALLENGE` and `ID` with the key. `STATUS` is `0` if the session is accepted, `1` for an
+//! unknown user or a wrong key, `2` for a disabled user, `3` for a user who has used up their quota.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time,
+};
+
+use crate::{
+    access_log::civil_date,
+    opt::PluginOpts,
+    ratelimit::RateLimiter,
+    session::{BoxedSessionStream, SessionStream},
+};
+
+const CHALLENGE_SIZE: usize = 16;
+const PROOF_SIZE: usize = 32;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNAUTHORIZED: u8 = 1;
+const STATUS_DISABLED: u8 = 2;
+const STATUS_QUOTA_EXCEEDED: u8 = 3;
+
+/// Timeout for authenticating a new session
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the users file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often usage is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Check that `user` is set with `user_key`, and that `users` does not meet `quic_port`
+pub fn check_plugin_opts(opts: &PluginOpts) -> io::Result<()> {
+    if let Some(ref id) = opts.user {
+        if opts.user_key.is_none() {
+            return Err(io::Error::other("user requires user_key"));
+        }
+        if id.len() > u8::MAX as usize {
+            return Err(io::Error::other("user is longer than 255 bytes"));
+        }
+    }
+    if opts.users.is_some() && opts.quic_port.is_some() {
+        return Err(io::Error::other(
+            "users does not apply to quic_port, which is not authenticated",
+        ));
+    }
+    Ok(())
+}
+
+/// A user in the users file
+#[derive(Debug, Deserialize)]
+struct UserEntry {
+    id: String,
+    key: String,
+    /// MiB per month
+    #[serde(default)]
+    quota: Option<u64>,
+    /// KiB per second
+    #[serde(default)]
+    rate_limit: Option<u64>,
+    #[serde(default = "enabled_default")]
+    enabled: bool,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// Usage of a user in the usage file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UsageRecord {
+    month: String,
+    bytes: u64,
+}
+
+/// `YYYY-MM` of now, in UTC
+fn current_month() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let (year, month, _) = civil_date(days as i64);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Bytes of a user in a month, kept when the users file is reloaded
+#[derive(Debug)]
+pub struct Usage {
+    month: Mutex<String>,
+    bytes: AtomicU64,
+}
+
+impl Usage {
+    fn new(record: UsageRecord) -> Usage {
+        Usage {
+            month: Mutex::new(record.month),
+            bytes: AtomicU64::new(record.bytes),
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, n: usize) {
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Start counting again if `month` is not the month being counted
+    fn roll(&self, month: &str) {
+        let mut current = self.month.lock().unwrap();
+        if *current != month {
+            *current = month.to_owned();
+            self.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn record(&self) -> UsageRecord {
+        UsageRecord {
+            month: self.month.lock().unwrap().clone(),
+            bytes: self.bytes(),
+        }
+    }
+}
+
+/// A user of the server
+#[derive(Debug)]
+pub struct User {
+    id: String,
+    key: String,
+    /// Bytes per month
+    quota: Option<u64>,
+    /// KiB per second, as in the users file
+    rate_limit: Option<u64>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    enabled: bool,
+    usage: Arc<Usage>,
+}
+
+impl User {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Rate limiter shared by all sessions of the user
+    pub fn rate_limiter(&self) -> Option<&Arc<RateLimiter>> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub fn usage(&self) -> &Arc<Usage> {
+        &self.usage
+    }
+
+    fn quota_exceeded(&self) -> bool {
+        self.usage.roll(&current_month());
+        self.quota.is_some_and(|quota| self.usage.bytes() >= quota)
+    }
+
+    fn verify(&self, challenge: &[u8], proof: &[u8]) -> bool {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(challenge);
+        mac.update(self.id.as_bytes());
+        mac.verify_slice(proof).is_ok()
+    }
+}
+
+/// Users of `users`, and their usage saved to `users_usage`
+#[derive(Debug)]
+pub struct Users {
+    path: String,
+    usage_path: String,
+    users: RwLock<HashMap<String, Arc<User>>>,
+    /// Usage of all users ever loaded, so that it survives a user being removed and added again
+    usage: Mutex<HashMap<String, Arc<Usage>>>,
+    /// Modification time of the users file when it was loaded
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Users {
+    /// Load users of `path`, and their usage of `usage_path` if it exists
+    pub fn load(path: &str, usage_path: &str) -> io::Result<Users> {
+        let usage = match fs::read(usage_path) {
+            Ok(data) => serde_json::from_slice::<HashMap<String, UsageRecord>>(&data)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        let users = Users {
+            path: path.to_owned(),
+            usage_path: usage_path.to_owned(),
+            users: RwLock::new(HashMap::new()),
+            usage: Mutex::new(usage.into_iter().map(|(id, r)| (id, Arc::new(Usage::new(r)))).collect()),
+            modified: Mutex::new(None),
+        };
+        users.reload()?;
+        Ok(users)
+    }
+
+    /// Users of `users`, if set, saving usage to `users_usage` or `USERS.usage` and reloading in the background
+    pub fn from_plugin_opts(opts: &PluginOpts) -> io::Result<Option<Arc<Users>>> {
+        match opts.users {
+            Some(ref path) => {
+                let usage_path = match opts.users_usage {
+                    Some(ref p) => p.clone(),
+                    None => format!("{}.usage", path),
+                };
+                let users = Arc::new(Users::load(path, &usage_path)?);
+                users.start();
+                Ok(Some(users))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read the users file again
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        let entries: Vec<UserEntry> = serde_json::from_slice(&fs::read(&self.path)?)?;
+
+        let month = current_month();
+        let mut users = HashMap::with_capacity(entries.len());
+        {
+            let old = self.users.read().unwrap();
+            let mut usage = self.usage.lock().unwrap();
+            for entry in entries {
+                let usage = usage
+                    .entry(entry.id.clone())
+                    .or_insert_with(|| {
+                        Arc::new(Usage::new(UsageRecord {
+                            month: month.clone(),
+                            bytes: 0,
+                        }))
+                    })
+                    .clone();
+
+                // Sessions keep going through the same buckets if the rate limit is unchanged
+                let rate_limit = entry.rate_limit.filter(|&r| r > 0);
+                let rate_limiter = match old.get(&entry.id) {
+                    Some(user) if user.rate_limit == rate_limit => user.rate_limiter.clone(),
+                    _ => rate_limit.map(|r| Arc::new(RateLimiter::new(r * 1024, r * 1024))),
+                };
+
+                let user = User {
+                    id: entry.id.clone(),
+                    key: entry.key,
+                    quota: entry.quota.filter(|&q| q > 0).map(|q| q * 1024 * 1024),
+                    rate_limit,
+                    rate_limiter,
+                    enabled: entry.enabled,
+                    usage,
+                };
+                users.insert(entry.id, Arc::new(user));
+            }
+        }
+
+        info!("loaded {} users from {}", users.len(), self.path);
+        *self.users.write().unwrap() = users;
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == *self.modified.lock().unwrap() {
+            return;
+        }
+        if let Err(err) = self.reload() {
+            error!(
+                "reload users {} failed, keeping previous users, error: {}",
+                self.path, err
+            );
+        }
+    }
+
+    /// Write usage of all users to the usage file
+    pub fn save_usage(&self) -> io::Result<()> {
+        let month = current_month();
+        let records = {
+            let usage = self.usage.lock().unwrap();
+            usage
+                .iter()
+                .map(|(id, u)| {
+                    u.roll(&month);
+                    (id.clone(), u.record())
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        // Replace the file at once, so that it is never seen half written
+        let tmp_path = format!("{}.tmp", self.usage_path);
+        fs::write(&tmp_path, serde_json::to_vec(&records)?)?;
+        fs::rename(&tmp_path, &self.usage_path)
+    }
+
+    /// Reload the users file when it changes, and save usage periodically, while `self` is alive
+    fn start(self: &Arc<Self>) {
+        let users = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = time::interval(RELOAD_INTERVAL);
+            let mut last_saved = time::Instant::now();
+            loop {
+                interval.tick().await;
+                let users = match Weak::upgrade(&users) {
+                    Some(u) => u,
+                    None => return,
+                };
+
+                users.reload_if_modified();
+                if last_saved.elapsed() >= SAVE_INTERVAL {
+                    last_saved = time::Instant::now();
+                    if let Err(err) = users.save_usage() {
+                        error!("save usage {} failed, error: {}", users.usage_path, err);
+                    }
+                }
+            }
+        });
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<User>> {
+        self.users.read().unwrap().get(id).cloned()
+    }
+
+    /// Authenticate the session on `stream` to a user
+    async fn authenticate(&self, mut stream: BoxedSessionStream) -> io::Result<(BoxedSessionStream, Arc<User>)> {
+        let mut id_len = [0u8; 1];
+        stream.read_exact(&mut id_len).await?;
+        let mut id = vec![0u8; id_len[0] as usize];
+        stream.read_exact(&mut id).await?;
+
+        let mut challenge = [0u8; CHALLENGE_SIZE];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        stream.write_all(&challenge).await?;
+        stream.flush().await?;
+
+        let mut proof = [0u8; PROOF_SIZE];
+        stream.read_exact(&mut proof).await?;
+
+        // Unknown users get the same challenge and status as wrong keys, so that they cannot be told apart
+        let id = String::from_utf8_lossy(&id);
+        let user = self.get(&id).filter(|user| user.verify(&challenge, &proof));
+        let status = match user {
+            None => STATUS_UNAUTHORIZED,
+            Some(ref user) if !user.enabled => STATUS_DISABLED,
+            Some(ref user) if user.quota_exceeded() => STATUS_QUOTA_EXCEEDED,
+            Some(..) => STATUS_OK,
+        };
+        stream.write_all(&[status]).await?;
+        stream.flush().await?;
+
+        match user {
+            Some(user) if status == STATUS_OK => {
+                let stream = UserStream {
+                    stream,
+                    id: user.id.clone(),
+                    quota: user.quota,
+                    usage: user.usage.clone(),
+                };
+                Ok((Box::new(stream), user))
+            }
+            _ => Err(status_error(status, &id)),
+        }
+    }
+}
+
+/// A session refused with `status`
+#[derive(Debug)]
+struct StatusError {
+    id: String,
+    status: u8,
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.status {
+            STATUS_UNAUTHORIZED => "unknown user or wrong key",
+            STATUS_DISABLED => "user is disabled",
+            _ => "user has used up their quota",
+        };
+        write!(f, "user {:?}: {}", self.id, reason)
+    }
+}
+
+impl Error for StatusError {}
+
+fn status_error(status: u8, id: &str) -> io::Error {
+    match status {
+        STATUS_UNAUTHORIZED | STATUS_DISABLED | STATUS_QUOTA_EXCEEDED => io::Error::new(
+            ErrorKind::PermissionDenied,
+            StatusError {
+                id: id.to_owned(),
+                status,
+            },
+        ),
+        _ => io::Error::new(
+            ErrorKind::InvalidData,
+            format!("user {:?} invalid status {}", id, status),
+        ),
+    }
+}
+
+/// Whether `err` is of an unknown user or a wrong key, rather than of a user who is disabled or over their quota
+pub fn is_unauthorized(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|e| e.downcast_ref::<StatusError>())
+        .is_some_and(|e| e.status == STATUS_UNAUTHORIZED)
+}
+
+/// Authenticate a new KCP session on server, to one of `users`
+pub async fn server_user_session(
+    stream: BoxedSessionStream,
+    users: &Users,
+) -> io::Result<(BoxedSessionStream, Arc<User>)> {
+    match time::timeout(AUTH_TIMEOUT, users.authenticate(stream)).await {
+        Ok(r) => r,
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "user authentication timeout")),
+    }
+}
+
+/// Authenticate a new KCP session on local, if `user` is set
+pub async fn client_user_session<S>(mut stream: S, opts: &PluginOpts) -> io::Result<S>
+where
+    S: SessionStream,
+{
+    let (id, key) = match (opts.user.as_ref(), opts.user_key.as_ref()) {
+        (Some(id), Some(key)) => (id, key),
+        _ => return Ok(stream),
+    };
+
+    let authenticate = async {
+        let mut buf = Vec::with_capacity(1 + id.len());
+        buf.push(id.len() as u8);
+        buf.extend_from_slice(id.as_bytes());
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+
+        let mut challenge = [0u8; CHALLENGE_SIZE];
+        stream.read_exact(&mut challenge).await?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(&challenge);
+        mac.update(id.as_bytes());
+        stream.write_all(&mac.finalize().into_bytes()).await?;
+        stream.flush().await?;
+
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status).await?;
+        match status[0] {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status, id)),
+        }
+    };
+
+    match time::timeout(AUTH_TIMEOUT, authenticate).await {
+        Ok(r) => r.map(|_| stream),
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "user authentication timeout")),
+    }
+}
+
+/// Session stream of a user, counting bytes in both directions to their usage
+///
+/// Fails once the user has used up their quota, which closes the session.
+struct UserStream {
+    stream: BoxedSessionStream,
+    id: String,
+    /// Bytes per month
+    quota: Option<u64>,
+    usage: Arc<Usage>,
+}
+
+impl UserStream {
+    fn check_quota(&self) -> io::Result<()> {
+        match self.quota {
+            Some(quota) if self.usage.bytes() >= quota => Err(status_error(STATUS_QUOTA_EXCEEDED, &self.id)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for UserStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.check_quota()?;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.usage.add(buf.filled().len() - filled);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for UserStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.check_quota()?;
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.usage.add(n);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+
+    /// Users file and usage file of a test, removed when dropped
+    struct TempUsers {
+        path: String,
+        usage_path: String,
+    }
+
+    impl TempUsers {
+        fn new(test: &str, users: &str, usage: &str) -> TempUsers {
+            let dir = std::env::temp_dir();
+            let prefix = format!("sskcp-users-{}-{}", std::process::id(), test);
+            let temp = TempUsers {
+                path: dir.join(format!("{}.json", prefix)).to_string_lossy().into_owned(),
+                usage_path: dir.join(format!("{}.usage", prefix)).to_string_lossy().into_owned(),
+            };
+            fs::write(&temp.path, users).unwrap();
+            fs::write(&temp.usage_path, usage).unwrap();
+            temp
+        }
+
+        fn load(&self) -> Users {
+            Users::load(&self.path, &self.usage_path).unwrap()
+        }
+    }
+
+    impl Drop for TempUsers {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+            let _ = fs::remove_file(&self.usage_path);
+        }
+    }
+
+    const USERS: &str = r#"[{"id": "alice", "key": "secret", "quota": 1},
+        {"id": "bob", "key": "another secret", "enabled": false}]"#;
+
+    async fn authenticate(
+        users: &Users,
+        id: &str,
+        key: &str,
+    ) -> (
+        io::Result<BoxedSessionStream>,
+        io::Result<(BoxedSessionStream, Arc<User>)>,
+    ) {
+        let opts = PluginOpts {
+            user: Some(id.to_owned()),
+            user_key: Some(key.to_owned()),
+            ..Default::default()
+        };
+        let (client, server) = duplex(1024);
+        let (client, server) = tokio::join!(
+            client_user_session(Box::new(client) as BoxedSessionStream, &opts),
+            server_user_session(Box::new(server), users),
+        );
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn authenticated_and_counted() {
+        let temp = TempUsers::new("authenticated", USERS, "{}");
+        let users = temp.load();
+
+        let (client, server) = authenticate(&users, "alice", "secret").await;
+        let (mut client, (mut server, user)) = (client.unwrap(), server.unwrap());
+        assert_eq!(user.id(), "alice");
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"hi").await.unwrap();
+        assert_eq!(user.usage().bytes(), 7);
+
+        users.save_usage().unwrap();
+        let saved: HashMap<String, UsageRecord> = serde_json::from_slice(&fs::read(&temp.usage_path).unwrap()).unwrap();
+        assert_eq!(saved["alice"].bytes, 7);
+        assert_eq!(saved["alice"].month, current_month());
+    }
+
+    #[tokio::test]
+    async fn refused() {
+        let temp = TempUsers::new("refused", USERS, "{}");
+        let users = temp.load();
+
+        for (id, key, reason) in [
+            ("alice", "wrong", "wrong key"),
+            ("carol", "secret", "wrong key"),
+            ("bob", "another secret", "disabled"),
+        ] {
+            let (client, server) = authenticate(&users, id, key).await;
+            let client = client.err().unwrap();
+            assert_eq!(client.kind(), ErrorKind::PermissionDenied);
+            assert!(client.to_string().contains(reason), "{}", client);
+            let server = server.err().unwrap();
+            assert_eq!(server.kind(), ErrorKind::PermissionDenied);
+            assert_eq!(is_unauthorized(&server), reason == "wrong key");
+        }
+    }
+
+    #[tokio::test]
+    async fn quota_exceeded() {
+        let usage = format!(r#"{{"alice": {{"month": "{}", "bytes": 1048576}}}}"#, current_month());
+        let temp = TempUsers::new("quota", USERS, &usage);
+        let users = temp.load();
+
+        let (client, server) = authenticate(&users, "alice", "secret").await;
+        assert!(client.err().unwrap().to_string().contains("quota"));
+        assert!(!is_unauthorized(&server.err().unwrap()));
+
+        // Usage of an earlier month does not count
+        let temp = TempUsers::new(
+            "quota-old",
+            USERS,
+            r#"{"alice": {"month": "2000-01", "bytes": 1048576}}"#,
+        );
+        let users = temp.load();
+        let (client, _) = authenticate(&users, "alice", "secret").await;
+        assert!(client.is_ok());
+    }
+
+    #[tokio::test]
+    async fn quota_used_up_in_session() {
+        let usage = format!(r#"{{"alice": {{"month": "{}", "bytes": 1048570}}}}"#, current_month());
+        let temp = TempUsers::new("quota-session", USERS, &usage);
+        let users = temp.load();
+
+        let (client, server) = authenticate(&users, "alice", "secret").await;
+        let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+
+        // The write that crosses the quota goes through, the session fails after it
+        server.write_all(b"hello, alice").await.unwrap();
+        let mut buf = [0u8; 12];
+        client.read_exact(&mut buf).await.unwrap();
+
+        let err = server.write_all(b"hello").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("quota"), "{}", err);
+        assert!(server.read(&mut buf).await.is_err());
+    }
+}